@@ -0,0 +1,214 @@
+use rsheet_lib::command::CellIdentifier;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::str::FromStr;
+
+/// How a locked range restricts writes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RangeLock {
+    /// Nobody may write to the range.
+    ReadOnly,
+    /// Only senders with the given role may write to the range.
+    Owner(String),
+}
+
+/// A rectangular range of cells with a lock applied to it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LockedRange {
+    name: String,
+    start: CellIdentifier,
+    end: CellIdentifier,
+    lock: RangeLock,
+}
+
+/// Access control configuration, loaded from a config file.
+///
+/// The file is line based. Blank lines and lines starting with `#` are ignored.
+///
+/// ```text
+/// role alice editor        # sender `alice` has role `editor`
+/// default viewer           # role for senders without a `role` line
+/// readonly A1_C1           # nobody may write to A1..C1
+/// owner D1_D100 editor     # only `editor` may write to D1..D100
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AccessConfig {
+    roles: HashMap<String, String>,
+    default_role: Option<String>,
+    ranges: Vec<LockedRange>,
+}
+
+impl AccessConfig {
+    /// Finds the role of a sender, given the id of its reader.
+    ///
+    /// Terminal senders have ids such as `alice: `, so trailing colons and whitespace are
+    /// ignored. Network senders are matched on their full address first, then on their IP.
+    ///
+    /// # Parameters
+    /// * `sender`: The id of the sender, from `Reader::id`.
+    ///
+    /// # Returns
+    /// The role of the sender, or the default role if the sender is unknown.
+    pub fn role_for(&self, sender: &str) -> Option<String> {
+        let name = sender.trim().trim_end_matches(':');
+        let ip = name
+            .parse::<SocketAddr>()
+            .ok()
+            .map(|addr| addr.ip().to_string());
+
+        self.roles
+            .get(name)
+            .or_else(|| ip.and_then(|ip| self.roles.get(&ip)))
+            .or(self.default_role.as_ref())
+            .cloned()
+    }
+
+    /// Checks whether a sender with the given role may write to a cell.
+    ///
+    /// # Parameters
+    /// * `role`: The role of the sender, if it has one.
+    /// * `cell`: The cell being written to.
+    ///
+    /// # Returns
+    /// `Ok(())` if the write is allowed, otherwise an error message naming the locked range.
+    pub fn check_write(&self, role: Option<&str>, cell: &CellIdentifier) -> Result<(), String> {
+        for range in self.ranges.iter().filter(|range| range.contains(cell)) {
+            match &range.lock {
+                RangeLock::ReadOnly => {
+                    return Err(format!("range {} is read-only", range.name));
+                }
+                RangeLock::Owner(owner) if Some(owner.as_str()) != role => {
+                    return Err(format!(
+                        "range {} is owned by role '{}' (you are {})",
+                        range.name,
+                        owner,
+                        role.map_or("not assigned a role".to_string(), |r| format!("'{}'", r))
+                    ));
+                }
+                RangeLock::Owner(_) => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+impl LockedRange {
+    fn contains(&self, cell: &CellIdentifier) -> bool {
+        (self.start.col..=self.end.col).contains(&cell.col)
+            && (self.start.row..=self.end.row).contains(&cell.row)
+    }
+}
+
+/// Parses a range such as `A1_C10`, or a single cell such as `B2`.
+fn parse_range(range: &str) -> Result<(CellIdentifier, CellIdentifier), String> {
+    let (start, end) = range.split_once('_').unwrap_or((range, range));
+    let start = start.parse::<CellIdentifier>()?;
+    let end = end.parse::<CellIdentifier>()?;
+
+    // Normalise so that `start` is always the top left corner
+    Ok((
+        CellIdentifier {
+            col: start.col.min(end.col),
+            row: start.row.min(end.row),
+        },
+        CellIdentifier {
+            col: start.col.max(end.col),
+            row: start.row.max(end.row),
+        },
+    ))
+}
+
+impl FromStr for AccessConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut config = AccessConfig::default();
+
+        for (index, line) in s.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            match parts.as_slice() {
+                ["role", sender, role] => {
+                    config.roles.insert(sender.to_string(), role.to_string());
+                }
+                ["default", role] => {
+                    config.default_role = Some(role.to_string());
+                }
+                ["readonly", range] | ["owner", range, _] => {
+                    let (start, end) = parse_range(range)
+                        .map_err(|e| format!("Error on line {}: {}", index + 1, e))?;
+                    let lock = match parts.get(2) {
+                        Some(owner) => RangeLock::Owner(owner.to_string()),
+                        None => RangeLock::ReadOnly,
+                    };
+                    config.ranges.push(LockedRange {
+                        name: range.to_string(),
+                        start,
+                        end,
+                        lock,
+                    });
+                }
+                _ => {
+                    return Err(format!(
+                        "Error on line {}: invalid access rule '{}'",
+                        index + 1,
+                        line
+                    ))
+                }
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+// ===================== TESTS ============================
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = "
+        # roles
+        role alice editor
+        role 127.0.0.1 admin
+        default viewer
+
+        readonly A1_C1
+        owner D1_D10 editor
+    ";
+
+    // 1. Test that senders are mapped to roles
+    #[test]
+    fn test_role_for() {
+        let config: AccessConfig = CONFIG.parse().unwrap();
+        assert_eq!(config.role_for("alice: "), Some("editor".to_string()));
+        assert_eq!(config.role_for("127.0.0.1:4321"), Some("admin".to_string()));
+        assert_eq!(config.role_for("bob: "), Some("viewer".to_string()));
+    }
+
+    // 2. Test read-only and owned ranges
+    #[test]
+    fn test_check_write() {
+        let config: AccessConfig = CONFIG.parse().unwrap();
+        let b1 = "B1".parse().unwrap();
+        let d5 = "D5".parse().unwrap();
+        let e5 = "E5".parse().unwrap();
+
+        assert!(config.check_write(Some("editor"), &b1).is_err());
+        assert!(config.check_write(Some("editor"), &d5).is_ok());
+        assert!(config.check_write(Some("viewer"), &d5).is_err());
+        assert!(config.check_write(None, &d5).is_err());
+        assert!(config.check_write(None, &e5).is_ok());
+    }
+
+    // 3. Test that invalid rules are rejected
+    #[test]
+    fn test_invalid_config() {
+        assert!("readonly nonsense".parse::<AccessConfig>().is_err());
+        assert!("grant alice".parse::<AccessConfig>().is_err());
+    }
+}
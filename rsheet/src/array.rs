@@ -0,0 +1,492 @@
+use crate::query::Query;
+use crate::{cell_to_string, limits, metrics, ACCESS_CONFIG};
+use log::warn;
+use rsheet_lib::cell_value::CellValue;
+use rsheet_lib::command::CellIdentifier;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+lazy_static::lazy_static! {
+    static ref SPILLS: Mutex<Spills> = Mutex::new(Spills::default());
+}
+
+/// The cells each array formula has spilled into, excluding the anchor cell itself.
+#[derive(Default)]
+struct Spills {
+    cells: HashMap<String, Vec<String>>,
+    owners: HashMap<String, String>,
+    /// The role of the sender who set each cell, which its spills are checked against.
+    /// Cells replayed from a primary have no entry, since the primary checked them already.
+    roles: HashMap<String, Option<String>>,
+}
+
+/// A function which turns a range of cells into another block of cells.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ArrayFunction {
+    /// Swaps the rows and columns of the range.
+    Transpose,
+    /// Sorts the rows of the range by their first column.
+    Sort,
+    /// Filters and aggregates the rows of the range, such as
+    /// `query(A1_F500 select B, sum(F) group by B)`.
+    Query(Query),
+}
+
+/// An array formula, such as `transpose(C1_E3)`, whose result spills into a block of cells.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ArrayFormula {
+    function: ArrayFunction,
+    start: CellIdentifier,
+    end: CellIdentifier,
+}
+
+/// The outcome of evaluating an array formula.
+pub(crate) struct Spilled {
+    /// The cells the formula reads from.
+    pub(crate) inputs: HashSet<String>,
+    /// The spilled cells whose values changed, so their dependents need recalculating.
+    pub(crate) changed: Vec<String>,
+}
+
+impl ArrayFormula {
+    /// Parses an expression of the form `function(START_END)`.
+    ///
+    /// # Parameters
+    /// * `expr`: The expression of a cell.
+    ///
+    /// # Returns
+    /// The array formula, or `None` if the expression is not one.
+    pub(crate) fn parse(expr: &str) -> Option<Self> {
+        let (name, rest) = expr.trim().split_once('(')?;
+        let args = rest.strip_suffix(')')?.trim();
+
+        let function = match name.trim() {
+            "transpose" => ArrayFunction::Transpose,
+            "sort" => ArrayFunction::Sort,
+            "query" => {
+                let query = Query::parse(args).ok()?;
+                return Some(ArrayFormula {
+                    start: query.start,
+                    end: query.end,
+                    function: ArrayFunction::Query(query),
+                });
+            }
+            _ => return None,
+        };
+
+        let (start, end) = parse_range(args)?;
+        Some(ArrayFormula {
+            function,
+            start,
+            end,
+        })
+    }
+
+    /// Returns the top left and bottom right cells of the range the formula reads.
+    pub(crate) fn range(&self) -> (CellIdentifier, CellIdentifier) {
+        (self.start, self.end)
+    }
+
+    /// Checks the range the formula reads against the range limit.
+    ///
+    /// # Returns
+    /// `Ok(())` if the range is within the limit, otherwise why it is not.
+    pub(crate) fn check_range(&self) -> Result<(), String> {
+        let range = format!(
+            "{}_{}",
+            cell_to_string(&self.start),
+            cell_to_string(&self.end)
+        );
+        limits::current().check_range(&range)
+    }
+
+    /// Evaluates the formula without spilling it, such as for a `query` command.
+    ///
+    /// # Parameters
+    /// * `cells`: A reference to the map of cell values.
+    ///
+    /// # Returns
+    /// The resulting block of values, or an error if the range is over the limits, holds an
+    /// error, or the function fails.
+    pub(crate) fn evaluate(
+        &self,
+        cells: &HashMap<String, CellValue>,
+    ) -> Result<Vec<Vec<CellValue>>, String> {
+        self.check_range()?;
+        let values = self.read(cells, &mut HashSet::new());
+        if values.iter().flatten().any(CellValue::is_error) {
+            return Err("it depends on an error".to_string());
+        }
+        self.apply(values)
+    }
+
+    /// Reads the range row by row, recording each cell as an input.
+    fn read(
+        &self,
+        cells: &HashMap<String, CellValue>,
+        inputs: &mut HashSet<String>,
+    ) -> Vec<Vec<CellValue>> {
+        (self.start.row..=self.end.row)
+            .map(|row| {
+                (self.start.col..=self.end.col)
+                    .map(|col| {
+                        let cell_address = cell_to_string(&CellIdentifier { col, row });
+                        let value = cells.get(&cell_address).cloned().unwrap_or(CellValue::None);
+                        inputs.insert(cell_address);
+                        value
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn apply(&self, values: Vec<Vec<CellValue>>) -> Result<Vec<Vec<CellValue>>, String> {
+        match &self.function {
+            ArrayFunction::Transpose => {
+                let width = values.first().map_or(0, Vec::len);
+                Ok((0..width)
+                    .map(|col| values.iter().map(|row| row[col].clone()).collect())
+                    .collect())
+            }
+            ArrayFunction::Sort => {
+                let mut values = values;
+                values.sort_by(|a, b| compare_values(&a[0], &b[0]));
+                Ok(values)
+            }
+            ArrayFunction::Query(query) => query.run(values),
+        }
+    }
+}
+
+/// Parses a range such as `C1_E3`, in either corner order.
+///
+/// # Returns
+/// The top left and bottom right cells of the range, or `None` if it is not a range.
+pub(crate) fn parse_range(range: &str) -> Option<(CellIdentifier, CellIdentifier)> {
+    let (start, end) = range.split_once('_')?;
+    let start = start.parse::<CellIdentifier>().ok()?;
+    let end = end.parse::<CellIdentifier>().ok()?;
+
+    Some((
+        CellIdentifier {
+            col: start.col.min(end.col),
+            row: start.row.min(end.row),
+        },
+        CellIdentifier {
+            col: start.col.max(end.col),
+            row: start.row.max(end.row),
+        },
+    ))
+}
+
+/// Orders integers before strings, and empty cells last.
+pub(crate) fn compare_values(a: &CellValue, b: &CellValue) -> Ordering {
+    fn rank(value: &CellValue) -> u8 {
+        match value {
+            CellValue::Int(_) => 0,
+            CellValue::String(_) => 1,
+            CellValue::Error(_) => 2,
+            CellValue::None => 3,
+        }
+    }
+
+    match (a, b) {
+        (CellValue::Int(a), CellValue::Int(b)) => a.cmp(b),
+        (CellValue::String(a), CellValue::String(b)) => a.cmp(b),
+        _ => rank(a).cmp(&rank(b)),
+    }
+}
+
+/// Evaluates an array formula and spills its result into the block anchored at a cell.
+///
+/// The anchor holds the top left value of the result. If any other cell of the block has its
+/// own expression, or is part of another spill, the spill is blocked and the anchor holds an
+/// error instead, as it does if the function fails, the spill is over any of the limits, or
+/// the sender who set the anchor may not write to any cell of the block.
+/// Cells from a previous spill of the same anchor that are no longer covered are cleared.
+///
+/// # Parameters
+/// * `formula`: The array formula to evaluate.
+/// * `cells`: A mutable reference to the map of cell values.
+/// * `anchor`: The address of the cell holding the formula.
+/// * `cell_errors`: A mutable reference to the map of errors.
+/// * `exprs`: A reference to the map of expressions.
+///
+/// # Returns
+/// The inputs of the formula, and which spilled cells changed.
+pub(crate) fn spill(
+    formula: &ArrayFormula,
+    cells: &mut HashMap<String, CellValue>,
+    anchor: &str,
+    cell_errors: &mut HashMap<String, String>,
+    exprs: &HashMap<String, String>,
+) -> Spilled {
+    let mut inputs = HashSet::new();
+    if let Err(e) = formula.check_range() {
+        warn!("Spill from {} rejected: {}", anchor, e);
+        cells.insert(anchor.to_string(), CellValue::Error(e));
+        let changed = clear(anchor, cells, exprs);
+        return Spilled { inputs, changed };
+    }
+    let values = formula.read(cells, &mut inputs);
+
+    // Errors propagate like they do for ordinary expressions
+    if values.iter().flatten().any(CellValue::is_error) {
+        cell_errors.insert(anchor.to_string(), "VariableDependsOnError".to_string());
+        let changed = clear(anchor, cells, exprs);
+        return Spilled { inputs, changed };
+    }
+    cell_errors.remove(anchor);
+
+    let result = match formula.apply(values) {
+        Ok(result) => result,
+        Err(e) => {
+            cells.insert(anchor.to_string(), CellValue::Error(e));
+            let changed = clear(anchor, cells, exprs);
+            return Spilled { inputs, changed };
+        }
+    };
+    let origin: CellIdentifier = anchor.parse().expect("anchor is a valid cell address");
+
+    let mut block = Vec::new();
+    for (row_offset, row) in result.iter().enumerate() {
+        for (col_offset, value) in row.iter().enumerate() {
+            let cell_address = cell_to_string(&CellIdentifier {
+                col: origin.col + col_offset as u32,
+                row: origin.row + row_offset as u32,
+            });
+            block.push((cell_address, value.clone()));
+        }
+    }
+
+    let mut spills = SPILLS.lock().unwrap();
+    let blocker = block.iter().skip(1).find(|(cell_address, _)| {
+        exprs.contains_key(cell_address)
+            || spills
+                .owners
+                .get(cell_address)
+                .is_some_and(|owner| owner != anchor)
+    });
+
+    if let Some((blocker, _)) = blocker {
+        let value = CellValue::Error(format!("Spill blocked by {}", blocker));
+        cells.insert(anchor.to_string(), value);
+        drop(spills);
+        let changed = clear(anchor, cells, exprs);
+        return Spilled { inputs, changed };
+    }
+
+    // A sender cannot write to a locked range by spilling into it
+    if let Some(role) = spills.roles.get(anchor) {
+        let config = metrics::timed_lock("ACCESS_CONFIG", &ACCESS_CONFIG);
+        let refused = block.iter().skip(1).find_map(|(cell_address, _)| {
+            let cell = cell_address
+                .parse()
+                .expect("spilled cell is a valid cell address");
+            config
+                .check_write(role.as_deref(), &cell)
+                .err()
+                .map(|e| format!("Cannot spill into {}: {}", cell_address, e))
+        });
+        if let Some(e) = refused {
+            warn!("Spill from {} rejected: {}", anchor, e);
+            cells.insert(anchor.to_string(), CellValue::Error(e));
+            drop(config);
+            drop(spills);
+            let changed = clear(anchor, cells, exprs);
+            return Spilled { inputs, changed };
+        }
+    }
+
+    // Spilled strings are held to the same limit as the value of any other cell
+    let limits = limits::current();
+    if let Some(Err(e)) = block
+        .iter()
+        .map(|(_, value)| limits.check_value(value))
+        .find(Result::is_err)
+    {
+        warn!("Spill from {} rejected: {}", anchor, e);
+        cells.insert(anchor.to_string(), CellValue::Error(e));
+        drop(spills);
+        let changed = clear(anchor, cells, exprs);
+        return Spilled { inputs, changed };
+    }
+
+    // A spill cannot grow the sheet past its cell limit either
+    if let Some(max) = limits.max_cells {
+        let added = block
+            .iter()
+            .filter(|(cell_address, _)| !cells.contains_key(cell_address))
+            .count();
+        if cells.len() + added > max {
+            let value = CellValue::Error(format!("Spill exceeds the limit of {} cells", max));
+            warn!(
+                "Spill from {} rejected: over the limit of {} cells",
+                anchor, max
+            );
+            cells.insert(anchor.to_string(), value);
+            drop(spills);
+            let changed = clear(anchor, cells, exprs);
+            return Spilled { inputs, changed };
+        }
+    }
+
+    // Clear any cells of the old spill which the new result does not cover
+    let covered: HashSet<&String> = block.iter().map(|(cell_address, _)| cell_address).collect();
+    let old_cells = spills.cells.remove(anchor).unwrap_or_default();
+    let mut changed = Vec::new();
+    for old in old_cells.iter().filter(|old| !covered.contains(old)) {
+        spills.owners.remove(old);
+        if !exprs.contains_key(old) && cells.remove(old).is_some() {
+            changed.push(old.clone());
+        }
+    }
+
+    let mut new_cells = Vec::new();
+    for (index, (cell_address, value)) in block.into_iter().enumerate() {
+        if index > 0 {
+            spills
+                .owners
+                .insert(cell_address.clone(), anchor.to_string());
+            new_cells.push(cell_address.clone());
+            if cells.get(&cell_address) != Some(&value) {
+                changed.push(cell_address.clone());
+            }
+        }
+        cells.insert(cell_address, value);
+    }
+    if result.is_empty() {
+        cells.insert(anchor.to_string(), CellValue::None);
+    }
+    spills.cells.insert(anchor.to_string(), new_cells);
+
+    Spilled { inputs, changed }
+}
+
+/// Clears every cell an anchor has spilled into, except cells that now have their own expression.
+///
+/// # Parameters
+/// * `anchor`: The address of the cell which may have spilled.
+/// * `cells`: A mutable reference to the map of cell values.
+/// * `exprs`: A reference to the map of expressions.
+///
+/// # Returns
+/// The cells which were cleared.
+pub(crate) fn clear(
+    anchor: &str,
+    cells: &mut HashMap<String, CellValue>,
+    exprs: &HashMap<String, String>,
+) -> Vec<String> {
+    let mut spills = SPILLS.lock().unwrap();
+    let old_cells = spills.cells.remove(anchor).unwrap_or_default();
+
+    let mut changed = Vec::new();
+    for old in old_cells {
+        spills.owners.remove(&old);
+        if !exprs.contains_key(&old) && cells.remove(&old).is_some() {
+            changed.push(old);
+        }
+    }
+    changed
+}
+
+/// Records the role of the sender who set a cell, so that any spill from it is checked against
+/// the locked ranges as that sender.
+pub(crate) fn set_role(cell_address: &str, role: Option<&str>) {
+    SPILLS
+        .lock()
+        .unwrap()
+        .roles
+        .insert(cell_address.to_string(), role.map(str::to_string));
+}
+
+/// Forgets who set a cell, so that spills from it are not checked.
+pub(crate) fn forget_role(cell_address: &str) {
+    SPILLS.lock().unwrap().roles.remove(cell_address);
+}
+
+/// Returns the anchor of the spill covering a cell, if any.
+pub(crate) fn spill_owner(cell_address: &str) -> Option<String> {
+    SPILLS.lock().unwrap().owners.get(cell_address).cloned()
+}
+
+/// Returns the cells an anchor currently spills into, excluding the anchor itself.
+pub(crate) fn spilled_cells(anchor: &str) -> Vec<String> {
+    SPILLS
+        .lock()
+        .unwrap()
+        .cells
+        .get(anchor)
+        .cloned()
+        .unwrap_or_default()
+}
+
+/// Forgets every spill.
+pub(crate) fn reset() {
+    let mut spills = SPILLS.lock().unwrap();
+    spills.cells.clear();
+    spills.owners.clear();
+    spills.roles.clear();
+}
+
+// ===================== TESTS ============================
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 1. Test parsing array formulas
+    #[test]
+    fn test_parse() {
+        let formula = ArrayFormula::parse("transpose(E3_C1)").unwrap();
+        assert_eq!(formula.function, ArrayFunction::Transpose);
+        assert_eq!(formula.start, "C1".parse().unwrap());
+        assert_eq!(formula.end, "E3".parse().unwrap());
+
+        assert!(ArrayFormula::parse("sum(A1_A3)").is_none());
+        assert!(ArrayFormula::parse("transpose(A1)").is_none());
+    }
+
+    // 2. Test transposing and sorting blocks
+    #[test]
+    fn test_apply() {
+        let values = vec![
+            vec![CellValue::Int(3), CellValue::Int(1)],
+            vec![CellValue::Int(2), CellValue::Int(4)],
+        ];
+
+        let transpose = ArrayFormula::parse("transpose(A1_B2)").unwrap();
+        assert_eq!(
+            transpose.apply(values.clone()).unwrap(),
+            vec![
+                vec![CellValue::Int(3), CellValue::Int(2)],
+                vec![CellValue::Int(1), CellValue::Int(4)],
+            ]
+        );
+
+        let sort = ArrayFormula::parse("sort(A1_B2)").unwrap();
+        assert_eq!(sort.apply(values.clone()).unwrap()[0][0], CellValue::Int(2));
+    }
+
+    // 3. Test that occupied cells block a spill
+    #[test]
+    fn test_spill_blocked() {
+        let mut cells = HashMap::new();
+        let mut cell_errors = HashMap::new();
+        let mut exprs = HashMap::new();
+        cells.insert("X1".to_string(), CellValue::Int(1));
+        cells.insert("Y1".to_string(), CellValue::Int(2));
+        exprs.insert("Z2".to_string(), "7".to_string());
+
+        let formula = ArrayFormula::parse("transpose(X1_Y1)").unwrap();
+        spill(&formula, &mut cells, "Z1", &mut cell_errors, &exprs);
+        assert!(cells["Z1"].is_error());
+
+        exprs.remove("Z2");
+        let spilled = spill(&formula, &mut cells, "Z1", &mut cell_errors, &exprs);
+        assert_eq!(cells["Z1"], CellValue::Int(1));
+        assert_eq!(cells["Z2"], CellValue::Int(2));
+        assert_eq!(spilled.changed, vec!["Z2".to_string()]);
+        assert_eq!(spill_owner("Z2"), Some("Z1".to_string()));
+    }
+}
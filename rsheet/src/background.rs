@@ -0,0 +1,258 @@
+use crate::{
+    array, evaluate_cell, metrics, record_dependencies, CELL_ERRORS, CELL_MAP, DEPENDENCIES,
+    DEPENDERS, EXPR_MAP,
+};
+use log::{debug, info};
+use rsheet_lib::cell_expr::CellExpr;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// How long a `get` waits for a dirty cell before giving up and returning its stale value.
+///
+/// This stops reads of cells in a dependency cycle, which never become clean, from hanging.
+pub(crate) const DIRTY_WAIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Appended to the name in the reply to a `get`, when the value is stale.
+pub const STALE_SUFFIX: &str = " (stale)";
+
+lazy_static::lazy_static! {
+    static ref DIRTY: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+    static ref CLEANED: Condvar = Condvar::new();
+    static ref JOBS: Mutex<Option<Sender<String>>> = Mutex::new(None);
+}
+
+static STALE_READS: AtomicBool = AtomicBool::new(false);
+
+/// Starts a pool of workers which recalculate dependent cells in the background.
+///
+/// Once started, a `Set` only evaluates its own cell. Every cell depending on it is marked
+/// dirty and recalculated by the workers, so the sender does not wait for the cascade.
+///
+/// # Parameters
+/// * `workers`: The number of worker threads to start.
+/// * `stale_reads`: Whether a `get` of a dirty cell returns its stale value straight away,
+///   rather than waiting for that cell to be recalculated. Stale values are marked in the
+///   reply, and clients can also list the cells still being recalculated with `dirty`.
+pub fn start_workers(workers: usize, stale_reads: bool) {
+    let (sender, receiver) = mpsc::channel();
+    let receiver = Arc::new(Mutex::new(receiver));
+
+    for _ in 0..workers {
+        let receiver = Arc::clone(&receiver);
+        thread::spawn(move || run_worker(receiver));
+    }
+
+    STALE_READS.store(stale_reads, Ordering::SeqCst);
+    *JOBS.lock().unwrap() = Some(sender);
+    info!("Started {} background recalculation workers", workers);
+}
+
+/// Returns whether dependents are recalculated in the background.
+pub(crate) fn enabled() -> bool {
+    JOBS.lock().unwrap().is_some()
+}
+
+fn run_worker(receiver: Arc<Mutex<Receiver<String>>>) {
+    loop {
+        let job = receiver.lock().unwrap().recv();
+        match job {
+            Ok(cell_address) => recalculate(&cell_address),
+            Err(_) => return,
+        }
+    }
+}
+
+fn enqueue(cell_address: &str) {
+    if let Some(jobs) = JOBS.lock().unwrap().as_ref() {
+        let _ = jobs.send(cell_address.to_string());
+    }
+}
+
+/// Marks everything depending on a cell as dirty, and queues its direct dependents.
+///
+/// Dependents further down are queued by the workers once their own inputs are clean.
+/// If the cell itself reads a dirty cell, it is left dirty to be recalculated after it.
+///
+/// # Parameters
+/// * `cell_address`: The address of the cell that was just set.
+/// * `inputs`: The cells the new expression reads from.
+/// * `spilled`: The cells whose values changed because the cell spilled into them.
+/// * `dependencies`: A reference to the map of dependencies.
+pub(crate) fn schedule(
+    cell_address: &str,
+    inputs: &HashSet<String>,
+    spilled: &[String],
+    dependencies: &HashMap<String, HashSet<String>>,
+) {
+    let mut dirty = DIRTY.lock().unwrap();
+
+    if inputs.iter().any(|input| dirty.contains(input)) {
+        dirty.insert(cell_address.to_string());
+    } else if dirty.remove(cell_address) {
+        CLEANED.notify_all();
+    }
+
+    let mut changed = vec![cell_address.to_string()];
+    changed.extend_from_slice(spilled);
+    mark_dirty(&mut dirty, &changed, dependencies);
+    debug!("Set of {} leaves {} cells dirty", cell_address, dirty.len());
+}
+
+/// Marks every transitive dependent of the changed cells dirty, then queues the direct ones.
+///
+/// A dirty array formula will also change the cells it spills into, so those are marked too.
+fn mark_dirty(
+    dirty: &mut HashSet<String>,
+    changed: &[String],
+    dependencies: &HashMap<String, HashSet<String>>,
+) {
+    let mut queue: VecDeque<String> = changed.iter().cloned().collect();
+    let mut seen = HashSet::new();
+    while let Some(cell) = queue.pop_front() {
+        for dependent in dependencies.get(&cell).into_iter().flatten() {
+            if !seen.insert(dependent.clone()) {
+                continue;
+            }
+            dirty.insert(dependent.clone());
+            queue.push_back(dependent.clone());
+
+            for spilled in array::spilled_cells(dependent) {
+                if seen.insert(spilled.clone()) {
+                    dirty.insert(spilled.clone());
+                    queue.push_back(spilled);
+                }
+            }
+        }
+    }
+
+    for cell in changed {
+        for dependent in dependencies.get(cell).into_iter().flatten() {
+            enqueue(dependent);
+        }
+    }
+}
+
+/// Recalculates a single dirty cell, then queues the cells depending on it.
+///
+/// Cells which are no longer dirty are skipped, as are cells with a dirty input; those are
+/// queued again once that input has been recalculated.
+fn recalculate(cell_address: &str) {
+    let mut cells = metrics::timed_lock("CELL_MAP", &CELL_MAP);
+    let exprs = metrics::timed_lock("EXPR_MAP", &EXPR_MAP);
+    let mut cell_errors = metrics::timed_lock("CELL_ERRORS", &CELL_ERRORS);
+    let mut dependers = metrics::timed_lock("DEPENDERS", &DEPENDERS);
+    let mut dependencies = metrics::timed_lock("DEPENDENCIES", &DEPENDENCIES);
+    let mut dirty = DIRTY.lock().unwrap();
+
+    if !dirty.contains(cell_address) {
+        return;
+    }
+    let inputs = dependers.get(cell_address);
+    if inputs.is_some_and(|inputs| inputs.iter().any(|input| dirty.contains(input))) {
+        return;
+    }
+
+    let expr = CellExpr::new(exprs.get(cell_address).map_or("", String::as_str));
+    let (new_dependers, spilled) =
+        evaluate_cell(expr, &mut cells, cell_address, &mut cell_errors, &exprs);
+    record_dependencies(
+        new_dependers,
+        cell_address,
+        &mut dependers,
+        &mut dependencies,
+    );
+
+    // The cells this spills into, or has just cleared, are now up to date too
+    dirty.remove(cell_address);
+    for cell in array::spilled_cells(cell_address).iter().chain(&spilled) {
+        dirty.remove(cell);
+    }
+    CLEANED.notify_all();
+
+    let mut changed = vec![cell_address.to_string()];
+    changed.extend(spilled);
+    mark_dirty(&mut dirty, &changed, &dependencies);
+}
+
+/// Forgets every dirty cell, for `reset_state`.
+pub(crate) fn reset() {
+    DIRTY.lock().unwrap().clear();
+    CLEANED.notify_all();
+}
+
+/// Returns every cell which is waiting to be recalculated, in order.
+pub(crate) fn dirty_cells() -> Vec<String> {
+    let mut cells: Vec<String> = DIRTY.lock().unwrap().iter().cloned().collect();
+    cells.sort();
+    cells
+}
+
+/// Waits until a cell is no longer dirty, unless stale reads are enabled.
+///
+/// # Parameters
+/// * `cell_address`: The address of the cell being read.
+/// * `timeout`: How long to wait before giving up.
+///
+/// # Returns
+/// `true` if the cell is still dirty, so its current value is stale.
+pub(crate) fn wait_until_clean(cell_address: &str, timeout: Duration) -> bool {
+    let dirty = DIRTY.lock().unwrap();
+    if STALE_READS.load(Ordering::SeqCst) {
+        return dirty.contains(cell_address);
+    }
+
+    let (dirty, _) = CLEANED
+        .wait_timeout_while(dirty, timeout, |dirty| dirty.contains(cell_address))
+        .unwrap();
+    dirty.contains(cell_address)
+}
+
+// ===================== TESTS ============================
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rsheet_lib::cell_value::CellValue;
+    use rsheet_lib::replies::Reply;
+    use std::time::Instant;
+
+    const TIMEOUT: Duration = Duration::from_millis(50);
+
+    // 1. Test a get of a cell which stays dirty gives up after the timeout, reporting it stale
+    #[test]
+    fn test_wait_until_clean_timeout() {
+        DIRTY.lock().unwrap().insert("ZZ999".to_string());
+        assert!(dirty_cells().contains(&"ZZ999".to_string()));
+
+        let start = Instant::now();
+        assert!(wait_until_clean("ZZ999", TIMEOUT));
+        assert!(start.elapsed() >= TIMEOUT);
+
+        // A clean cell is not waited for at all
+        let start = Instant::now();
+        assert!(!wait_until_clean("ZZ998", DIRTY_WAIT_TIMEOUT));
+        assert!(start.elapsed() < DIRTY_WAIT_TIMEOUT);
+        DIRTY.lock().unwrap().remove("ZZ999");
+    }
+
+    // 2. Test the reply to a get of a dirty cell is marked stale
+    #[test]
+    fn test_get_marks_stale() {
+        let cell = "ZZ997".parse().unwrap();
+        DIRTY.lock().unwrap().insert("ZZ997".to_string());
+        let reply = crate::handle_get(&cell, TIMEOUT);
+        DIRTY.lock().unwrap().remove("ZZ997");
+
+        match reply {
+            Reply::Value(name, CellValue::None) => assert_eq!(name, "ZZ997 (stale)"),
+            _ => panic!("expected a value"),
+        }
+        assert!(matches!(
+            crate::handle_get(&cell, TIMEOUT),
+            Reply::Value(name, _) if name == "ZZ997"
+        ));
+    }
+}
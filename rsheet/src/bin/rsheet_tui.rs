@@ -0,0 +1,628 @@
+//! An interactive terminal client for an rsheet server.
+//!
+//! Renders a scrollable grid of cells, lets you edit the expression of the selected cell in
+//! place, and refreshes the visible cells from the server every second and after every edit.
+//! The server serves every client at the same time, so edits made by others show up on the next
+//! refresh, along with the expression of the selected cell.
+
+use clap::Parser;
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::style::{Attribute, Print, SetAttribute};
+use crossterm::terminal::{self, ClearType};
+use crossterm::{cursor, execute, queue};
+use rsheet::background::STALE_SUFFIX;
+use rsheet_lib::cell_value::CellValue;
+use rsheet_lib::cells::column_number_to_name;
+use rsheet_lib::command::CellIdentifier;
+use rsheet_lib::connect::resolve_address;
+use rsheet_lib::replies::Reply;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::error::Error;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Width of each cell, including the space separating it from the next.
+const CELL_WIDTH: u16 = 12;
+
+/// Width of the row numbers down the left of the grid.
+const ROW_LABEL_WIDTH: u16 = 6;
+
+/// How often the visible cells are fetched from the server.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How many `get`s may be waiting for a reply at once.
+const MAX_IN_FLIGHT: usize = 16;
+
+/// How long to wait for a key press before checking for replies.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(Parser, Debug)]
+struct Args {
+    /// Address of the rsheet server to connect to
+    addr: String,
+}
+
+/// A message to the server, kept until the server has replied to it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Request {
+    Get(CellIdentifier),
+    /// A `set`, which is only replied to if it fails.
+    Set(CellIdentifier, String),
+    /// A `dirty`, which is replied to with the cells still being recalculated.
+    Dirty,
+    /// A `formula`, which is replied to with the expression the cell was set to.
+    Formula(CellIdentifier),
+}
+
+/// What the main loop should do after a key press.
+enum Action {
+    None,
+    Quit,
+    Set(CellIdentifier, String),
+}
+
+/// Everything shown on screen.
+struct Grid {
+    values: HashMap<CellIdentifier, CellValue>,
+    /// Cells the server is still recalculating, so their values are out of date.
+    stale: HashSet<CellIdentifier>,
+    exprs: HashMap<CellIdentifier, String>,
+    cursor: CellIdentifier,
+    top_left: CellIdentifier,
+    editing: Option<String>,
+    status: String,
+}
+
+impl Grid {
+    fn new() -> Self {
+        Grid {
+            values: HashMap::new(),
+            stale: HashSet::new(),
+            exprs: HashMap::new(),
+            cursor: CellIdentifier { col: 0, row: 0 },
+            top_left: CellIdentifier { col: 0, row: 0 },
+            editing: None,
+            status: "Arrows move, Enter edits, Esc quits".to_string(),
+        }
+    }
+
+    /// Applies a reply to the request it answers.
+    ///
+    /// The value or error from a `get` is stored for its cell, and marked stale if the reply
+    /// says so. The cells from a `dirty` are marked stale too, until they are next fetched, and
+    /// the expression from a `formula` is stored for its cell. Anything else, such as a failed `set`, is shown in the status line.
+    ///
+    /// # Parameters
+    /// * `request`: The request the reply answers, from `Outbox::received`.
+    /// * `reply`: The reply.
+    fn apply_reply(&mut self, request: Option<Request>, reply: Reply) {
+        match (request, reply) {
+            (Some(Request::Get(cell)), Reply::Value(name, value)) => {
+                // The value may be stale even if the cell is clean by the time `dirty` is answered
+                if name.ends_with(STALE_SUFFIX) {
+                    self.stale.insert(cell);
+                } else {
+                    self.stale.remove(&cell);
+                }
+                self.values.insert(cell, value);
+            }
+            // Gets of cells depending on errors are replied to with an error, not a value
+            (Some(Request::Get(cell)), Reply::Error(e)) => {
+                self.values.insert(cell, CellValue::Error(e));
+            }
+            (Some(Request::Dirty), Reply::Value(_, CellValue::String(cells))) => {
+                self.stale.extend(
+                    cells
+                        .split_whitespace()
+                        .filter_map(|address| address.parse::<CellIdentifier>().ok()),
+                );
+            }
+            (Some(Request::Formula(cell)), Reply::Value(_, CellValue::String(expr))) => {
+                self.exprs.insert(cell, expr);
+            }
+            (Some(Request::Formula(cell)), Reply::Value(_, CellValue::None)) => {
+                self.exprs.remove(&cell);
+            }
+            (_, Reply::Value(name, value)) => self.status = format!("{} = {}", name, value),
+            (_, Reply::Error(e)) => self.status = format!("Error: {}", e),
+        }
+    }
+
+    /// Scrolls so that the cursor is within the visible columns and rows.
+    fn scroll_to_cursor(&mut self, cols: u32, rows: u32) {
+        let top_left = &mut self.top_left;
+        top_left.col = top_left
+            .col
+            .clamp((self.cursor.col + 1).saturating_sub(cols), self.cursor.col);
+        top_left.row = top_left
+            .row
+            .clamp((self.cursor.row + 1).saturating_sub(rows), self.cursor.row);
+    }
+
+    fn handle_key(&mut self, key: KeyEvent, rows: u32) -> Action {
+        if key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL) {
+            return Action::Quit;
+        }
+
+        if let Some(buffer) = self.editing.as_mut() {
+            match key.code {
+                KeyCode::Char(c) => buffer.push(c),
+                KeyCode::Backspace => {
+                    buffer.pop();
+                }
+                KeyCode::Esc => self.editing = None,
+                KeyCode::Enter => {
+                    let expr = self.editing.take().unwrap_or_default();
+                    if !expr.trim().is_empty() {
+                        self.exprs.insert(self.cursor, expr.clone());
+                        return Action::Set(self.cursor, expr);
+                    }
+                }
+                _ => {}
+            }
+            return Action::None;
+        }
+
+        let cursor = &mut self.cursor;
+        match key.code {
+            KeyCode::Esc => return Action::Quit,
+            KeyCode::Up => cursor.row = cursor.row.saturating_sub(1),
+            KeyCode::Down => cursor.row += 1,
+            KeyCode::Left => cursor.col = cursor.col.saturating_sub(1),
+            KeyCode::Right => cursor.col += 1,
+            KeyCode::PageUp => cursor.row = cursor.row.saturating_sub(rows),
+            KeyCode::PageDown => cursor.row += rows,
+            KeyCode::Home => cursor.col = 0,
+            KeyCode::Enter | KeyCode::F(2) => {
+                self.editing = Some(self.exprs.get(cursor).cloned().unwrap_or_default());
+            }
+            // Typing starts a new expression, like most spreadsheets
+            KeyCode::Char(c) => self.editing = Some(c.to_string()),
+            _ => {}
+        }
+        Action::None
+    }
+}
+
+fn cell_address(cell: &CellIdentifier) -> String {
+    format!("{}{}", column_number_to_name(cell.col), cell.row + 1)
+}
+
+/// Formats a value to fit in a cell, right aligning numbers.
+fn format_cell(value: Option<&CellValue>, width: usize) -> String {
+    let text = match value {
+        Some(CellValue::Int(i)) => return format!("{:>width$.width$}", i.to_string()),
+        Some(CellValue::String(s)) => s.clone(),
+        Some(CellValue::Error(_)) => "#ERROR".to_string(),
+        Some(CellValue::None) | None => String::new(),
+    };
+    format!("{:<width$.width$}", text)
+}
+
+/// Returns the number of columns and rows of cells that fit in the terminal.
+fn visible_size(width: u16, height: u16) -> (u32, u32) {
+    let cols = width.saturating_sub(ROW_LABEL_WIDTH) / CELL_WIDTH;
+    // One line for the column names, and two for the edit and status lines
+    let rows = height.saturating_sub(3);
+    (u32::from(cols.max(1)), u32::from(rows.max(1)))
+}
+
+fn draw(out: &mut impl Write, grid: &Grid, width: u16, height: u16) -> io::Result<()> {
+    let (cols, rows) = visible_size(width, height);
+    let cell_width = usize::from(CELL_WIDTH - 1);
+    queue!(out, terminal::Clear(ClearType::All), cursor::MoveTo(0, 0))?;
+
+    // Column names
+    queue!(out, SetAttribute(Attribute::Bold))?;
+    queue!(out, Print(" ".repeat(usize::from(ROW_LABEL_WIDTH))))?;
+    for col in grid.top_left.col..grid.top_left.col + cols {
+        let name = column_number_to_name(col);
+        queue!(out, Print(format!("{:^cell_width$} ", name)))?;
+    }
+    queue!(out, SetAttribute(Attribute::Reset))?;
+
+    for (line, row) in (grid.top_left.row..grid.top_left.row + rows).enumerate() {
+        let label_width = usize::from(ROW_LABEL_WIDTH - 1);
+        queue!(
+            out,
+            cursor::MoveTo(0, line as u16 + 1),
+            SetAttribute(Attribute::Bold),
+            Print(format!("{:>label_width$} ", row + 1)),
+            SetAttribute(Attribute::Reset)
+        )?;
+
+        for col in grid.top_left.col..grid.top_left.col + cols {
+            let cell = CellIdentifier { col, row };
+            let text = format_cell(grid.values.get(&cell), cell_width);
+            if cell == grid.cursor {
+                queue!(out, SetAttribute(Attribute::Reverse))?;
+            } else if grid.stale.contains(&cell) {
+                queue!(out, SetAttribute(Attribute::Dim))?;
+            }
+            queue!(out, Print(text), SetAttribute(Attribute::Reset), Print(" "))?;
+        }
+    }
+
+    // The edit line shows the selected cell, or the expression being typed
+    let address = cell_address(&grid.cursor);
+    let edit_line = match &grid.editing {
+        Some(buffer) => format!("{} := {}_", address, buffer),
+        None => {
+            let value = grid.values.get(&grid.cursor).unwrap_or(&CellValue::None);
+            match grid.exprs.get(&grid.cursor) {
+                Some(expr) => format!("{} = {}    ({})", address, value, expr),
+                None => format!("{} = {}", address, value),
+            }
+        }
+    };
+    queue!(
+        out,
+        cursor::MoveTo(0, height.saturating_sub(2)),
+        Print(edit_line),
+        cursor::MoveTo(0, height.saturating_sub(1)),
+        SetAttribute(Attribute::Dim),
+        Print(&grid.status),
+        SetAttribute(Attribute::Reset)
+    )?;
+    out.flush()
+}
+
+impl Request {
+    fn message(&self) -> String {
+        match self {
+            Request::Get(cell) => format!("get {}", cell_address(cell)),
+            Request::Set(cell, expr) => format!("set {} {}", cell_address(cell), expr),
+            Request::Dirty => "dirty".to_string(),
+            Request::Formula(cell) => format!("formula {}", cell_address(cell)),
+        }
+    }
+}
+
+/// Messages waiting to be sent to the server, and those waiting for a reply.
+///
+/// The server answers one message at a time, so replies are matched to requests in the order
+/// they were sent. A `set` is always followed by a `dirty`, whose reply is always a value, so
+/// an error arriving before it is the `set` failing.
+///
+/// The server reads each connection through a small fixed buffer, and drops the connection if
+/// that buffer fills up without a complete line. So only a few requests are in flight at a
+/// time, and a `set` is only sent once every earlier request has been answered.
+#[derive(Default)]
+struct Outbox {
+    queue: VecDeque<Request>,
+    in_flight: VecDeque<Request>,
+}
+
+impl Outbox {
+    /// Queues a `get` of every visible cell, then asks which cells are stale and for the
+    /// expression of the selected cell, which may have been set by another client.
+    fn request_visible(&mut self, grid: &Grid, cols: u32, rows: u32) {
+        for row in grid.top_left.row..grid.top_left.row + rows {
+            for col in grid.top_left.col..grid.top_left.col + cols {
+                self.queue
+                    .push_back(Request::Get(CellIdentifier { col, row }));
+            }
+        }
+        self.queue.push_back(Request::Dirty);
+        self.queue.push_back(Request::Formula(grid.cursor));
+    }
+
+    /// Queues a `set`, followed by the `dirty` which shows whether it failed.
+    fn request_set(&mut self, cell: CellIdentifier, expr: String) {
+        self.queue.push_back(Request::Set(cell, expr));
+        self.queue.push_back(Request::Dirty);
+    }
+
+    /// Finds the request a reply answers.
+    ///
+    /// # Returns
+    /// The request, or `None` if nothing was waiting for a reply.
+    fn received(&mut self, reply: &Reply) -> Option<Request> {
+        let request = self.in_flight.pop_front()?;
+        match (request, reply) {
+            (Request::Set(cell, expr), Reply::Error(_)) => Some(Request::Set(cell, expr)),
+            // The `set` succeeded, so this answers the `dirty` after it
+            (Request::Set(..), Reply::Value(..)) => self.in_flight.pop_front(),
+            (request, _) => Some(request),
+        }
+    }
+
+    /// Sends as many queued messages as the server can safely buffer.
+    fn flush(&mut self, stream: &mut impl Write) -> io::Result<()> {
+        while let Some(request) = self.queue.front() {
+            let is_set = matches!(request, Request::Set(..));
+            if (is_set && !self.in_flight.is_empty()) || self.in_flight.len() >= MAX_IN_FLIGHT {
+                break;
+            }
+            // One write per line, since the server cannot handle a line split across reads
+            stream.write_all(format!("{}\n", request.message()).as_bytes())?;
+            self.in_flight.extend(self.queue.pop_front());
+        }
+        Ok(())
+    }
+}
+
+/// Reads replies from the server on a separate thread, so the grid never blocks on the network.
+fn spawn_reply_reader(stream: TcpStream) -> Receiver<Reply> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for line in BufReader::new(stream).lines() {
+            let Ok(line) = line else {
+                return;
+            };
+            if let Ok(reply) = serde_json::from_str::<Reply>(&line) {
+                if sender.send(reply).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+    receiver
+}
+
+fn run(mut stream: TcpStream, replies: Receiver<Reply>) -> Result<(), Box<dyn Error>> {
+    let mut out = io::stdout();
+    let mut grid = Grid::new();
+    let mut outbox = Outbox::default();
+    let mut last_refresh: Option<Instant> = None;
+    let mut connected = true;
+    let mut redraw = true;
+
+    loop {
+        loop {
+            match replies.try_recv() {
+                Ok(reply) => {
+                    let request = outbox.received(&reply);
+                    grid.apply_reply(request, reply);
+                    redraw = true;
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    if connected {
+                        grid.status = "Disconnected from server, Esc quits".to_string();
+                        connected = false;
+                        redraw = true;
+                    }
+                    break;
+                }
+            }
+        }
+
+        let (width, height) = terminal::size()?;
+        let (cols, rows) = visible_size(width, height);
+        grid.scroll_to_cursor(cols, rows);
+
+        // Skip a refresh while the previous one is still being sent
+        let refresh_due = last_refresh.is_none_or(|last| last.elapsed() >= REFRESH_INTERVAL);
+        if connected && refresh_due && outbox.queue.is_empty() {
+            outbox.request_visible(&grid, cols, rows);
+            last_refresh = Some(Instant::now());
+        }
+        if connected {
+            outbox.flush(&mut stream)?;
+        }
+
+        if redraw {
+            draw(&mut out, &grid, width, height)?;
+            redraw = false;
+        }
+
+        if !event::poll(POLL_INTERVAL)? {
+            continue;
+        }
+        match event::read()? {
+            Event::Key(key) if key.kind == KeyEventKind::Press => {
+                match grid.handle_key(key, rows) {
+                    Action::None => {}
+                    Action::Quit => return Ok(()),
+                    Action::Set(cell, expr) => {
+                        outbox.request_set(cell, expr);
+                        // Refresh straight away to show the effect of the edit
+                        last_refresh = None;
+                    }
+                }
+                redraw = true;
+            }
+            Event::Resize(..) => redraw = true,
+            _ => {}
+        }
+    }
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let args = Args::parse();
+
+    let addr = resolve_address(&args.addr)?;
+    let stream = TcpStream::connect(addr)?;
+    let replies = spawn_reply_reader(stream.try_clone()?);
+
+    let mut out = io::stdout();
+    terminal::enable_raw_mode()?;
+    execute!(out, terminal::EnterAlternateScreen, cursor::Hide)?;
+
+    let result = run(stream, replies);
+
+    // Always give the terminal back, even if the client failed
+    execute!(out, cursor::Show, terminal::LeaveAlternateScreen)?;
+    terminal::disable_raw_mode()?;
+    result
+}
+
+// ===================== TESTS ============================
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cell(address: &str) -> CellIdentifier {
+        address.parse().unwrap()
+    }
+
+    // 1. Test scrolling keeps the cursor visible, stopping at the top and left edges
+    #[test]
+    fn test_scroll_to_cursor() {
+        let mut grid = Grid::new();
+        grid.cursor = cell("J20");
+        grid.scroll_to_cursor(5, 10);
+        assert_eq!(grid.top_left, cell("F11"));
+
+        // Moving within the visible cells does not scroll
+        grid.cursor = cell("G15");
+        grid.scroll_to_cursor(5, 10);
+        assert_eq!(grid.top_left, cell("F11"));
+
+        grid.cursor = cell("A1");
+        grid.scroll_to_cursor(5, 10);
+        assert_eq!(grid.top_left, cell("A1"));
+
+        // A grid too small for any cells still shows the cursor
+        grid.cursor = cell("C3");
+        grid.scroll_to_cursor(1, 1);
+        assert_eq!(grid.top_left, cell("C3"));
+    }
+
+    // 2. Test values are aligned and truncated to the cell width
+    #[test]
+    fn test_format_cell() {
+        assert_eq!(format_cell(Some(&CellValue::Int(42)), 6), "    42");
+        assert_eq!(format_cell(Some(&CellValue::Int(-1234567)), 6), "-12345");
+        assert_eq!(
+            format_cell(Some(&CellValue::String("hello world".to_string())), 6),
+            "hello "
+        );
+        assert_eq!(
+            format_cell(Some(&CellValue::String("hi".to_string())), 6),
+            "hi    "
+        );
+        assert_eq!(
+            format_cell(Some(&CellValue::Error("oops".to_string())), 6),
+            "#ERROR"
+        );
+        assert_eq!(format_cell(Some(&CellValue::None), 6), "      ");
+        assert_eq!(format_cell(None, 6), "      ");
+    }
+
+    // 3. Test replies are stored against the request they answer
+    #[test]
+    fn test_apply_reply() {
+        let mut grid = Grid::new();
+        grid.apply_reply(
+            Some(Request::Get(cell("B2"))),
+            Reply::Value("B2".to_string(), CellValue::Int(7)),
+        );
+        assert_eq!(grid.values.get(&cell("B2")), Some(&CellValue::Int(7)));
+
+        // An error from a get is the cell's value, whatever the message says
+        grid.apply_reply(
+            Some(Request::Get(cell("C3"))),
+            Reply::Error("Cannot get cell C3: depends on an error".to_string()),
+        );
+        assert!(matches!(
+            grid.values.get(&cell("C3")),
+            Some(CellValue::Error(_))
+        ));
+
+        grid.apply_reply(
+            Some(Request::Dirty),
+            Reply::Value("dirty".to_string(), CellValue::String("A1 B2".to_string())),
+        );
+        assert_eq!(grid.stale, HashSet::from([cell("A1"), cell("B2")]));
+
+        // A get marks its cell stale or not, whether or not `dirty` listed it
+        grid.apply_reply(
+            Some(Request::Get(cell("B2"))),
+            Reply::Value("B2".to_string(), CellValue::Int(8)),
+        );
+        grid.apply_reply(
+            Some(Request::Get(cell("C1"))),
+            Reply::Value("C1 (stale)".to_string(), CellValue::Int(1)),
+        );
+        assert_eq!(grid.stale, HashSet::from([cell("A1"), cell("C1")]));
+
+        // Expressions set by other clients are learned from `formula`
+        grid.apply_reply(
+            Some(Request::Formula(cell("E5"))),
+            Reply::Value("E5".to_string(), CellValue::String("A1 + 1".to_string())),
+        );
+        assert_eq!(
+            grid.exprs.get(&cell("E5")).map(String::as_str),
+            Some("A1 + 1")
+        );
+        grid.apply_reply(
+            Some(Request::Formula(cell("E5"))),
+            Reply::Value("E5".to_string(), CellValue::None),
+        );
+        assert!(!grid.exprs.contains_key(&cell("E5")));
+
+        // A failed set is only shown in the status line
+        grid.apply_reply(
+            Some(Request::Set(cell("D4"), "1 +".to_string())),
+            Reply::Error("invalid expression".to_string()),
+        );
+        assert_eq!(grid.status, "Error: invalid expression");
+        assert!(!grid.values.contains_key(&cell("D4")));
+    }
+
+    // 4. Test replies are matched to requests, whether or not a set failed
+    #[test]
+    fn test_outbox_matches_replies() {
+        let mut outbox = Outbox::default();
+        let mut sent = Vec::new();
+        outbox.request_set(cell("A1"), "1".to_string());
+        outbox.request_set(cell("A2"), "oops".to_string());
+        outbox.queue.push_back(Request::Get(cell("A3")));
+
+        outbox.flush(&mut sent).unwrap();
+        assert_eq!(
+            String::from_utf8(sent.clone()).unwrap(),
+            "set A1 1\ndirty\n"
+        );
+
+        // The first set succeeded, so its only reply is to the dirty after it
+        let dirty = Reply::Value("dirty".to_string(), CellValue::String(String::new()));
+        assert_eq!(outbox.received(&dirty), Some(Request::Dirty));
+        assert!(outbox.in_flight.is_empty());
+
+        // The second set failed, so it is replied to before its dirty
+        outbox.flush(&mut sent).unwrap();
+        let error = Reply::Error("invalid".to_string());
+        assert_eq!(
+            outbox.received(&error),
+            Some(Request::Set(cell("A2"), "oops".to_string()))
+        );
+        assert_eq!(outbox.received(&dirty), Some(Request::Dirty));
+
+        let value = Reply::Value("A3".to_string(), CellValue::None);
+        assert_eq!(outbox.received(&value), Some(Request::Get(cell("A3"))));
+        assert_eq!(outbox.received(&value), None);
+    }
+
+    // 5. Test no more than MAX_IN_FLIGHT requests are sent before replies arrive
+    #[test]
+    fn test_outbox_limits_in_flight() {
+        let mut grid = Grid::new();
+        grid.top_left = cell("A1");
+        let mut outbox = Outbox::default();
+        outbox.request_visible(&grid, 5, 5);
+        outbox.request_set(cell("A1"), "1".to_string());
+
+        let mut sent = Vec::new();
+        outbox.flush(&mut sent).unwrap();
+        assert_eq!(outbox.in_flight.len(), MAX_IN_FLIGHT);
+
+        // The set waits until every get has been answered
+        let value = Reply::Value("A1".to_string(), CellValue::None);
+        while !outbox.in_flight.is_empty() {
+            outbox.received(&value);
+            outbox.flush(&mut sent).unwrap();
+        }
+        let sent = String::from_utf8(sent).unwrap();
+        assert_eq!(
+            sent.lines().filter(|line| line.starts_with("get ")).count(),
+            25
+        );
+        assert!(sent.ends_with("dirty\nformula A1\nset A1 1\ndirty\n"));
+    }
+}
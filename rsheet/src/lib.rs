@@ -1,4 +1,13 @@
-use log::info;
+pub mod access;
+mod array;
+pub mod background;
+pub mod limits;
+mod metrics;
+mod query;
+pub mod replication;
+
+use access::AccessConfig;
+use log::{debug, info, warn};
 use rsheet_lib::cell_expr::{CellArgument, CellExpr, CellExprEvalError};
 use rsheet_lib::cell_value::CellValue;
 use rsheet_lib::cells::column_number_to_name;
@@ -11,6 +20,7 @@ use std::collections::HashMap;
 use std::collections::HashSet;
 use std::error::Error;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use std::thread;
 
@@ -19,12 +29,40 @@ type ExprMap = Mutex<HashMap<String, String>>;
 type DepMap = Mutex<HashMap<String, HashSet<String>>>;
 
 // Initialize the all global maps
+//
+// Any code holding more than one of the maps at once locks them in this order, so that no two
+// threads can each wait for a map the other holds: CELL_MAP, EXPR_MAP, CELL_ERRORS, DEPENDERS,
+// then DEPENDENCIES. ACCESS_CONFIG may be locked after any of them, but never before.
 lazy_static::lazy_static! {
     static ref CELL_MAP: CellMap = Mutex::new(HashMap::new());
     static ref EXPR_MAP: ExprMap = Mutex::new(HashMap::new());
     static ref DEPENDENCIES: DepMap = Mutex::new(HashMap::new());
     static ref DEPENDERS: DepMap = Mutex::new(HashMap::new());
     static ref CELL_ERRORS: ExprMap = Mutex::new(HashMap::new());
+    static ref ACCESS_CONFIG: Mutex<AccessConfig> = Mutex::new(AccessConfig::default());
+}
+
+/// Sets the access control configuration used to authorise `Set` commands.
+///
+/// Without a configuration, every sender may write to every cell.
+///
+/// # Parameters
+/// * `config`: The parsed access control configuration.
+pub fn set_access_config(config: AccessConfig) {
+    *ACCESS_CONFIG.lock().unwrap() = config;
+}
+
+/// Clears every cell, expression, error and dependency, as if the server had just started.
+///
+/// This lets several scripted tests drive `start_server` one after another in one process.
+pub fn reset_state() {
+    CELL_MAP.lock().unwrap().clear();
+    EXPR_MAP.lock().unwrap().clear();
+    CELL_ERRORS.lock().unwrap().clear();
+    DEPENDERS.lock().unwrap().clear();
+    DEPENDENCIES.lock().unwrap().clear();
+    array::reset();
+    background::reset();
 }
 
 // ===================== STAGE 3 ============================
@@ -36,7 +74,9 @@ struct SenderHandle {
 
 /// Starts the server and listens for incoming connections. Handles `Get` and `Set` commands.
 ///
-/// The function listens for incoming connections. Every new connection starts a new thread.
+/// The function listens for incoming connections. Every new connection starts a new thread, so
+/// senders are served concurrently, and all of them are joined once there are no more
+/// connections.
 ///
 /// # Parameters
 /// * `manager`: An instance of `Manager` that accepts new connections.
@@ -48,6 +88,7 @@ where
     M: Manager,
 {
     let mut sender_map: HashMap<String, Arc<SenderHandle>> = HashMap::new();
+    let mut handles = Vec::new();
 
     loop {
         info!("Just got a message!");
@@ -65,19 +106,21 @@ where
 
                 let sender_handle = Arc::clone(sender_handle);
 
-                // Spawn a thread to handle the new connection
-                let handle = thread::spawn(move || {
+                // Spawn a thread to handle the new connection, without waiting for it to close
+                handles.push(thread::spawn(move || {
                     if let Err(e) = handle_connection(sender_handle, &mut reader, &mut writer) {
                         eprintln!("Error handling connection for {}: {}", sender_name, e);
                     }
-                });
-
-                handle.join().unwrap();
+                }));
             }
             Connection::NoMoreConnections => break,
         }
     }
 
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
     Ok(())
 }
 
@@ -98,21 +141,74 @@ fn handle_connection(
     recv: &mut dyn Reader,
     send: &mut dyn Writer,
 ) -> Result<(), Box<dyn Error>> {
+    // Authenticate the sender once for the whole connection
+    let role = metrics::timed_lock("ACCESS_CONFIG", &ACCESS_CONFIG).role_for(&recv.id());
+
     loop {
         match recv.read_message() {
             ReadMessageResult::Message(msg) => {
                 // Acquire the lock for the sender's sequence
                 let _lock = reader.order_mutex.lock().unwrap();
+                metrics::record_command();
+
+                // `stats` is not a `Command`, so it is checked for before parsing
+                if msg.trim() == "stats" {
+                    for reply in metrics::stats_replies() {
+                        match send.write_message(reply) {
+                            WriteMessageResult::Ok => {}
+                            WriteMessageResult::ConnectionClosed => return Ok(()),
+                            WriteMessageResult::Err(e) => return Err(Box::new(e)),
+                        }
+                    }
+                    continue;
+                }
+
+                // Nor is `dirty`, which lists the cells whose values are stale
+                if msg.trim() == "dirty" {
+                    let dirty = CellValue::String(background::dirty_cells().join(" "));
+                    match send.write_message(Reply::Value("dirty".to_string(), dirty)) {
+                        WriteMessageResult::Ok => {}
+                        WriteMessageResult::ConnectionClosed => return Ok(()),
+                        WriteMessageResult::Err(e) => return Err(Box::new(e)),
+                    }
+                    continue;
+                }
+
+                // Or `formula`, which replies with the expression a cell was set to
+                if let Some(text) = msg.trim().strip_prefix("formula ") {
+                    match send.write_message(handle_formula(text)) {
+                        WriteMessageResult::Ok => {}
+                        WriteMessageResult::ConnectionClosed => return Ok(()),
+                        WriteMessageResult::Err(e) => return Err(Box::new(e)),
+                    }
+                    continue;
+                }
+
+                // Neither is `query`, which replies with a whole table
+                if let Some(text) = msg.trim().strip_prefix("query ") {
+                    for reply in handle_query(text, role.as_deref()) {
+                        match send.write_message(reply) {
+                            WriteMessageResult::Ok => {}
+                            WriteMessageResult::ConnectionClosed => return Ok(()),
+                            WriteMessageResult::Err(e) => return Err(Box::new(e)),
+                        }
+                    }
+                    continue;
+                }
 
                 // Handle the message
                 let reply = match msg.parse::<Command>() {
                     Ok(command) => match command {
-                        Command::Get { cell_identifier } => handle_get(&cell_identifier),
+                        Command::Get { cell_identifier } => {
+                            handle_get(&cell_identifier, background::DIRTY_WAIT_TIMEOUT)
+                        }
                         Command::Set {
                             cell_identifier,
                             cell_expr,
                         } => {
-                            if let Some(reply) = handle_set(&cell_identifier, &cell_expr) {
+                            if let Some(reply) =
+                                handle_set(&cell_identifier, &cell_expr, role.as_deref())
+                            {
                                 reply
                             } else {
                                 continue;
@@ -177,23 +273,11 @@ fn evaluate_expr(
     dependers: &mut HashMap<String, HashSet<String>>,
     dependencies: &mut HashMap<String, HashSet<String>>,
 ) {
-    let mut new_dependers: HashSet<String> = HashSet::new();
-    let variables = parse_expr_args(&expr, cells, &mut new_dependers);
+    let _nested = metrics::Nested::enter();
+    let (new_dependers, spilled) = evaluate_cell(expr, cells, &cell_address, cell_errors, exprs);
 
-    let result: Result<CellValue, CellExprEvalError> = expr.evaluate(&variables);
-    match result {
-        // Ok -> Store
-        Ok(value) => {
-            cells.insert(cell_address.clone(), value.clone());
-            cell_errors.remove(&cell_address);
-        }
-        // Eval Error
-        Err(e) => {
-            cell_errors.insert(cell_address.clone(), format!("{:?}", e));
-        }
-    }
     process_dependencies(
-        new_dependers.clone(),
+        new_dependers,
         cell_address.clone(),
         exprs,
         cells,
@@ -201,6 +285,105 @@ fn evaluate_expr(
         dependers,
         dependencies,
     );
+
+    // Cells changed by a spill have dependents of their own
+    for spilled_cell in spilled {
+        update_dependencies(
+            spilled_cell,
+            dependencies,
+            exprs,
+            cells,
+            cell_errors,
+            dependers,
+        );
+    }
+}
+
+/// Evaluates a cell without re-evaluating anything that depends on it.
+///
+/// Array formulas spill their result into the neighbouring cells. Any other expression is
+/// evaluated into the cell alone, clearing anything the cell spilled before.
+///
+/// # Parameters
+/// * `expr`: The `CellExpr` to evaluate.
+/// * `cells`: A mutable reference to the map of cell values.
+/// * `cell_address`: The address of the cell being evaluated.
+/// * `cell_errors`: A mutable reference to the map of errors.
+/// * `exprs`: A reference to the map of expressions, used to find array formulas.
+///
+/// # Returns
+/// The set of cells the expression reads from, and the spilled cells whose values changed.
+fn evaluate_cell(
+    expr: CellExpr,
+    cells: &mut HashMap<String, CellValue>,
+    cell_address: &str,
+    cell_errors: &mut HashMap<String, String>,
+    exprs: &HashMap<String, String>,
+) -> (HashSet<String>, Vec<String>) {
+    let _span = metrics::Span::evaluate(cell_address);
+    match exprs
+        .get(cell_address)
+        .and_then(|e| array::ArrayFormula::parse(e))
+    {
+        Some(formula) => {
+            let spilled = array::spill(&formula, cells, cell_address, cell_errors, exprs);
+            (spilled.inputs, spilled.changed)
+        }
+        None => {
+            let new_dependers = evaluate_value(expr, cells, cell_address, cell_errors);
+            (new_dependers, array::clear(cell_address, cells, exprs))
+        }
+    }
+}
+
+/// Evaluates a cell expression and stores the result, without touching any other cell.
+///
+/// # Parameters
+/// * `expr`: The `CellExpr` to evaluate.
+/// * `cells`: A mutable reference to the map of cell values.
+/// * `cell_address`: The address of the cell being evaluated.
+/// * `cell_errors`: A mutable reference to the map of errors.
+///
+/// # Returns
+/// The set of cells the expression reads from.
+fn evaluate_value(
+    expr: CellExpr,
+    cells: &mut HashMap<String, CellValue>,
+    cell_address: &str,
+    cell_errors: &mut HashMap<String, String>,
+) -> HashSet<String> {
+    let mut new_dependers: HashSet<String> = HashSet::new();
+    let variables = match parse_expr_args(&expr, cells, &mut new_dependers) {
+        Ok(variables) => variables,
+        // A range over the limits is never read, so the cell holds why instead
+        Err(e) => {
+            warn!("Rejected range read by {}: {}", cell_address, e);
+            cell_errors.insert(cell_address.to_string(), e);
+            return new_dependers;
+        }
+    };
+
+    let start = Instant::now();
+    let result: Result<CellValue, CellExprEvalError> = expr.evaluate(&variables);
+    metrics::record_eval_latency(start.elapsed());
+    match result {
+        // Ok -> Store, unless the value is over the limits
+        Ok(value) => match limits::current().check_value(&value) {
+            Ok(()) => {
+                cells.insert(cell_address.to_string(), value.clone());
+                cell_errors.remove(cell_address);
+            }
+            Err(e) => {
+                warn!("Rejected value of {}: {}", cell_address, e);
+                cell_errors.insert(cell_address.to_string(), e);
+            }
+        },
+        // Eval Error
+        Err(e) => {
+            cell_errors.insert(cell_address.to_string(), format!("{:?}", e));
+        }
+    }
+    new_dependers
 }
 
 // ===================== STAGE 1 ============================
@@ -208,18 +391,25 @@ fn evaluate_expr(
 /// Handles a `Get` command and retrieves the value of a cell.
 ///
 /// The function checks if the requested cell exists and if it has any errors. If the cell has errors,
-/// an error message is returned. Otherwise, the value of the cell is returned.
+/// an error message is returned. Otherwise, the value of the cell is returned. If the cell is still
+/// being recalculated in the background, its last value is returned, and the cell is listed by
+/// the `dirty` command until it has been recalculated.
 ///
 /// # Parameters
 /// * `cell_identifier`: A reference to the `CellIdentifier` that identifies the cell.
+/// * `timeout`: How long to wait for the cell if it is being recalculated in the background.
 ///
 /// # Returns
-/// A `Reply` containing either the value of the cell or an error message.
-fn handle_get(cell_identifier: &CellIdentifier) -> Reply {
+/// A `Reply` containing either the value of the cell or an error message. If the cell was
+/// still dirty, its name in the reply is followed by `background::STALE_SUFFIX`.
+fn handle_get(cell_identifier: &CellIdentifier, timeout: Duration) -> Reply {
     let cell_address = cell_to_string(cell_identifier);
 
-    let cell_errors = CELL_ERRORS.lock().unwrap();
-    let cells = CELL_MAP.lock().unwrap();
+    // Wait for background recalculation before taking the locks the workers need
+    let stale = background::wait_until_clean(&cell_address, timeout);
+
+    let cells = metrics::timed_lock("CELL_MAP", &CELL_MAP);
+    let cell_errors = metrics::timed_lock("CELL_ERRORS", &CELL_ERRORS);
 
     // Check if any cells are depending on errors
     if let Some(error) = cell_errors.get(&cell_address) {
@@ -230,10 +420,34 @@ fn handle_get(cell_identifier: &CellIdentifier) -> Reply {
     }
 
     // Otherwise, proceed with checking cells
-    match cells.get(&cell_address) {
-        Some(value) => Reply::Value(cell_address, value.clone()),
-        None => Reply::Value(cell_address, CellValue::None),
+    let value = cells.get(&cell_address).cloned().unwrap_or(CellValue::None);
+    if stale {
+        debug!("Replying with the stale value of {}", cell_address);
+        return Reply::Value(cell_address + background::STALE_SUFFIX, value);
     }
+    Reply::Value(cell_address, value)
+}
+
+/// Handles a `formula` command, which looks up the expression a cell was last set to.
+///
+/// # Parameters
+/// * `text`: The address of the cell, after `formula`.
+///
+/// # Returns
+/// A `Reply` with the expression as a string, or `None` if the cell was never set.
+fn handle_formula(text: &str) -> Reply {
+    let cell_identifier = match text.trim().parse::<CellIdentifier>() {
+        Ok(cell_identifier) => cell_identifier,
+        Err(e) => return Reply::Error(format!("Error parsing command: {}", e)),
+    };
+    let cell_address = cell_to_string(&cell_identifier);
+
+    let exprs = metrics::timed_lock("EXPR_MAP", &EXPR_MAP);
+    let expr = match exprs.get(&cell_address) {
+        Some(expr) => CellValue::String(expr.clone()),
+        None => CellValue::None,
+    };
+    Reply::Value(cell_address, expr)
 }
 
 /// Handles a `Set` command and evaluates an expression to update a cell's value.
@@ -244,33 +458,218 @@ fn handle_get(cell_identifier: &CellIdentifier) -> Reply {
 /// # Parameters
 /// * `cell_identifier`: A reference to the `CellIdentifier` identifying the cell to update.
 /// * `cell_expr`: The expression to evaluate for the cell.
+/// * `role`: The role of the sender, used to check the cell is not in a locked range.
 ///
 /// # Returns
 /// An `Option<Reply>` that is `None` when the operation is successful, or a `Reply` with an error if something goes wrong.
-fn handle_set(cell_identifier: &CellIdentifier, cell_expr: &str) -> Option<Reply> {
-    let mut cells = CELL_MAP.lock().unwrap();
-    let mut exprs = EXPR_MAP.lock().unwrap();
-    let mut cell_errors = CELL_ERRORS.lock().unwrap();
-    let mut dependers = DEPENDERS.lock().unwrap();
-    let mut dependencies = DEPENDENCIES.lock().unwrap();
+fn handle_set(
+    cell_identifier: &CellIdentifier,
+    cell_expr: &str,
+    role: Option<&str>,
+) -> Option<Reply> {
+    let cell_address = cell_to_string(cell_identifier);
+
+    // Followers only ever take sets from their primary
+    if let Some(primary) = replication::primary_address() {
+        return Some(Reply::Error(format!(
+            "Cannot set cell {}: this server is a read-only follower of {}, send sets to the primary instead",
+            cell_address, primary
+        )));
+    }
+
+    // Reject writes to locked ranges before touching any cells
+    if let Err(e) =
+        metrics::timed_lock("ACCESS_CONFIG", &ACCESS_CONFIG).check_write(role, cell_identifier)
+    {
+        warn!("Rejected set of {} by role {:?}: {}", cell_address, role, e);
+        return Some(Reply::Error(format!(
+            "Cannot set cell {}: {}",
+            cell_address, e
+        )));
+    }
+
+    // Reject anything which would grow the sheet past its limits
+    if let Err(e) = apply_set(cell_identifier, cell_expr, SetOrigin::Sender(role)) {
+        return Some(Reply::Error(format!(
+            "Cannot set cell {}: {}",
+            cell_address, e
+        )));
+    }
+
+    None
+}
+
+/// Checks a `Set` against the configured limits before anything is stored.
+///
+/// # Parameters
+/// * `cell_address`: The address of the cell being set.
+/// * `cell_expr`: The expression the cell is being set to.
+/// * `cells`: A reference to the map of cell values, which must stay locked until the set is
+///   applied, so no other set can take the last free cell in between.
+/// * `exprs`: A reference to the map of expressions, locked for the same reason.
+///
+/// # Returns
+/// `Ok(())` if the set is within every limit, otherwise why it is not.
+fn check_limits(
+    cell_address: &str,
+    cell_expr: &str,
+    cells: &HashMap<String, CellValue>,
+    exprs: &HashMap<String, String>,
+) -> Result<(), String> {
+    let limits = limits::current();
+    limits.check_expr(cell_expr)?;
+
+    let is_new = !cells.contains_key(cell_address) && !exprs.contains_key(cell_address);
+    limits.check_new_cell(is_new, cells.len().max(exprs.len()))
+}
+
+/// Where a `Set` came from.
+#[derive(Debug, Clone, Copy)]
+enum SetOrigin<'a> {
+    /// A sender with the given role, if it has one.
+    Sender(Option<&'a str>),
+    /// The primary, replayed by a follower. The primary has already checked who sent it.
+    Primary,
+}
+
+/// Checks an expression against the limits, then stores and evaluates it for a cell and
+/// forwards the set to any followers.
+///
+/// Unlike `handle_set`, no checks are made on who sent the command. This is also used by
+/// followers to replay sets which were already accepted by the primary, which are held to
+/// the follower's own limits.
+///
+/// # Parameters
+/// * `cell_identifier`: A reference to the `CellIdentifier` identifying the cell to update.
+/// * `cell_expr`: The expression to evaluate for the cell.
+/// * `origin`: Where the set came from. An array formula set by a sender may only spill into
+///   cells that sender could set itself.
+///
+/// # Returns
+/// `Ok(())` if the set was applied, otherwise which limit it is over.
+fn apply_set(
+    cell_identifier: &CellIdentifier,
+    cell_expr: &str,
+    origin: SetOrigin,
+) -> Result<(), String> {
+    let _recalc = metrics::SetRecalc::start();
+    let mut cells = metrics::timed_lock("CELL_MAP", &CELL_MAP);
+    let mut exprs = metrics::timed_lock("EXPR_MAP", &EXPR_MAP);
+    let mut cell_errors = metrics::timed_lock("CELL_ERRORS", &CELL_ERRORS);
+    let mut dependers = metrics::timed_lock("DEPENDERS", &DEPENDERS);
+    let mut dependencies = metrics::timed_lock("DEPENDENCIES", &DEPENDENCIES);
 
     let cell_address = cell_to_string(cell_identifier);
+    if let Err(e) = check_limits(&cell_address, cell_expr, &cells, &exprs) {
+        warn!("Rejected set of {}: {}", cell_address, e);
+        return Err(e);
+    }
     let expr = CellExpr::new(cell_expr);
 
     exprs.insert(cell_address.clone(), (*cell_expr).to_string());
+    match origin {
+        SetOrigin::Sender(role) => array::set_role(&cell_address, role),
+        SetOrigin::Primary => array::forget_role(&cell_address),
+    }
 
-    evaluate_expr(
-        expr,
-        &mut cells,
-        cell_address.clone(),
-        &mut cell_errors,
-        &mut exprs,
-        &mut dependers,
-        &mut dependencies,
-    );
-    // update dependencies
+    // A cell with its own expression blocks any spill covering it, so that spill is redone
+    let mut updated = vec![(cell_address.clone(), expr)];
+    if let Some(anchor) = array::spill_owner(&cell_address) {
+        let anchor_expr = CellExpr::new(&exprs[&anchor]);
+        updated.push((anchor, anchor_expr));
+    }
 
-    None
+    for (cell_address, expr) in updated {
+        if background::enabled() {
+            // Only evaluate this cell, and leave its dependents to the workers
+            let (new_dependers, spilled) =
+                evaluate_cell(expr, &mut cells, &cell_address, &mut cell_errors, &exprs);
+            background::schedule(&cell_address, &new_dependers, &spilled, &dependencies);
+            record_dependencies(
+                new_dependers,
+                &cell_address,
+                &mut dependers,
+                &mut dependencies,
+            );
+        } else {
+            evaluate_expr(
+                expr,
+                &mut cells,
+                cell_address,
+                &mut cell_errors,
+                &mut exprs,
+                &mut dependers,
+                &mut dependencies,
+            );
+        }
+    }
+
+    // Queued while still holding `exprs`, so followers receive sets in the order they were
+    // applied, and written to them once the locks are released
+    replication::broadcast_set(&cell_address, cell_expr);
+    Ok(())
+}
+
+/// Handles a `query` command, which runs a SQL-like statement over a range.
+///
+/// The statement looks like `A1_F500 select B, sum(F) where C = "NSW" group by B`, where the
+/// first row of the range holds the column headers. The result is replied as a table, one
+/// reply per row starting with the header row. If the statement ends with `into H1`, the
+/// result is instead written from `H1` as an array formula, so it updates with its range.
+///
+/// # Parameters
+/// * `text`: The query, after the `query` command itself.
+/// * `role`: The role of the sender, used to check the target cell is not in a locked range.
+///
+/// # Returns
+/// The replies to send back, which is empty when the result was written into the sheet.
+fn handle_query(text: &str, role: Option<&str>) -> Vec<Reply> {
+    let (statement, target) = match text.rsplit_once(" into ") {
+        Some((statement, target)) => match target.trim().parse::<CellIdentifier>() {
+            Ok(target) => (statement.trim(), Some(target)),
+            Err(_) => (text.trim(), None),
+        },
+        None => (text.trim(), None),
+    };
+
+    if let Err(e) = query::Query::parse(statement) {
+        return vec![Reply::Error(format!("Error parsing query: {}", e))];
+    }
+    let expr = format!("query({})", statement);
+
+    if let Some(target) = target {
+        return handle_set(&target, &expr, role).into_iter().collect();
+    }
+
+    let formula = array::ArrayFormula::parse(&expr).expect("query was already parsed");
+    if let Err(e) = formula.check_range() {
+        return vec![Reply::Error(format!("Cannot run query: {}", e))];
+    }
+    let (start, end) = formula.range();
+    let mut stale = false;
+    for row in start.row..=end.row {
+        for col in start.col..=end.col {
+            let cell_address = cell_to_string(&CellIdentifier { col, row });
+            stale |= background::wait_until_clean(&cell_address, background::DIRTY_WAIT_TIMEOUT);
+        }
+    }
+
+    let cells = metrics::timed_lock("CELL_MAP", &CELL_MAP);
+    match formula.evaluate(&cells) {
+        Ok(table) => table
+            .iter()
+            .enumerate()
+            .map(|(index, row)| {
+                let name = match index {
+                    0 if stale => format!("header{}", background::STALE_SUFFIX),
+                    0 => "header".to_string(),
+                    _ => format!("row {}", index),
+                };
+                Reply::Value(name, CellValue::String(query::format_row(row)))
+            })
+            .collect(),
+        Err(e) => vec![Reply::Error(format!("Cannot run query: {}", e))],
+    }
 }
 
 // ===================== STAGE 2 ============================
@@ -286,16 +685,17 @@ fn handle_set(cell_identifier: &CellIdentifier, cell_expr: &str) -> Option<Reply
 /// * `new_dependers`: A mutable set that tracks the variables that depend on the evaluated expression.
 ///
 /// # Returns
-/// A `HashMap<String, CellArgument>` that maps each variable name to its corresponding `CellArgument` value.
+/// A `HashMap<String, CellArgument>` that maps each variable name to its corresponding `CellArgument` value,
+/// or why a range could not be read.
 fn parse_expr_args(
     cell_expr: &CellExpr,
     cells: &HashMap<String, CellValue>,
     new_dependers: &mut HashSet<String>,
-) -> HashMap<String, CellArgument> {
+) -> Result<HashMap<String, CellArgument>, String> {
     // Check for args
     let vars = cell_expr.find_variable_names();
     if vars.is_empty() {
-        return HashMap::new();
+        return Ok(HashMap::new());
     }
     let mut results = HashMap::new();
 
@@ -315,11 +715,11 @@ fn parse_expr_args(
 
                 // Matrix: both row and column are different
                 if row1 != row2 && col1 != col2 {
-                    value = get_matrix(coords, cells, new_dependers);
+                    value = get_matrix(coords, cells, new_dependers)?;
                 }
                 // Vector: either rows or columns are the same
                 else if row1 == row2 || col1 == col2 {
-                    value = get_vector(coords, cells, new_dependers);
+                    value = get_vector(coords, cells, new_dependers)?;
                 }
             }
         } else {
@@ -329,7 +729,7 @@ fn parse_expr_args(
         // Insert
         results.insert(var, value);
     }
-    results
+    Ok(results)
 }
 
 /// Retrieves the value of a specific cell from the `cells` map.
@@ -357,15 +757,17 @@ fn get_value(var: &str, cells: &HashMap<String, CellValue>) -> CellValue {
 /// * `dependers`: A mutable set that tracks which cells depend on this vector.
 ///
 /// # Returns
-/// A `CellArgument::Vector` containing the values of the vector's cells.
+/// A `CellArgument::Vector` containing the values of the vector's cells, or an error if the
+/// vector covers more cells than the range limit allows.
 fn get_vector(
     coords: Vec<&str>,
     cells: &HashMap<String, CellValue>,
     dependers: &mut HashSet<String>,
-) -> CellArgument {
+) -> Result<CellArgument, String> {
     // Extract start and end coordinates
     let start = coords[0];
     let end = coords[1];
+    limits::current().check_range(&coords.join("_"))?;
 
     // Determine if we're dealing with a row or column vector
     let start_row = start.chars().next().unwrap();
@@ -390,7 +792,7 @@ fn get_vector(
         }
     }
 
-    CellArgument::Vector(vector_values)
+    Ok(CellArgument::Vector(vector_values))
 }
 
 /// Retrieves the values of a matrix of cells, iterating over both rows and columns.
@@ -404,15 +806,17 @@ fn get_vector(
 /// * `dependers`: A mutable set that tracks which cells depend on this matrix.
 ///
 /// # Returns
-/// A `CellArgument::Matrix` containing the values of the matrix' cells.
+/// A `CellArgument::Matrix` containing the values of the matrix' cells, or an error if the
+/// matrix covers more cells than the range limit allows.
 fn get_matrix(
     coords: Vec<&str>,
     cells: &HashMap<String, CellValue>,
     dependers: &mut HashSet<String>,
-) -> CellArgument {
+) -> Result<CellArgument, String> {
     // Extract start and end coordinates
     let start = coords[0];
     let end = coords[1];
+    limits::current().check_range(&coords.join("_"))?;
 
     let start_row = start.chars().next().unwrap();
     let end_row = end.chars().next().unwrap();
@@ -430,7 +834,7 @@ fn get_matrix(
         matrix_values.push(row_values);
     }
 
-    CellArgument::Matrix(matrix_values)
+    Ok(CellArgument::Matrix(matrix_values))
 }
 
 // ===================== STAGE 4 + 5 ============================
@@ -496,18 +900,41 @@ fn process_dependencies(
     dependers: &mut HashMap<String, HashSet<String>>,
     dependencies: &mut HashMap<String, HashSet<String>>,
 ) {
-    let old_dependers = dependers.remove(&cell_address).unwrap_or_default();
+    let _span = metrics::Span::dependencies(&cell_address);
+    record_dependencies(new_dependers, &cell_address, dependers, dependencies);
+
+    update_dependencies(
+        cell_address.clone(),
+        dependencies,
+        exprs,
+        cells,
+        cell_errors,
+        dependers,
+    );
+}
+
+/// Updates the `dependers` and `dependencies` maps for a cell, without re-evaluating anything.
+///
+/// # Parameters
+/// * `new_dependers`: The new dependers of the cell.
+/// * `cell_address`: The address of the cell whose dependencies are being recorded.
+/// * `dependers`: A mutable reference to the map of dependers.
+/// * `dependencies`: A mutable reference to the map of dependencies.
+fn record_dependencies(
+    new_dependers: HashSet<String>,
+    cell_address: &str,
+    dependers: &mut HashMap<String, HashSet<String>>,
+    dependencies: &mut HashMap<String, HashSet<String>>,
+) {
+    let old_dependers = dependers.remove(cell_address).unwrap_or_default();
 
     let added_dependers = new_dependers.difference(&old_dependers);
     let removed_dependers = old_dependers.difference(&new_dependers);
 
-    // Insert new dependers
-    dependers.insert(cell_address.clone(), new_dependers.clone());
-
     // Edit old dependencies (for removed dependers)
     for dependency in removed_dependers {
         if let Some(mut curr_dependencies) = dependencies.remove(dependency) {
-            curr_dependencies.remove(&cell_address);
+            curr_dependencies.remove(cell_address);
             dependencies.insert(dependency.to_string(), curr_dependencies);
         }
     }
@@ -516,23 +943,43 @@ fn process_dependencies(
     for dependency in added_dependers {
         // Use entry to get a mutable reference to the dependencies of the dependency
         let added_dependencies = dependencies.entry(dependency.to_string()).or_default();
-        added_dependencies.insert(cell_address.clone());
+        added_dependencies.insert(cell_address.to_string());
     }
 
-    update_dependencies(
-        cell_address.clone(),
-        dependencies,
-        exprs,
-        cells,
-        cell_errors,
-        dependers,
-    );
+    // Insert new dependers
+    dependers.insert(cell_address.to_string(), new_dependers);
 }
 
 // ===================== TESTS ============================
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::sync::MutexGuard;
+
+    lazy_static::lazy_static! {
+        static ref SETTINGS: Mutex<()> = Mutex::new(());
+    }
+
+    /// Held by tests which change the global settings, so they run one at a time, and puts the
+    /// defaults back when the test ends, even if it fails.
+    struct SettingsGuard {
+        _lock: MutexGuard<'static, ()>,
+    }
+
+    impl SettingsGuard {
+        fn lock() -> Self {
+            SettingsGuard {
+                _lock: SETTINGS.lock().unwrap_or_else(|e| e.into_inner()),
+            }
+        }
+    }
+
+    impl Drop for SettingsGuard {
+        fn drop(&mut self) {
+            set_access_config(AccessConfig::default());
+            limits::set_limits(limits::Limits::default());
+        }
+    }
 
     // 1. Test `cell_to_string` function with a sample CellIdentifier
     #[test]
@@ -548,7 +995,7 @@ mod tests {
         let cell_expr = "5";
 
         // Execute
-        assert!(handle_set(&cell_id, cell_expr).is_none());
+        assert!(handle_set(&cell_id, cell_expr, None).is_none());
 
         // Check CELL_MAP for the updated value
         let cells = CELL_MAP.lock().unwrap();
@@ -627,7 +1074,7 @@ mod tests {
         let expr = CellExpr::new("B1 + 10");
         let mut new_dependers = HashSet::new();
 
-        let args = parse_expr_args(&expr, &cells, &mut new_dependers);
+        let args = parse_expr_args(&expr, &cells, &mut new_dependers).unwrap();
         assert!(args.contains_key("B1"));
         assert_eq!(args["B1"], CellArgument::Value(CellValue::Int(5)));
     }
@@ -657,4 +1104,93 @@ mod tests {
         assert!(dependers.contains_key(&cell_address));
         assert_eq!(dependers[&cell_address], new_dependers);
     }
+
+    // 7. Test `handle_set` rejects writes to locked ranges, and allows their owner
+    #[test]
+    fn test_handle_set_locked_range() {
+        let _settings = SettingsGuard::lock();
+        set_access_config("readonly Z100\nowner Y100 editor".parse().unwrap());
+        let z100 = CellIdentifier { row: 99, col: 25 };
+        let y100 = CellIdentifier { row: 99, col: 24 };
+
+        assert!(matches!(
+            handle_set(&z100, "1", Some("editor")),
+            Some(Reply::Error(_))
+        ));
+        assert!(matches!(
+            handle_set(&y100, "1", Some("viewer")),
+            Some(Reply::Error(_))
+        ));
+        assert!(handle_set(&y100, "2", Some("editor")).is_none());
+
+        let cells = CELL_MAP.lock().unwrap();
+        assert!(!cells.contains_key("Z100"));
+        assert_eq!(cells.get("Y100"), Some(&CellValue::Int(2)));
+    }
+
+    // 8. Test ranges and spills are held to the limits while they are evaluated
+    #[test]
+    fn test_evaluate_limits() {
+        let _settings = SettingsGuard::lock();
+        limits::set_limits(limits::Limits {
+            max_string_bytes: Some(5),
+            max_range_cells: Some(4),
+            ..Default::default()
+        });
+        let mut cells = HashMap::new();
+        let mut cell_errors = HashMap::new();
+        let exprs = HashMap::new();
+        cells.insert("Q1".to_string(), CellValue::String("too long".to_string()));
+
+        assert!(get_matrix(vec!["Q1", "R2"], &cells, &mut HashSet::new()).is_ok());
+        assert!(get_matrix(vec!["Q1", "R3"], &cells, &mut HashSet::new()).is_err());
+        assert!(get_vector(vec!["Q1", "Q5"], &cells, &mut HashSet::new()).is_err());
+
+        // A cell reading a range over the limit holds why
+        evaluate_value(
+            CellExpr::new("sum(Q1_R3)"),
+            &mut cells,
+            "S1",
+            &mut cell_errors,
+        );
+        assert!(cell_errors["S1"].contains("over the limit of 4"));
+
+        let formula = array::ArrayFormula::parse("transpose(Q1_Q5)").unwrap();
+        array::spill(&formula, &mut cells, "S2", &mut cell_errors, &exprs);
+        assert!(cells["S2"].is_error());
+
+        // Spilled strings are checked like any other value
+        let formula = array::ArrayFormula::parse("transpose(Q1_Q2)").unwrap();
+        array::spill(&formula, &mut cells, "S3", &mut cell_errors, &exprs);
+        assert!(cells["S3"].is_error());
+        assert!(!cells.contains_key("T3"));
+    }
+
+    // 9. Test a spill cannot write into a range its sender could not set directly
+    #[test]
+    fn test_spill_locked_range() {
+        let _settings = SettingsGuard::lock();
+        set_access_config(
+            "readonly W201_X201\nowner W202_X202 editor"
+                .parse()
+                .unwrap(),
+        );
+        let cell = |address: &str| address.parse::<CellIdentifier>().unwrap();
+        for (address, value) in [("V211", "1"), ("V212", "2"), ("V213", "3")] {
+            assert!(handle_set(&cell(address), value, None).is_none());
+        }
+
+        assert!(handle_set(&cell("V201"), "transpose(V211_V213)", Some("editor")).is_none());
+        assert!(handle_set(&cell("V202"), "transpose(V211_V213)", Some("editor")).is_none());
+        assert!(handle_set(&cell("V203"), "transpose(V211_V213)", Some("viewer")).is_none());
+
+        let cells = CELL_MAP.lock().unwrap();
+        assert_eq!(
+            cells["V201"],
+            CellValue::Error("Cannot spill into W201: range W201_X201 is read-only".to_string())
+        );
+        assert!(!cells.contains_key("W201"));
+        assert_eq!(cells.get("W202"), Some(&CellValue::Int(2)));
+        assert_eq!(cells.get("X203"), Some(&CellValue::Int(3)));
+    }
 }
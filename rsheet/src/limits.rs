@@ -0,0 +1,158 @@
+use crate::array::parse_range;
+use rsheet_lib::cell_value::CellValue;
+use std::sync::Mutex;
+
+lazy_static::lazy_static! {
+    static ref LIMITS: Mutex<Limits> = Mutex::new(Limits::default());
+}
+
+/// Caps on how much a single client can make the server hold in memory.
+///
+/// Each limit is unlimited when `None`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Limits {
+    /// The most cells which may hold a value or expression at once.
+    pub max_cells: Option<usize>,
+    /// The longest expression a cell may be set to, in bytes.
+    pub max_expr_bytes: Option<usize>,
+    /// The longest string a cell may evaluate to, in bytes.
+    pub max_string_bytes: Option<usize>,
+    /// The most cells a single range in an expression, such as `A1_Z100`, may cover.
+    pub max_range_cells: Option<usize>,
+}
+
+/// Sets the limits enforced on every `Set` from now on.
+///
+/// # Parameters
+/// * `limits`: The limits to enforce.
+pub fn set_limits(limits: Limits) {
+    *LIMITS.lock().unwrap() = limits;
+}
+
+/// Returns the limits currently being enforced.
+pub(crate) fn current() -> Limits {
+    *LIMITS.lock().unwrap()
+}
+
+impl Limits {
+    /// Checks the length of an expression, and the size of every range it reads.
+    ///
+    /// # Parameters
+    /// * `expr`: The expression a cell is being set to.
+    ///
+    /// # Returns
+    /// `Ok(())` if the expression is within the limits, otherwise why it is not.
+    pub(crate) fn check_expr(&self, expr: &str) -> Result<(), String> {
+        if let Some(max) = self.max_expr_bytes {
+            if expr.len() > max {
+                return Err(format!(
+                    "expression is {} bytes, over the limit of {}",
+                    expr.len(),
+                    max
+                ));
+            }
+        }
+
+        // Ranges can appear in plain expressions as well as in array formulas and queries
+        let words = expr.split(|c: char| !(c.is_ascii_alphanumeric() || c == '_'));
+        for range in words.filter(|word| word.contains('_')) {
+            self.check_range(range)?;
+        }
+        Ok(())
+    }
+
+    /// Checks the number of cells a range covers.
+    ///
+    /// # Parameters
+    /// * `range`: The range being read, such as `A1_Z100`.
+    ///
+    /// # Returns
+    /// `Ok(())` if the range is within the limits, or is not a valid range, otherwise why it
+    /// is not.
+    pub(crate) fn check_range(&self, range: &str) -> Result<(), String> {
+        let (Some(max), Some((start, end))) = (self.max_range_cells, parse_range(range)) else {
+            return Ok(());
+        };
+        let size = (end.col - start.col + 1) as usize * (end.row - start.row + 1) as usize;
+        if size > max {
+            return Err(format!(
+                "range {} covers {} cells, over the limit of {}",
+                range, size, max
+            ));
+        }
+        Ok(())
+    }
+
+    /// Checks that a cell can be added to the sheet.
+    ///
+    /// # Parameters
+    /// * `is_new`: Whether the cell is not yet held by the sheet.
+    /// * `count`: The number of cells the sheet currently holds.
+    ///
+    /// # Returns
+    /// `Ok(())` if the cell fits, otherwise why it does not.
+    pub(crate) fn check_new_cell(&self, is_new: bool, count: usize) -> Result<(), String> {
+        match self.max_cells {
+            Some(max) if is_new && count >= max => Err(format!(
+                "the sheet already holds {} cells, the limit",
+                count
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks the size of a string value a cell evaluated to.
+    ///
+    /// # Parameters
+    /// * `value`: The value of the cell.
+    ///
+    /// # Returns
+    /// `Ok(())` if the value is within the limits, otherwise why it is not.
+    pub(crate) fn check_value(&self, value: &CellValue) -> Result<(), String> {
+        match (value, self.max_string_bytes) {
+            (CellValue::String(string), Some(max)) if string.len() > max => Err(format!(
+                "string value is {} bytes, over the limit of {}",
+                string.len(),
+                max
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+// ===================== TESTS ============================
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 1. Test expressions, ranges and strings against their limits
+    #[test]
+    fn test_limits() {
+        let limits = Limits {
+            max_cells: Some(2),
+            max_expr_bytes: Some(20),
+            max_string_bytes: Some(3),
+            max_range_cells: Some(10),
+        };
+
+        assert!(limits.check_expr("sum(A1_A10)").is_ok());
+        assert!(limits.check_expr("sum(A1_B10)").is_err());
+        assert!(limits.check_expr("transpose(B5_A1)").is_ok());
+        assert!(limits.check_expr("1 + 1 + 1 + 1 + 1 + 1 + 1").is_err());
+        assert!(limits.check_range("A1_B5").is_ok());
+        assert!(limits.check_range("A1_C5").is_err());
+        assert!(Limits::default().check_range("A1_ZZ9999").is_ok());
+
+        assert!(limits.check_new_cell(true, 1).is_ok());
+        assert!(limits.check_new_cell(true, 2).is_err());
+        assert!(limits.check_new_cell(false, 2).is_ok());
+
+        assert!(limits
+            .check_value(&CellValue::String("abc".to_string()))
+            .is_ok());
+        assert!(limits
+            .check_value(&CellValue::String("abcd".to_string()))
+            .is_err());
+        assert!(limits.check_value(&CellValue::Int(12345)).is_ok());
+    }
+}
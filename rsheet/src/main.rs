@@ -1,7 +1,9 @@
 use std::error::Error;
 
 use clap::Parser;
-use rsheet::start_server;
+use rsheet::access::AccessConfig;
+use rsheet::limits::{set_limits, Limits};
+use rsheet::{background, replication, set_access_config, start_server};
 use rsheet_lib::connect::{resolve_address, ConnectionManager, TerminalManager};
 
 #[derive(Parser, Debug)]
@@ -12,6 +14,43 @@ struct Args {
     /// Hides the contents of error messages
     #[arg(short, long, default_value_t = false)]
     mark_mode: bool,
+
+    /// Path to an access control config mapping senders to roles and locking ranges
+    #[arg(long)]
+    access: Option<std::path::PathBuf>,
+
+    /// Address to accept follower servers on, which are streamed every applied set
+    #[arg(long)]
+    replicate: Option<String>,
+
+    /// Address of a primary server to follow; this server then rejects sets
+    #[arg(long)]
+    follow: Option<String>,
+
+    /// Recalculate dependent cells on this many background threads, so sets return immediately
+    #[arg(long, default_value_t = 0)]
+    recalc_workers: usize,
+
+    /// With background recalculation, reply to gets of dirty cells with their stale value
+    /// instead of waiting for them, marking the reply with `(stale)`
+    #[arg(long, default_value_t = false)]
+    stale_reads: bool,
+
+    /// Reject sets which would make the sheet hold more than this many cells
+    #[arg(long)]
+    max_cells: Option<usize>,
+
+    /// Reject sets whose expression is longer than this many bytes
+    #[arg(long)]
+    max_expr_bytes: Option<usize>,
+
+    /// Store an error instead of any string value longer than this many bytes
+    #[arg(long)]
+    max_string_bytes: Option<usize>,
+
+    /// Reject sets reading a range which covers more than this many cells
+    #[arg(long)]
+    max_range_cells: Option<usize>,
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
@@ -19,6 +58,30 @@ fn main() -> Result<(), Box<dyn Error>> {
 
     let args = Args::parse();
 
+    if let Some(path) = &args.access {
+        let config: AccessConfig = std::fs::read_to_string(path)?.parse()?;
+        set_access_config(config);
+    }
+
+    set_limits(Limits {
+        max_cells: args.max_cells,
+        max_expr_bytes: args.max_expr_bytes,
+        max_string_bytes: args.max_string_bytes,
+        max_range_cells: args.max_range_cells,
+    });
+
+    if args.recalc_workers > 0 {
+        background::start_workers(args.recalc_workers, args.stale_reads);
+    }
+
+    if let Some(addr) = &args.replicate {
+        replication::serve_followers(resolve_address(addr)?)?;
+    }
+
+    if let Some(primary) = &args.follow {
+        replication::follow(resolve_address(primary)?);
+    }
+
     if let Some(addr) = args.addr {
         let addr = resolve_address(&addr)?;
         let manager = ConnectionManager::launch(addr.ip(), addr.port());
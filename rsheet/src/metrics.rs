@@ -0,0 +1,416 @@
+use log::debug;
+use rsheet_lib::cell_value::CellValue;
+use rsheet_lib::replies::Reply;
+use std::cell::Cell;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// How far back `commands_per_second` looks.
+const RATE_WINDOW: Duration = Duration::from_secs(10);
+
+/// Number of power-of-two buckets kept by each histogram.
+const BUCKETS: usize = 32;
+
+lazy_static::lazy_static! {
+    static ref METRICS: Mutex<Metrics> = Mutex::new(Metrics::new());
+}
+
+thread_local! {
+    // Recalculation state of the `Set` being handled on this thread
+    static RECALC_COUNT: Cell<u64> = const { Cell::new(0) };
+    static RECALC_DEPTH: Cell<u64> = const { Cell::new(0) };
+    static RECALC_MAX_DEPTH: Cell<u64> = const { Cell::new(0) };
+}
+
+/// A histogram of non-negative samples, bucketed by powers of two.
+#[derive(Debug, Clone)]
+struct Histogram {
+    count: u64,
+    sum: u64,
+    min: u64,
+    max: u64,
+    buckets: [u64; BUCKETS],
+}
+
+struct Metrics {
+    started: Instant,
+    commands: u64,
+    /// How many commands arrived in each second since `started`, as `(second, count)`, for
+    /// the seconds within `RATE_WINDOW`.
+    recent_commands: VecDeque<(u64, u64)>,
+    /// Every cell evaluated, whether for a `Set` or by a background worker.
+    cells_recalculated_total: u64,
+    cells_recalculated: Histogram,
+    recalc_depth: Histogram,
+    eval_latency_us: Histogram,
+    lock_wait_us: BTreeMap<&'static str, Histogram>,
+}
+
+impl Histogram {
+    fn new() -> Self {
+        Histogram {
+            count: 0,
+            sum: 0,
+            min: u64::MAX,
+            max: 0,
+            buckets: [0; BUCKETS],
+        }
+    }
+
+    fn record(&mut self, sample: u64) {
+        // Bucket `i` holds samples below 2^i
+        let bucket = (u64::BITS - sample.leading_zeros()) as usize;
+        self.buckets[bucket.min(BUCKETS - 1)] += 1;
+        self.count += 1;
+        self.sum += sample;
+        self.min = self.min.min(sample);
+        self.max = self.max.max(sample);
+    }
+
+    /// Returns an upper bound for the given percentile, from the bucket it falls in.
+    fn percentile(&self, percentile: u64) -> u64 {
+        let target = (self.count * percentile).div_ceil(100);
+        let mut seen = 0;
+        for (bucket, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= target {
+                return ((1u64 << bucket) - 1).min(self.max);
+            }
+        }
+        self.max
+    }
+
+    fn summary(&self) -> String {
+        if self.count == 0 {
+            return "count=0".to_string();
+        }
+        format!(
+            "count={} mean={:.2} min={} max={} p50<={} p99<={}",
+            self.count,
+            self.sum as f64 / self.count as f64,
+            self.min,
+            self.max,
+            self.percentile(50),
+            self.percentile(99)
+        )
+    }
+}
+
+impl Metrics {
+    fn new() -> Self {
+        Metrics {
+            started: Instant::now(),
+            commands: 0,
+            recent_commands: VecDeque::new(),
+            cells_recalculated_total: 0,
+            cells_recalculated: Histogram::new(),
+            recalc_depth: Histogram::new(),
+            eval_latency_us: Histogram::new(),
+            lock_wait_us: BTreeMap::new(),
+        }
+    }
+
+    /// Counts a command, keeping only the last `RATE_WINDOW` of per-second counts.
+    fn record_command(&mut self, now: Instant) {
+        self.commands += 1;
+        let second = now.duration_since(self.started).as_secs();
+        match self.recent_commands.back_mut() {
+            Some((last, count)) if *last == second => *count += 1,
+            _ => self.recent_commands.push_back((second, 1)),
+        }
+        self.prune(second);
+    }
+
+    /// Drops the counts of seconds which are no longer within `RATE_WINDOW` of `second`.
+    fn prune(&mut self, second: u64) {
+        while let Some((oldest, _)) = self.recent_commands.front() {
+            if oldest + RATE_WINDOW.as_secs() > second {
+                break;
+            }
+            self.recent_commands.pop_front();
+        }
+    }
+
+    fn commands_per_second(&mut self, now: Instant) -> f64 {
+        let uptime = now.duration_since(self.started);
+        self.prune(uptime.as_secs());
+
+        // Servers younger than the window are averaged over their uptime instead
+        let window = uptime.min(RATE_WINDOW);
+        let commands: u64 = self.recent_commands.iter().map(|(_, count)| count).sum();
+        commands as f64 / window.as_secs_f64().max(1.0)
+    }
+}
+
+fn micros(duration: Duration) -> u64 {
+    duration.as_micros().try_into().unwrap_or(u64::MAX)
+}
+
+// ===================== RECORDING ============================
+
+/// Counts a command received from any sender.
+pub(crate) fn record_command() {
+    METRICS.lock().unwrap().record_command(Instant::now());
+}
+
+/// Records how long a single call to `CellExpr::evaluate` took.
+pub(crate) fn record_eval_latency(elapsed: Duration) {
+    METRICS
+        .lock()
+        .unwrap()
+        .eval_latency_us
+        .record(micros(elapsed));
+}
+
+/// Locks one of the global mutexes, recording how long the lock took to acquire.
+///
+/// # Parameters
+/// * `name`: The name the wait time is reported under by `stats`.
+/// * `mutex`: The mutex to lock.
+///
+/// # Returns
+/// The guard of the locked mutex.
+pub(crate) fn timed_lock<'a, T>(name: &'static str, mutex: &'a Mutex<T>) -> MutexGuard<'a, T> {
+    let start = Instant::now();
+    let guard = mutex.lock().unwrap();
+    let waited = micros(start.elapsed());
+
+    METRICS
+        .lock()
+        .unwrap()
+        .lock_wait_us
+        .entry(name)
+        .or_insert_with(Histogram::new)
+        .record(waited);
+    guard
+}
+
+/// Tracks the recalculations caused by a single `Set`, recording them when dropped.
+pub(crate) struct SetRecalc;
+
+impl SetRecalc {
+    /// Starts tracking the recalculations of a `Set` on the current thread.
+    pub(crate) fn start() -> Self {
+        RECALC_COUNT.with(|count| count.set(0));
+        RECALC_DEPTH.with(|depth| depth.set(0));
+        RECALC_MAX_DEPTH.with(|depth| depth.set(0));
+        SetRecalc
+    }
+}
+
+impl Drop for SetRecalc {
+    fn drop(&mut self) {
+        let count = RECALC_COUNT.with(Cell::get);
+        let max_depth = RECALC_MAX_DEPTH.with(Cell::get);
+
+        let mut metrics = METRICS.lock().unwrap();
+        metrics.cells_recalculated.record(count);
+        metrics.recalc_depth.record(max_depth);
+    }
+}
+
+/// Tracks how deeply recalculations are nested within a `Set`, for as long as it is held.
+pub(crate) struct Nested;
+
+impl Nested {
+    /// Goes one level deeper, around a cell and everything recalculated because of it.
+    pub(crate) fn enter() -> Self {
+        let depth = RECALC_DEPTH.with(|depth| {
+            depth.set(depth.get() + 1);
+            depth.get()
+        });
+        RECALC_MAX_DEPTH.with(|max| max.set(max.get().max(depth)));
+        Nested
+    }
+}
+
+impl Drop for Nested {
+    fn drop(&mut self) {
+        RECALC_DEPTH.with(|depth| depth.set(depth.get().saturating_sub(1)));
+    }
+}
+
+/// A structured log span, which logs its fields on entry and its duration on exit.
+pub(crate) struct Span {
+    name: &'static str,
+    cell: String,
+    start: Instant,
+}
+
+impl Span {
+    /// Enters a span around `evaluate_cell` for the given cell, counting it as one
+    /// recalculation.
+    ///
+    /// Every evaluation goes through `evaluate_cell`, both while handling a `Set` and on the
+    /// background workers, so both are measured.
+    pub(crate) fn evaluate(cell: &str) -> Self {
+        RECALC_COUNT.with(|count| count.set(count.get() + 1));
+        METRICS.lock().unwrap().cells_recalculated_total += 1;
+        Self::enter("evaluate_cell", cell)
+    }
+
+    /// Enters a span around `process_dependencies` for the given cell.
+    pub(crate) fn dependencies(cell: &str) -> Self {
+        Self::enter("process_dependencies", cell)
+    }
+
+    fn enter(name: &'static str, cell: &str) -> Self {
+        let depth = RECALC_DEPTH.with(Cell::get);
+        debug!(target: "rsheet::span", "enter span={} cell={} depth={}", name, cell, depth);
+        Span {
+            name,
+            cell: cell.to_string(),
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        let depth = RECALC_DEPTH.with(Cell::get);
+        debug!(
+            target: "rsheet::span",
+            "exit span={} cell={} depth={} elapsed_us={}",
+            self.name,
+            self.cell,
+            depth,
+            micros(self.start.elapsed())
+        );
+    }
+}
+
+// ===================== REPORTING ============================
+
+/// Builds the replies for a `stats` command, one per metric.
+///
+/// Counters are replied as integers, and histograms as a summary string.
+pub(crate) fn stats_replies() -> Vec<Reply> {
+    let mut metrics = METRICS.lock().unwrap();
+    let commands_per_second = metrics.commands_per_second(Instant::now());
+
+    let mut replies = vec![
+        Reply::Value(
+            "uptime_seconds".to_string(),
+            CellValue::Int(metrics.started.elapsed().as_secs() as i64),
+        ),
+        Reply::Value(
+            "commands_total".to_string(),
+            CellValue::Int(metrics.commands as i64),
+        ),
+        Reply::Value(
+            "commands_per_second".to_string(),
+            CellValue::String(format!("{:.2}", commands_per_second)),
+        ),
+        Reply::Value(
+            "cells_recalculated_total".to_string(),
+            CellValue::Int(metrics.cells_recalculated_total as i64),
+        ),
+        Reply::Value(
+            "cells_recalculated_per_set".to_string(),
+            CellValue::String(metrics.cells_recalculated.summary()),
+        ),
+        Reply::Value(
+            "recalculation_depth".to_string(),
+            CellValue::String(metrics.recalc_depth.summary()),
+        ),
+        Reply::Value(
+            "evaluation_latency_us".to_string(),
+            CellValue::String(metrics.eval_latency_us.summary()),
+        ),
+    ];
+
+    for (name, histogram) in metrics.lock_wait_us.iter() {
+        replies.push(Reply::Value(
+            format!("lock_wait_us[{}]", name),
+            CellValue::String(histogram.summary()),
+        ));
+    }
+    replies
+}
+
+// ===================== TESTS ============================
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rsheet_lib::cell_expr::CellExpr;
+    use std::collections::HashMap;
+
+    // 1. Test histogram summaries and percentile bounds
+    #[test]
+    fn test_histogram() {
+        let mut histogram = Histogram::new();
+        assert_eq!(histogram.summary(), "count=0");
+
+        for sample in [1, 2, 3, 100] {
+            histogram.record(sample);
+        }
+        assert_eq!(histogram.count, 4);
+        assert_eq!(histogram.min, 1);
+        assert_eq!(histogram.max, 100);
+        assert_eq!(histogram.percentile(50), 3);
+        assert_eq!(histogram.percentile(99), 100);
+    }
+
+    // 2. Test that nested recalculations track their depth
+    #[test]
+    fn test_recalc_depth() {
+        let _set = SetRecalc::start();
+        {
+            let _outer = Nested::enter();
+            let _a1 = Span::evaluate("A1");
+            let _inner = Nested::enter();
+            let _b1 = Span::evaluate("B1");
+        }
+        let _sibling = Nested::enter();
+        let _c1 = Span::evaluate("C1");
+
+        assert_eq!(RECALC_COUNT.with(Cell::get), 3);
+        assert_eq!(RECALC_MAX_DEPTH.with(Cell::get), 2);
+        assert_eq!(RECALC_DEPTH.with(Cell::get), 1);
+    }
+
+    // 3. Test the command rate only keeps the seconds within its window
+    #[test]
+    fn test_commands_per_second() {
+        let mut metrics = Metrics::new();
+        let started = metrics.started;
+        for second in 0..100 {
+            for _ in 0..5 {
+                metrics.record_command(started + Duration::from_secs(second));
+            }
+        }
+        assert_eq!(metrics.commands, 500);
+        assert_eq!(metrics.recent_commands.len(), 10);
+
+        let now = started + Duration::from_millis(99_500);
+        assert_eq!(metrics.commands_per_second(now), 5.0);
+        // Nothing has arrived for a whole window
+        assert_eq!(
+            metrics.commands_per_second(now + RATE_WINDOW + RATE_WINDOW),
+            0.0
+        );
+        assert!(metrics.recent_commands.is_empty());
+    }
+
+    // 4. Test evaluating a single cell, as the background workers do, is counted
+    #[test]
+    fn test_evaluate_cell_counted() {
+        let _set = SetRecalc::start();
+        let total = METRICS.lock().unwrap().cells_recalculated_total;
+
+        let mut cells = HashMap::new();
+        let mut cell_errors = HashMap::new();
+        crate::evaluate_cell(
+            CellExpr::new("1 + 1"),
+            &mut cells,
+            "ZZ1",
+            &mut cell_errors,
+            &HashMap::new(),
+        );
+
+        assert_eq!(cells["ZZ1"], CellValue::Int(2));
+        assert_eq!(RECALC_COUNT.with(Cell::get), 1);
+        assert!(METRICS.lock().unwrap().cells_recalculated_total > total);
+    }
+}
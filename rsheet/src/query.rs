@@ -0,0 +1,612 @@
+use crate::array::{compare_values, parse_range};
+use rsheet_lib::cell_value::CellValue;
+use rsheet_lib::cells::{column_name_to_number, column_number_to_name};
+use rsheet_lib::command::CellIdentifier;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+/// A SQL-like statement run over a range whose first row holds the column headers, such as
+/// `A1_F500 select B, sum(F) where C = "NSW" group by B`.
+///
+/// Columns are referred to by their sheet column names. The result of a query is itself a
+/// table, starting with a header row.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Query {
+    pub(crate) start: CellIdentifier,
+    pub(crate) end: CellIdentifier,
+    select: Vec<Item>,
+    filters: Vec<Filter>,
+    group_by: Vec<u32>,
+}
+
+/// A single entry of the `select` list.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Item {
+    Column(u32),
+    /// An aggregate over a column, or over whole rows for `count(*)`.
+    Aggregate(Aggregate, Option<u32>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Aggregate {
+    Sum,
+    Count,
+    Min,
+    Max,
+}
+
+/// A `where` condition comparing a column against a literal.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Filter {
+    column: u32,
+    comparison: Comparison,
+    value: CellValue,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Word(String),
+    Str(String),
+    Int(i64),
+    Symbol(String),
+}
+
+impl Aggregate {
+    fn name(&self) -> &'static str {
+        match self {
+            Aggregate::Sum => "sum",
+            Aggregate::Count => "count",
+            Aggregate::Min => "min",
+            Aggregate::Max => "max",
+        }
+    }
+}
+
+impl Comparison {
+    fn matches(&self, ordering: Option<Ordering>) -> bool {
+        match self {
+            Comparison::Eq => ordering == Some(Ordering::Equal),
+            Comparison::Ne => ordering != Some(Ordering::Equal),
+            Comparison::Lt => ordering == Some(Ordering::Less),
+            Comparison::Le => matches!(ordering, Some(Ordering::Less | Ordering::Equal)),
+            Comparison::Gt => ordering == Some(Ordering::Greater),
+            Comparison::Ge => matches!(ordering, Some(Ordering::Greater | Ordering::Equal)),
+        }
+    }
+}
+
+impl Filter {
+    /// Compares like values only, so `C = 5` never matches the string `"5"` or an empty cell.
+    fn matches(&self, value: &CellValue) -> bool {
+        let ordering = match (value, &self.value) {
+            (CellValue::Int(a), CellValue::Int(b)) => Some(a.cmp(b)),
+            (CellValue::String(a), CellValue::String(b)) => Some(a.cmp(b)),
+            _ => None,
+        };
+        self.comparison.matches(ordering)
+    }
+}
+
+/// Splits a statement into words, quoted strings, integers and symbols.
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '"' || c == '\'' {
+            chars.next();
+            let mut string = String::new();
+            loop {
+                match chars.next() {
+                    Some(next) if next == c => break,
+                    Some(next) => string.push(next),
+                    None => return Err(format!("unterminated string {}{}", c, string)),
+                }
+            }
+            tokens.push(Token::Str(string));
+        } else if c.is_ascii_alphanumeric() || c == '_' || c == '-' {
+            let mut word = String::new();
+            while let Some(&next) = chars.peek() {
+                if !(next.is_ascii_alphanumeric()
+                    || next == '_'
+                    || (next == '-' && word.is_empty()))
+                {
+                    break;
+                }
+                word.push(next);
+                chars.next();
+            }
+            match word.parse::<i64>() {
+                Ok(int) => tokens.push(Token::Int(int)),
+                Err(_) if word == "-" => return Err("unexpected '-'".to_string()),
+                Err(_) => tokens.push(Token::Word(word)),
+            }
+        } else {
+            chars.next();
+            let mut symbol = c.to_string();
+            if let Some(&next) = chars.peek() {
+                if matches!((c, next), ('!', '=') | ('<', '=') | ('>', '=') | ('<', '>')) {
+                    symbol.push(next);
+                    chars.next();
+                }
+            }
+            tokens.push(Token::Symbol(symbol));
+        }
+    }
+    Ok(tokens)
+}
+
+/// Walks the tokens of a statement.
+struct Parser<'a> {
+    tokens: &'a [Token],
+    position: usize,
+    start: CellIdentifier,
+    end: CellIdentifier,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&'a Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<&'a Token> {
+        let token = self.tokens.get(self.position);
+        self.position += 1;
+        token
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Some(Token::Word(word)) if word.eq_ignore_ascii_case(keyword))
+    }
+
+    fn is_symbol(&self, symbol: &str) -> bool {
+        matches!(self.peek(), Some(Token::Symbol(s)) if s == symbol)
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<(), String> {
+        if !self.is_keyword(keyword) {
+            return Err(format!(
+                "expected '{}' but found {}",
+                keyword,
+                self.describe()
+            ));
+        }
+        self.position += 1;
+        Ok(())
+    }
+
+    fn expect_symbol(&mut self, symbol: &str) -> Result<(), String> {
+        if !self.is_symbol(symbol) {
+            return Err(format!(
+                "expected '{}' but found {}",
+                symbol,
+                self.describe()
+            ));
+        }
+        self.position += 1;
+        Ok(())
+    }
+
+    /// Describes the next token for error messages.
+    fn describe(&self) -> String {
+        match self.peek() {
+            Some(Token::Word(word)) => format!("'{}'", word),
+            Some(Token::Str(string)) => format!("\"{}\"", string),
+            Some(Token::Int(int)) => format!("'{}'", int),
+            Some(Token::Symbol(symbol)) => format!("'{}'", symbol),
+            None => "the end of the query".to_string(),
+        }
+    }
+
+    /// Parses a column name, which must be inside the queried range.
+    fn column(&mut self) -> Result<u32, String> {
+        let description = self.describe();
+        let Some(Token::Word(name)) = self.next() else {
+            return Err(format!("expected a column but found {}", description));
+        };
+        if !name.chars().all(|c| c.is_ascii_uppercase()) {
+            return Err(format!("expected a column but found {}", description));
+        }
+
+        let column = column_name_to_number(name);
+        if column < self.start.col || column > self.end.col {
+            return Err(format!("column {} is outside the queried range", name));
+        }
+        Ok(column)
+    }
+
+    fn item(&mut self) -> Result<Item, String> {
+        let aggregate = match self.peek() {
+            Some(Token::Word(word)) => match word.to_lowercase().as_str() {
+                "sum" => Some(Aggregate::Sum),
+                "count" => Some(Aggregate::Count),
+                "min" => Some(Aggregate::Min),
+                "max" => Some(Aggregate::Max),
+                _ => None,
+            },
+            _ => None,
+        };
+
+        match aggregate {
+            // Column `C` could also be written in lower case, so only a `(` makes an aggregate
+            Some(aggregate) if self.tokens.get(self.position + 1) == Some(&sym("(")) => {
+                self.position += 2;
+                let column = if aggregate == Aggregate::Count && self.is_symbol("*") {
+                    self.position += 1;
+                    None
+                } else {
+                    Some(self.column()?)
+                };
+                self.expect_symbol(")")?;
+                Ok(Item::Aggregate(aggregate, column))
+            }
+            _ => Ok(Item::Column(self.column()?)),
+        }
+    }
+
+    fn filter(&mut self) -> Result<Filter, String> {
+        let column = self.column()?;
+
+        let description = self.describe();
+        let comparison = match self.next() {
+            Some(Token::Symbol(symbol)) => match symbol.as_str() {
+                "=" => Comparison::Eq,
+                "!=" | "<>" => Comparison::Ne,
+                "<" => Comparison::Lt,
+                "<=" => Comparison::Le,
+                ">" => Comparison::Gt,
+                ">=" => Comparison::Ge,
+                _ => return Err(format!("expected a comparison but found {}", description)),
+            },
+            _ => return Err(format!("expected a comparison but found {}", description)),
+        };
+
+        let description = self.describe();
+        let value = match self.next() {
+            Some(Token::Str(string)) => CellValue::String(string.clone()),
+            Some(Token::Int(int)) => CellValue::Int(*int),
+            _ => return Err(format!("expected a value but found {}", description)),
+        };
+
+        Ok(Filter {
+            column,
+            comparison,
+            value,
+        })
+    }
+}
+
+fn sym(symbol: &str) -> Token {
+    Token::Symbol(symbol.to_string())
+}
+
+impl Query {
+    /// Parses a range followed by a `select` statement.
+    ///
+    /// # Parameters
+    /// * `text`: The query, such as `A1_F500 select B, sum(F) where C = "NSW" group by B`.
+    ///
+    /// # Returns
+    /// The query, or a description of why it could not be parsed.
+    pub(crate) fn parse(text: &str) -> Result<Self, String> {
+        let tokens = tokenize(text)?;
+        let (start, end) = match tokens.first() {
+            Some(Token::Word(range)) => parse_range(range),
+            _ => None,
+        }
+        .ok_or_else(|| "a query must start with a range such as A1_F500".to_string())?;
+
+        let mut parser = Parser {
+            tokens: &tokens,
+            position: 1,
+            start,
+            end,
+        };
+
+        parser.expect_keyword("select")?;
+        let mut select = vec![parser.item()?];
+        while parser.is_symbol(",") {
+            parser.position += 1;
+            select.push(parser.item()?);
+        }
+
+        let mut filters = Vec::new();
+        if parser.is_keyword("where") {
+            parser.position += 1;
+            filters.push(parser.filter()?);
+            while parser.is_keyword("and") {
+                parser.position += 1;
+                filters.push(parser.filter()?);
+            }
+        }
+
+        let mut group_by = Vec::new();
+        if parser.is_keyword("group") {
+            parser.position += 1;
+            parser.expect_keyword("by")?;
+            group_by.push(parser.column()?);
+            while parser.is_symbol(",") {
+                parser.position += 1;
+                group_by.push(parser.column()?);
+            }
+        }
+
+        if parser.peek().is_some() {
+            return Err(format!("unexpected {}", parser.describe()));
+        }
+
+        // Without a group, a plain column has no single value to go alongside an aggregate
+        let aggregating = !group_by.is_empty()
+            || select
+                .iter()
+                .any(|item| matches!(item, Item::Aggregate(..)));
+        for item in &select {
+            if let Item::Column(column) = item {
+                if aggregating && !group_by.contains(column) {
+                    return Err(format!(
+                        "column {} must be in the group by or inside an aggregate",
+                        column_number_to_name(*column)
+                    ));
+                }
+            }
+        }
+
+        Ok(Query {
+            start,
+            end,
+            select,
+            filters,
+            group_by,
+        })
+    }
+
+    /// Runs the query over the values of its range.
+    ///
+    /// Rows which are entirely empty are skipped, so a range can leave room for more data.
+    ///
+    /// # Parameters
+    /// * `values`: The values of the range, row by row, starting with the header row.
+    ///
+    /// # Returns
+    /// The resulting table starting with its own header row, or an error if an aggregate
+    /// cannot be calculated.
+    pub(crate) fn run(&self, values: Vec<Vec<CellValue>>) -> Result<Vec<Vec<CellValue>>, String> {
+        let index = |column: u32| (column - self.start.col) as usize;
+        let header = values.first().cloned().unwrap_or_default();
+
+        let column_name = |column: u32| match header.get(index(column)) {
+            Some(CellValue::String(name)) => name.clone(),
+            Some(CellValue::Int(name)) => name.to_string(),
+            _ => column_number_to_name(column),
+        };
+        let mut table = vec![self
+            .select
+            .iter()
+            .map(|item| match item {
+                Item::Column(column) => CellValue::String(column_name(*column)),
+                Item::Aggregate(aggregate, Some(column)) => {
+                    CellValue::String(format!("{}({})", aggregate.name(), column_name(*column)))
+                }
+                Item::Aggregate(aggregate, None) => {
+                    CellValue::String(format!("{}(*)", aggregate.name()))
+                }
+            })
+            .collect()];
+
+        let rows: Vec<&Vec<CellValue>> = values
+            .iter()
+            .skip(1)
+            .filter(|row| row.iter().any(|value| *value != CellValue::None))
+            .filter(|row| {
+                self.filters
+                    .iter()
+                    .all(|filter| filter.matches(&row[index(filter.column)]))
+            })
+            .collect();
+
+        let aggregating = !self.group_by.is_empty()
+            || self
+                .select
+                .iter()
+                .any(|item| matches!(item, Item::Aggregate(..)));
+        if !aggregating {
+            for row in rows {
+                table.push(
+                    self.select
+                        .iter()
+                        .map(|item| match item {
+                            Item::Column(column) => row[index(*column)].clone(),
+                            Item::Aggregate(..) => unreachable!("checked when parsed"),
+                        })
+                        .collect(),
+                );
+            }
+            return Ok(table);
+        }
+
+        // Groups keep the order in which they first appear
+        let mut groups: Vec<Vec<&Vec<CellValue>>> = Vec::new();
+        let mut group_index: HashMap<Vec<CellValue>, usize> = HashMap::new();
+        if self.group_by.is_empty() {
+            groups.push(rows);
+        } else {
+            for row in rows {
+                let key: Vec<CellValue> = self
+                    .group_by
+                    .iter()
+                    .map(|column| row[index(*column)].clone())
+                    .collect();
+                let position = *group_index.entry(key).or_insert_with(|| {
+                    groups.push(Vec::new());
+                    groups.len() - 1
+                });
+                groups[position].push(row);
+            }
+        }
+
+        for group in groups {
+            let mut result = Vec::new();
+            for item in &self.select {
+                result.push(match item {
+                    Item::Column(column) => group[0][index(*column)].clone(),
+                    Item::Aggregate(aggregate, column) => {
+                        let column_values: Vec<&CellValue> = match column {
+                            Some(column) => group.iter().map(|row| &row[index(*column)]).collect(),
+                            None => Vec::new(),
+                        };
+                        match (aggregate, column) {
+                            (Aggregate::Count, None) => CellValue::Int(group.len() as i64),
+                            _ => aggregate.apply(&column_values, *column)?,
+                        }
+                    }
+                });
+            }
+            table.push(result);
+        }
+        Ok(table)
+    }
+}
+
+impl Aggregate {
+    /// Aggregates the values of a column, ignoring empty cells.
+    fn apply(&self, values: &[&CellValue], column: Option<u32>) -> Result<CellValue, String> {
+        let values = values.iter().filter(|value| ***value != CellValue::None);
+        match self {
+            Aggregate::Count => Ok(CellValue::Int(values.count() as i64)),
+            Aggregate::Sum => {
+                let mut total: i64 = 0;
+                for value in values {
+                    match value {
+                        CellValue::Int(int) => {
+                            total = total.checked_add(*int).ok_or_else(|| {
+                                format!(
+                                    "sum of column {} overflows",
+                                    column.map_or(String::new(), column_number_to_name)
+                                )
+                            })?
+                        }
+                        other => {
+                            return Err(format!(
+                                "cannot sum {} in column {}",
+                                other,
+                                column.map_or(String::new(), column_number_to_name)
+                            ))
+                        }
+                    }
+                }
+                Ok(CellValue::Int(total))
+            }
+            Aggregate::Min => Ok(values
+                .min_by(|a, b| compare_values(a, b))
+                .map_or(CellValue::None, |value| (*value).clone())),
+            Aggregate::Max => Ok(values
+                .max_by(|a, b| compare_values(a, b))
+                .map_or(CellValue::None, |value| (*value).clone())),
+        }
+    }
+}
+
+/// Formats a row of a query result for a table reply, with columns separated by ` | `.
+pub(crate) fn format_row(row: &[CellValue]) -> String {
+    row.iter()
+        .map(|value| match value {
+            CellValue::Int(int) => int.to_string(),
+            CellValue::String(string) => string.clone(),
+            CellValue::Error(error) => format!("Error: {}", error),
+            CellValue::None => String::new(),
+        })
+        .collect::<Vec<_>>()
+        .join(" | ")
+}
+
+// ===================== TESTS ============================
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sales() -> Vec<Vec<CellValue>> {
+        let row = |state: &str, product: &str, amount: i64| {
+            vec![
+                CellValue::String(state.to_string()),
+                CellValue::String(product.to_string()),
+                CellValue::Int(amount),
+            ]
+        };
+        vec![
+            vec![
+                CellValue::String("State".to_string()),
+                CellValue::String("Product".to_string()),
+                CellValue::String("Amount".to_string()),
+            ],
+            row("NSW", "Tea", 5),
+            row("VIC", "Tea", 7),
+            row("NSW", "Coffee", 3),
+            row("NSW", "Tea", 10),
+            vec![CellValue::None, CellValue::None, CellValue::None],
+        ]
+    }
+
+    // 1. Test parsing queries, and rejecting invalid ones
+    #[test]
+    fn test_parse() {
+        let query = Query::parse("A1_C6 select B, sum(C) where A = \"NSW\" group by B").unwrap();
+        assert_eq!(query.start, "A1".parse().unwrap());
+        assert_eq!(query.end, "C6".parse().unwrap());
+        assert_eq!(query.group_by, vec![1]);
+        assert_eq!(
+            query.select,
+            vec![Item::Column(1), Item::Aggregate(Aggregate::Sum, Some(2))]
+        );
+
+        assert!(Query::parse("A1_C6 B").is_err());
+        assert!(Query::parse("A1_C6 select D").is_err());
+        assert!(Query::parse("A1_C6 select A, sum(C)").is_err());
+        assert!(Query::parse("A1_C6 select A where A = ").is_err());
+    }
+
+    // 2. Test filtering and grouping rows
+    #[test]
+    fn test_run() {
+        let query = Query::parse("A1_C6 select B, sum(C) where A = \"NSW\" group by B").unwrap();
+        let table = query.run(sales()).unwrap();
+        assert_eq!(format_row(&table[0]), "Product | sum(Amount)");
+        assert_eq!(format_row(&table[1]), "Tea | 15");
+        assert_eq!(format_row(&table[2]), "Coffee | 3");
+        assert_eq!(table.len(), 3);
+
+        let query = Query::parse("A1_C6 select A, C where C >= 7").unwrap();
+        let table = query.run(sales()).unwrap();
+        assert_eq!(table.len(), 3);
+        assert_eq!(
+            table[2],
+            vec![CellValue::String("NSW".to_string()), CellValue::Int(10)]
+        );
+
+        let query = Query::parse("A1_C6 select count(*), max(C), sum(B)").unwrap();
+        assert!(query.run(sales()).is_err());
+    }
+
+    // 3. Test a sum too large for an integer is an error, not a panic
+    #[test]
+    fn test_sum_overflow() {
+        let mut rows = sales();
+        rows[1][2] = CellValue::Int(i64::MAX);
+        let query = Query::parse("A1_C6 select sum(C)").unwrap();
+        assert_eq!(
+            query.run(rows),
+            Err("sum of column C overflows".to_string())
+        );
+    }
+}
@@ -0,0 +1,159 @@
+use crate::{apply_set, SetOrigin, EXPR_MAP};
+use log::{info, warn};
+use rsheet_lib::command::Command;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+/// How long to wait before reconnecting to a primary that went away.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// How long a follower may stall a write before it is dropped.
+const FOLLOWER_WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+lazy_static::lazy_static! {
+    /// The queue of lines waiting to be written to each follower.
+    static ref FOLLOWERS: Mutex<Vec<Sender<String>>> = Mutex::new(Vec::new());
+    static ref PRIMARY: Mutex<Option<SocketAddr>> = Mutex::new(None);
+}
+
+// ===================== PRIMARY ============================
+
+/// Starts accepting follower connections in the background.
+///
+/// Each follower is first sent every stored expression as a `set` line, then every `Set`
+/// applied afterwards, using the same line protocol as clients.
+///
+/// # Parameters
+/// * `addr`: The address to accept follower connections on.
+///
+/// # Returns
+/// * Result, which fails if the address could not be bound.
+pub fn serve_followers(addr: SocketAddr) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    info!("Accepting followers on {}", addr);
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream.and_then(add_follower);
+            if let Err(e) = result {
+                warn!("Could not add follower: {}", e);
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Queues a snapshot of all expressions for a new follower, registers it for updates, and
+/// starts the thread which writes to it.
+///
+/// The expression lock is held until the follower is registered, so no set can be applied
+/// between the snapshot and the first streamed update.
+fn add_follower(stream: TcpStream) -> io::Result<()> {
+    stream.set_write_timeout(Some(FOLLOWER_WRITE_TIMEOUT))?;
+    let peer = stream.peer_addr()?;
+    let (sender, receiver) = mpsc::channel();
+
+    {
+        let exprs = EXPR_MAP.lock().unwrap();
+        for (cell_address, expr) in exprs.iter() {
+            let _ = sender.send(format!("set {} {}\n", cell_address, expr));
+        }
+        FOLLOWERS.lock().unwrap().push(sender);
+    }
+
+    info!("Follower {} connected", peer);
+    thread::spawn(move || {
+        if let Err(e) = write_to_follower(stream, receiver) {
+            warn!("Dropping follower {}: {}", peer, e);
+        }
+    });
+    Ok(())
+}
+
+/// Writes queued lines to a follower until it stops accepting them.
+///
+/// Lines which are already queued are written together, and flushed once the queue is empty.
+fn write_to_follower(stream: TcpStream, lines: Receiver<String>) -> io::Result<()> {
+    let mut writer = BufWriter::new(stream);
+    while let Ok(line) = lines.recv() {
+        writer.write_all(line.as_bytes())?;
+        for line in lines.try_iter() {
+            writer.write_all(line.as_bytes())?;
+        }
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+/// Queues an applied set for every follower, dropping any whose writer has stopped.
+///
+/// Nothing is written here, since this is called with every map locked. Each follower's own
+/// thread writes the set, so a slow or dead follower cannot hold up the primary.
+///
+/// # Parameters
+/// * `cell_address`: The address of the cell that was set.
+/// * `cell_expr`: The expression the cell was set to.
+pub(crate) fn broadcast_set(cell_address: &str, cell_expr: &str) {
+    let line = format!("set {} {}\n", cell_address, cell_expr);
+
+    FOLLOWERS
+        .lock()
+        .unwrap()
+        .retain(|follower| follower.send(line.clone()).is_ok());
+}
+
+// ===================== FOLLOWER ============================
+
+/// Starts this server as a read-only follower of a primary.
+///
+/// A background thread tails the primary's stream of sets and applies them locally,
+/// reconnecting whenever the primary goes away.
+///
+/// # Parameters
+/// * `primary`: The address the primary accepts followers on.
+pub fn follow(primary: SocketAddr) {
+    *PRIMARY.lock().unwrap() = Some(primary);
+
+    thread::spawn(move || loop {
+        match TcpStream::connect(primary) {
+            Ok(stream) => {
+                info!("Following primary {}", primary);
+                match tail_primary(stream) {
+                    Ok(()) => warn!("Primary {} closed the connection", primary),
+                    Err(e) => warn!("Lost connection to primary {}: {}", primary, e),
+                }
+            }
+            Err(e) => warn!("Could not connect to primary {}: {}", primary, e),
+        }
+        thread::sleep(RECONNECT_DELAY);
+    });
+}
+
+/// Applies every `set` line sent by the primary until the connection closes.
+fn tail_primary(stream: TcpStream) -> io::Result<()> {
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        match line.parse::<Command>() {
+            Ok(Command::Set {
+                cell_identifier,
+                cell_expr,
+            }) => {
+                if let Err(e) = apply_set(&cell_identifier, &cell_expr, SetOrigin::Primary) {
+                    warn!("Could not apply set from primary: {}", e);
+                }
+            }
+            _ => warn!("Ignoring invalid replication message: {}", line),
+        }
+    }
+    Ok(())
+}
+
+/// Returns the address of the primary, if this server is a follower.
+pub fn primary_address() -> Option<SocketAddr> {
+    *PRIMARY.lock().unwrap()
+}
@@ -0,0 +1,25 @@
+mod harness;
+
+use std::fmt::Write;
+
+/// Runs gets of clean cells while background workers are recalculating many dependents, so
+/// the gets take the cell locks at the same time as the workers.
+#[test]
+fn test_gets_during_recalculation() {
+    rsheet::background::start_workers(4, false);
+
+    let mut script = String::from("a: set C1 5\n");
+    for i in 1..=50 {
+        writeln!(script, "a: set B{} A1 + {}", i, i).unwrap();
+    }
+    for round in 1..=20 {
+        writeln!(script, "a: set A1 {}", round).unwrap();
+        for _ in 0..5 {
+            script.push_str("a: get C1\na> C1 = 5\n");
+        }
+    }
+    // A get of a dirty cell waits for the workers to recalculate it
+    script.push_str("a: get B50\na> B50 = 70\n");
+
+    harness::run_script(&script).unwrap();
+}
@@ -0,0 +1,220 @@
+//! An in-memory `Manager` which drives `start_server` from a script.
+//!
+//! Scripts are line based. Blank lines and lines starting with `#` are ignored.
+//!
+//! ```text
+//! alice: set A1 5        # send `set A1 5` as sender `alice`
+//! alice: sleep 100       # `alice` waits 100ms before reading its next command
+//! bob: get A1            # senders connect the first time they are used
+//! bob> A1 = 5            # `bob`'s next reply must be `A1 = 5`
+//! bob> Error: Cannot*    # a trailing `*` only matches the start of the reply
+//! sleep 50               # the script itself waits 50ms
+//! ```
+//!
+//! Replies are formatted the same way as the `TerminalManager` prints them. Once the script
+//! ends, every connection is closed and any reply that was not expected fails the script.
+
+use rsheet::start_server;
+use rsheet_lib::connect::{
+    Connection, Manager, ReadMessageResult, Reader, ReaderWriter, WriteMessageResult, Writer,
+};
+use rsheet_lib::replies::Reply;
+use std::collections::HashMap;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::Duration;
+
+/// How long to wait for an expected reply before failing.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct ScriptManager {
+    receiver: Receiver<(ScriptReader, ScriptWriter)>,
+}
+
+pub struct ScriptReaderWriter;
+
+pub struct ScriptReader {
+    receiver: Receiver<String>,
+    id: String,
+}
+
+pub struct ScriptWriter {
+    sender: Sender<Reply>,
+    id: String,
+}
+
+impl ReaderWriter for ScriptReaderWriter {
+    type Reader = ScriptReader;
+    type Writer = ScriptWriter;
+}
+
+impl Manager for ScriptManager {
+    type ReaderWriter = ScriptReaderWriter;
+
+    fn accept_new_connection(&mut self) -> Connection<ScriptReader, ScriptWriter> {
+        match self.receiver.recv() {
+            Ok((reader, writer)) => Connection::NewConnection { reader, writer },
+            Err(_) => Connection::NoMoreConnections,
+        }
+    }
+}
+
+impl Reader for ScriptReader {
+    fn read_message(&mut self) -> ReadMessageResult {
+        loop {
+            match self.receiver.recv() {
+                Ok(message) => match message.strip_prefix("sleep ") {
+                    Some(millis) => {
+                        thread::sleep(Duration::from_millis(millis.trim().parse().unwrap_or(1)))
+                    }
+                    None => return ReadMessageResult::Message(message),
+                },
+                Err(_) => return ReadMessageResult::ConnectionClosed,
+            }
+        }
+    }
+
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+}
+
+impl Writer for ScriptWriter {
+    fn write_message(&mut self, message: Reply) -> WriteMessageResult {
+        match self.sender.send(message) {
+            Ok(()) => WriteMessageResult::Ok,
+            Err(_) => WriteMessageResult::ConnectionClosed,
+        }
+    }
+
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+}
+
+/// Formats a reply the same way `TerminalWriter` prints it.
+fn format_reply(reply: &Reply) -> String {
+    match reply {
+        Reply::Value(name, value) => format!("{} = {}", name, value),
+        Reply::Error(e) => format!("Error: {}", e),
+    }
+}
+
+fn matches(expected: &str, actual: &str) -> bool {
+    match expected.strip_suffix('*') {
+        Some(prefix) => actual.starts_with(prefix),
+        None => actual == expected,
+    }
+}
+
+/// Splits a `name: command` or `name> reply` line into the sender name, separator and rest.
+fn split_sender(line: &str) -> Option<(&str, char, &str)> {
+    let end = line.find(|c: char| !(c.is_alphanumeric() || c == '_'))?;
+    let (name, rest) = line.split_at(end);
+    let separator = rest.chars().next()?;
+    if name.is_empty() || !matches!(separator, ':' | '>') {
+        return None;
+    }
+    Some((name, separator, rest[1..].trim()))
+}
+
+/// Runs a script against a fresh server.
+///
+/// # Parameters
+/// * `script`: The contents of the script.
+///
+/// # Returns
+/// `Ok(())` if every expected reply was received and nothing else, otherwise a description of
+/// the first failure and the line it happened on.
+pub fn run_script(script: &str) -> Result<(), String> {
+    rsheet::reset_state();
+
+    let (connections, receiver) = mpsc::channel();
+    let server =
+        thread::spawn(move || start_server(ScriptManager { receiver }).map_err(|e| e.to_string()));
+
+    let mut commands: HashMap<String, Sender<String>> = HashMap::new();
+    let mut replies: HashMap<String, Receiver<Reply>> = HashMap::new();
+
+    for (index, line) in script.lines().enumerate() {
+        let line_number = index + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        if let Some(millis) = line.strip_prefix("sleep ") {
+            let millis = millis
+                .trim()
+                .parse()
+                .map_err(|_| format!("line {}: invalid sleep '{}'", line_number, line))?;
+            thread::sleep(Duration::from_millis(millis));
+            continue;
+        }
+
+        let (name, separator, rest) = split_sender(line)
+            .ok_or_else(|| format!("line {}: invalid script line '{}'", line_number, line))?;
+
+        if separator == ':' {
+            // Connect the sender the first time it is used
+            if !commands.contains_key(name) {
+                let (command_sender, command_receiver) = mpsc::channel();
+                let (reply_sender, reply_receiver) = mpsc::channel();
+                let reader = ScriptReader {
+                    receiver: command_receiver,
+                    id: name.to_string(),
+                };
+                let writer = ScriptWriter {
+                    sender: reply_sender,
+                    id: name.to_string(),
+                };
+                connections
+                    .send((reader, writer))
+                    .map_err(|_| format!("line {}: server has stopped", line_number))?;
+                commands.insert(name.to_string(), command_sender);
+                replies.insert(name.to_string(), reply_receiver);
+            }
+            commands[name]
+                .send(rest.to_string())
+                .map_err(|_| format!("line {}: {} is disconnected", line_number, name))?;
+        } else {
+            let receiver = replies.get(name).ok_or_else(|| {
+                format!(
+                    "line {}: {} expects a reply but never connected",
+                    line_number, name
+                )
+            })?;
+            let actual = receiver
+                .recv_timeout(REPLY_TIMEOUT)
+                .map(|reply| format_reply(&reply))
+                .map_err(|_| {
+                    format!(
+                        "line {}: {} expected '{}' but got nothing",
+                        line_number, name, rest
+                    )
+                })?;
+            if !matches(rest, &actual) {
+                return Err(format!(
+                    "line {}: {} expected '{}' but got '{}'",
+                    line_number, name, rest, actual
+                ));
+            }
+        }
+    }
+
+    // Close every connection, then wait for the server to finish
+    drop(commands);
+    drop(connections);
+    server.join().map_err(|_| "server panicked".to_string())??;
+
+    for (name, receiver) in replies {
+        if let Ok(reply) = receiver.try_recv() {
+            return Err(format!(
+                "{} got unexpected reply '{}'",
+                name,
+                format_reply(&reply)
+            ));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,35 @@
+mod harness;
+
+use rsheet::limits::{set_limits, Limits};
+
+/// Runs sets and queries against a server with every limit set.
+#[test]
+fn test_limits() {
+    set_limits(Limits {
+        max_cells: Some(3),
+        max_expr_bytes: Some(30),
+        max_string_bytes: Some(5),
+        max_range_cells: Some(4),
+    });
+
+    let script = "
+        a: set A1 1
+        a: set A2 2
+        a: set A3 \"abcdef\"
+        a: get A3
+        a> Error: Cannot get cell A3: it depends on an error - string value is 6 bytes*
+
+        # Cells which are already held can still be set
+        a: set A4 4
+        a> Error: Cannot set cell A4: the sheet already holds 3 cells, the limit
+        a: set A3 sum(A1_A2)
+        a: get A3
+        a> A3 = 3
+
+        a: set A1 sum(B1_B5)
+        a> Error: Cannot set cell A1: range B1_B5 covers 5 cells, over the limit of 4
+        a: query A1_A5 select A
+        a> Error: Cannot run query: range A1_A5 covers 5 cells, over the limit of 4
+    ";
+    harness::run_script(script).unwrap();
+}
@@ -0,0 +1,67 @@
+mod harness;
+
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+/// Runs a script against a primary, with a follower server in another process, then checks
+/// the follower has every set and rejects sets of its own.
+#[test]
+fn test_follower_replays_sets() {
+    // Find a free port for the primary to accept followers on
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    rsheet::replication::serve_followers(addr).unwrap();
+
+    let mut follower = Command::new(env!("CARGO_BIN_EXE_rsheet"))
+        .args(["--follow", &addr.to_string()])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    // Sets made before the follower connects reach it in its snapshot, and the rest are streamed
+    let script = "
+        a: set A1 5
+        sleep 500
+        a: set A2 A1 * 2
+        a: set A1 7
+        a: get A2
+        a> A2 = 14
+        sleep 500
+    ";
+    let result = harness::run_script(script);
+
+    let mut stdin = follower.stdin.take().unwrap();
+    stdin
+        .write_all(b"get A1\nget A2\nset A3 1\nsleep 100\n")
+        .unwrap();
+    thread::sleep(Duration::from_millis(200));
+    // An empty line stops the follower once it has answered
+    stdin.write_all(b"\n").unwrap();
+    drop(stdin);
+
+    let mut output = String::new();
+    follower
+        .stdout
+        .take()
+        .unwrap()
+        .read_to_string(&mut output)
+        .unwrap();
+    follower.wait().unwrap();
+    result.unwrap();
+
+    let lines: Vec<&str> = output.lines().collect();
+    assert_eq!(lines.len(), 3, "{:?}", lines);
+    assert_eq!(lines[0], "A1 = 7");
+    assert_eq!(lines[1], "A2 = 14");
+    assert!(
+        lines[2].starts_with("Error: Cannot set cell A3: this server is a read-only follower"),
+        "{}",
+        lines[2]
+    );
+}
@@ -0,0 +1,31 @@
+mod harness;
+
+use std::fs;
+use std::path::Path;
+
+/// Runs every script in `tests/scripts` one after another, since they share the server's state.
+#[test]
+fn test_scripts() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/scripts");
+    let mut paths: Vec<_> = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "rss"))
+        .collect();
+    paths.sort();
+    assert!(!paths.is_empty(), "no scripts found in {:?}", dir);
+
+    let mut failures = Vec::new();
+    for path in &paths {
+        let script = fs::read_to_string(path).unwrap();
+        if let Err(e) = harness::run_script(&script) {
+            failures.push(format!(
+                "{}: {}",
+                path.file_name().unwrap().to_string_lossy(),
+                e
+            ));
+        }
+    }
+
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}
@@ -0,0 +1,298 @@
+//! Compares running large `WHILE` loops on the bytecode VM with the tree-walking interpreter it
+//! replaced.
+//!
+//! The tree-walker is in `tree_walker` below, ported to the current AST with only the
+//! statements these loops use. Like the original, it parses the program once, then walks the
+//! statements on every iteration, reading each literal from its word as it goes.
+//!
+//! Each is timed as the fastest of a few runs, since a single run is easily slowed by other work
+//! on the machine. The VM ran these loops about twice as fast as the tree-walker when this was
+//! written.
+//!
+//! Run with `cargo bench --bench vm`.
+
+use rslogo::canvas::Canvas;
+use rslogo::compiler::compile;
+use rslogo::lexer::tokenize;
+use rslogo::parser::parse;
+use rslogo::vm::Vm;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// The body of each loop, which moves the turtle in a spiral without drawing.
+const BODY: &str = "FORWARD / :i \"100\nTURN \"7\nMAKE \"x + XCOR * :i \"2\nIF GT :x \"1000 [\n  SETX \"0\n]\nADDASSIGN \"i \"1\n";
+
+/// How many times each program is run, keeping the fastest.
+const RUNS: usize = 3;
+
+fn program(iterations: usize) -> String {
+    format!("MAKE \"i \"0\nWHILE LT :i \"{} [\n{}]\n", iterations, BODY)
+}
+
+/// Runs the program on the tree-walking interpreter.
+fn walked(iterations: usize) -> Duration {
+    let program = program(iterations);
+    let mut image = Canvas::new(400, 400);
+    let start = Instant::now();
+    let statements = parse(&tokenize(&program)).unwrap();
+    tree_walker::Interpreter::new(&mut image)
+        .run(&statements)
+        .unwrap();
+    start.elapsed()
+}
+
+/// Runs the program compiled to bytecode.
+fn compiled(iterations: usize) -> Duration {
+    let program = program(iterations);
+    let mut image = Canvas::new(400, 400);
+    let start = Instant::now();
+    let chunk = Rc::new(compile(&parse(&tokenize(&program)).unwrap()));
+    Vm::new(&mut image).run(&chunk).unwrap();
+    start.elapsed()
+}
+
+/// Returns the fastest of `RUNS` runs.
+fn fastest(run: fn(usize) -> Duration, iterations: usize) -> Duration {
+    (0..RUNS)
+        .map(|_| run(iterations))
+        .min()
+        .expect("RUNS is not 0")
+}
+
+fn main() {
+    println!(
+        "{:>10}  {:>12}  {:>12}  {:>8}",
+        "iterations", "tree-walker", "vm", "speedup"
+    );
+    for iterations in [10_000, 100_000, 1_000_000] {
+        let slow = fastest(walked, iterations);
+        let fast = fastest(compiled, iterations);
+        println!(
+            "{:>10}  {:>10.2}ms  {:>10.2}ms  {:>7.1}x",
+            iterations,
+            slow.as_secs_f64() * 1000.0,
+            fast.as_secs_f64() * 1000.0,
+            slow.as_secs_f64() / fast.as_secs_f64()
+        );
+    }
+}
+
+/// The interpreter from before the VM, which runs statements straight from the AST.
+mod tree_walker {
+    use rslogo::ast::{Expression, ExpressionKind, Operator, Query, Statement, StatementKind};
+    use rslogo::canvas::Canvas;
+    use rslogo::error::{ErrorKind, LogoError};
+    use rslogo::lexer::Position;
+    use rslogo::turtle::Turtle;
+    use rslogo::value::Value;
+    use std::collections::HashMap;
+
+    pub struct Interpreter<'a> {
+        canvas: &'a mut Canvas,
+        turtle: Turtle,
+        /// The global scope, followed by the scope of each procedure being called.
+        scopes: Vec<HashMap<String, Value>>,
+    }
+
+    impl<'a> Interpreter<'a> {
+        pub fn new(canvas: &'a mut Canvas) -> Self {
+            let (width, height) = canvas.get_dimensions();
+            Interpreter {
+                canvas,
+                turtle: Turtle::new(width, height),
+                scopes: vec![HashMap::new()],
+            }
+        }
+
+        fn variable(&self, name: &str) -> Option<&Value> {
+            self.scopes.iter().rev().find_map(|scope| scope.get(name))
+        }
+
+        /// Sets a variable in the innermost scope which has it, or globally if none does.
+        fn assign(&mut self, name: String, value: Value) {
+            let scope = match self
+                .scopes
+                .iter()
+                .rposition(|scope| scope.contains_key(&name))
+            {
+                Some(index) => &mut self.scopes[index],
+                None => &mut self.scopes[0],
+            };
+            scope.insert(name, value);
+        }
+
+        pub fn run(&mut self, statements: &[Statement]) -> Result<(), LogoError> {
+            for statement in statements {
+                self.execute(statement)?;
+            }
+            Ok(())
+        }
+
+        fn execute(&mut self, statement: &Statement) -> Result<(), LogoError> {
+            match &statement.kind {
+                StatementKind::Move {
+                    direction,
+                    distance,
+                } => {
+                    let distance = self.number(distance)?;
+                    self.turtle
+                        .move_by(self.canvas, direction.offset(), distance);
+                }
+                StatementKind::Turn(angle) => {
+                    let angle = self.number(angle)?;
+                    self.turtle.turn(angle);
+                }
+                StatementKind::SetX(x) => {
+                    let x = self.number(x)?;
+                    self.turtle.set_position(x, self.turtle.y);
+                }
+                StatementKind::Make { name, value } => {
+                    let name = self.evaluate(name)?.to_string();
+                    let value = self.evaluate(value)?;
+                    self.assign(name, value);
+                }
+                StatementKind::AddAssign { name, value } => {
+                    let name_position = name.position;
+                    let name = self.evaluate(name)?.to_string();
+                    let value = self.number(value)?;
+                    let current = self.variable(&name).ok_or_else(|| {
+                        LogoError::new(ErrorKind::UnknownVariable(name.clone()), name_position)
+                    })?;
+                    let sum = number(current, name_position)?
+                        .checked_add(value)
+                        .ok_or_else(|| LogoError::new(ErrorKind::Overflow, name_position))?;
+                    self.assign(name, Value::Number(sum));
+                }
+                StatementKind::If {
+                    condition,
+                    body,
+                    else_body,
+                } => {
+                    if self.condition(condition)? {
+                        self.run(body)?;
+                    } else if let Some(else_body) = else_body {
+                        self.run(else_body)?;
+                    }
+                }
+                StatementKind::While { condition, body } => {
+                    while self.condition(condition)? {
+                        self.run(body)?;
+                    }
+                }
+                other => unimplemented!("the benchmark does not use {:?}", other),
+            }
+            Ok(())
+        }
+
+        fn number(&self, expression: &Expression) -> Result<i32, LogoError> {
+            number(&self.evaluate(expression)?, expression.position)
+        }
+
+        fn condition(&self, expression: &Expression) -> Result<bool, LogoError> {
+            boolean(&self.evaluate(expression)?, expression.position)
+        }
+
+        fn evaluate(&self, expression: &Expression) -> Result<Value, LogoError> {
+            let position = expression.position;
+            match &expression.kind {
+                ExpressionKind::Literal(literal) => Ok(Value::from_word(literal)),
+                ExpressionKind::Variable(name) => self.variable(name).cloned().ok_or_else(|| {
+                    LogoError::new(ErrorKind::UnknownVariable(name.clone()), position)
+                }),
+                ExpressionKind::Query(Query::XCor) => Ok(Value::Number(self.turtle.x)),
+                ExpressionKind::Query(Query::YCor) => Ok(Value::Number(self.turtle.y)),
+                ExpressionKind::Query(other) => {
+                    unimplemented!("the benchmark does not use {:?}", other)
+                }
+                ExpressionKind::Binary {
+                    operator,
+                    left,
+                    right,
+                } => {
+                    let a = self.evaluate(left)?;
+                    let b = self.evaluate(right)?;
+                    apply(
+                        *operator,
+                        (&a, left.position),
+                        (&b, right.position),
+                        position,
+                    )
+                }
+            }
+        }
+    }
+
+    fn number(value: &Value, position: Position) -> Result<i32, LogoError> {
+        match value {
+            Value::Number(number) => Ok(*number),
+            other => Err(LogoError::new(
+                ErrorKind::WrongType {
+                    expected: "a number",
+                    found: other.to_string(),
+                },
+                position,
+            )),
+        }
+    }
+
+    fn boolean(value: &Value, position: Position) -> Result<bool, LogoError> {
+        match value {
+            Value::Bool(boolean) => Ok(*boolean),
+            other => Err(LogoError::new(
+                ErrorKind::WrongType {
+                    expected: "TRUE or FALSE",
+                    found: other.to_string(),
+                },
+                position,
+            )),
+        }
+    }
+
+    fn apply(
+        operator: Operator,
+        left: (&Value, Position),
+        right: (&Value, Position),
+        position: Position,
+    ) -> Result<Value, LogoError> {
+        match operator {
+            Operator::Add | Operator::Subtract | Operator::Multiply | Operator::Divide => {
+                let a = number(left.0, left.1)?;
+                let b = number(right.0, right.1)?;
+                let result = match operator {
+                    Operator::Add => a.checked_add(b),
+                    Operator::Subtract => a.checked_sub(b),
+                    Operator::Multiply => a.checked_mul(b),
+                    _ => {
+                        if b == 0 {
+                            return Err(LogoError::new(ErrorKind::DivisionByZero, position));
+                        }
+                        a.checked_div(b)
+                    }
+                };
+                result
+                    .map(Value::Number)
+                    .ok_or_else(|| LogoError::new(ErrorKind::Overflow, position))
+            }
+            Operator::Lt | Operator::Gt => {
+                let a = number(left.0, left.1)?;
+                let b = number(right.0, right.1)?;
+                Ok(Value::Bool(if operator == Operator::Lt {
+                    a < b
+                } else {
+                    a > b
+                }))
+            }
+            Operator::Eq => Ok(Value::Bool(left.0 == right.0)),
+            Operator::Ne => Ok(Value::Bool(left.0 != right.0)),
+            Operator::And | Operator::Or => {
+                let a = boolean(left.0, left.1)?;
+                let b = boolean(right.0, right.1)?;
+                Ok(Value::Bool(if operator == Operator::And {
+                    a && b
+                } else {
+                    a || b
+                }))
+            }
+        }
+    }
+}
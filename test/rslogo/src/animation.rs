@@ -0,0 +1,257 @@
+use crate::canvas::{self, Canvas, Cursor, Shape};
+use resvg::tiny_skia;
+use std::fs::File;
+use std::path::Path;
+
+/// How many segments are drawn each second by default.
+pub const DEFAULT_SPEED: u32 = 10;
+
+/// The shortest delay between GIF frames, in hundredths of a second, which viewers respect.
+const MIN_GIF_DELAY: u32 = 2;
+
+/// How long a GIF shows the finished drawing before it starts again, in hundredths of a second.
+const GIF_HOLD: u16 = 200;
+
+/// Writes the drawing as an SVG which animates itself with SMIL, with the turtle on top.
+///
+/// Each frame appears `1 / speed` seconds after the one before it, and the finished drawing
+/// stays on screen.
+///
+/// # Parameters
+/// * `canvas`: The canvas, after a program has drawn on it.
+/// * `speed`: How many frames to draw per second.
+pub fn to_animated_svg(canvas: &Canvas, speed: u32) -> String {
+    let speed = f64::from(speed.max(1));
+    let time = |frame: usize| canvas::number(frame as f64 / speed);
+
+    let mut svg = canvas.svg_start() + &canvas.svg_background();
+    for (shape, &frame) in canvas.shapes().iter().zip(canvas.shape_frames()) {
+        svg.push_str(&format!(
+            "    {} visibility=\"hidden\">\n        <set attributeName=\"visibility\" to=\"visible\" begin=\"{}s\" fill=\"freeze\"/>\n    </path>\n",
+            shape.svg_element(),
+            time(frame)
+        ));
+    }
+
+    let cursors = canvas.cursors();
+    if let Some(first) = cursors.first() {
+        let duration = time(cursors.len());
+        let translations: Vec<_> = cursors
+            .iter()
+            .map(|cursor| format!("{} {}", cursor.position.0, cursor.position.1))
+            .collect();
+        let rotations: Vec<_> = cursors
+            .iter()
+            .map(|cursor| cursor.heading.to_string())
+            .collect();
+        svg.push_str(&format!(
+            "    <g transform=\"translate({} {})\">\n        <animateTransform attributeName=\"transform\" type=\"translate\" calcMode=\"discrete\" values=\"{}\" dur=\"{}s\" fill=\"freeze\"/>\n",
+            first.position.0,
+            first.position.1,
+            translations.join(";"),
+            duration
+        ));
+        svg.push_str(&format!(
+            "        <g transform=\"rotate({})\">\n            <animateTransform attributeName=\"transform\" type=\"rotate\" calcMode=\"discrete\" values=\"{}\" dur=\"{}s\" fill=\"freeze\"/>\n",
+            first.heading,
+            rotations.join(";"),
+            duration
+        ));
+        svg.push_str(&format!(
+            "            {}/>\n        </g>\n    </g>\n",
+            Cursor::svg_arrow()
+        ));
+    }
+
+    svg.push_str("</svg>\n");
+    svg
+}
+
+/// Saves the drawing as a GIF which loops forever, with the turtle on top.
+///
+/// GIF frames cannot be arbitrarily short, so at high speeds each frame of the GIF draws
+/// several frames of the canvas.
+///
+/// # Parameters
+/// * `canvas`: The canvas, after a program has drawn on it.
+/// * `path`: Where to save the GIF.
+/// * `speed`: How many frames of the canvas to draw per second.
+pub fn save_gif<P: AsRef<Path>>(canvas: &Canvas, path: P, speed: u32) -> Result<(), String> {
+    let (width, height) = canvas.get_dimensions();
+    let too_large = |_| format!("a GIF cannot be larger than {0}x{0}", u16::MAX);
+    let gif_width = u16::try_from(width).map_err(too_large)?;
+    let gif_height = u16::try_from(height).map_err(too_large)?;
+
+    let speed = speed.max(1);
+    let delay = (100 / speed).max(MIN_GIF_DELAY);
+    let step = (speed * delay / 100).max(1) as usize;
+    let last = canvas.cursors().len().saturating_sub(1);
+    let mut frames: Vec<usize> = (0..=last).step_by(step).collect();
+    if frames.last() != Some(&last) {
+        frames.push(last);
+    }
+
+    let file = File::create(path).map_err(|e| e.to_string())?;
+    let mut encoder =
+        gif::Encoder::new(file, gif_width, gif_height, &[]).map_err(|e| e.to_string())?;
+    encoder
+        .set_repeat(gif::Repeat::Infinite)
+        .map_err(|e| e.to_string())?;
+
+    let mut renderer = Renderer::new(canvas)?;
+    let mut previous: Option<tiny_skia::Pixmap> = None;
+    for (i, &frame) in frames.iter().enumerate() {
+        let pixmap = renderer.frame(frame)?;
+        let (left, top, right, bottom) = match &previous {
+            Some(previous) => changed(previous, &pixmap).unwrap_or((0, 0, 1, 1)),
+            None => (0, 0, width, height),
+        };
+
+        // Only the part which changed is encoded, and the rest is kept from the last frame
+        let mut pixels = Vec::with_capacity(((right - left) * (bottom - top) * 4) as usize);
+        for y in top..bottom {
+            let row = (y * width) as usize * 4;
+            pixels.extend_from_slice(
+                &pixmap.data()[row + left as usize * 4..row + right as usize * 4],
+            );
+        }
+        let mut gif_frame = gif::Frame::from_rgba_speed(
+            (right - left) as u16,
+            (bottom - top) as u16,
+            &mut pixels,
+            10,
+        );
+        gif_frame.left = left as u16;
+        gif_frame.top = top as u16;
+        gif_frame.dispose = gif::DisposalMethod::Keep;
+        gif_frame.delay = if i + 1 == frames.len() {
+            GIF_HOLD
+        } else {
+            delay as u16
+        };
+        encoder.write_frame(&gif_frame).map_err(|e| e.to_string())?;
+        previous = Some(pixmap);
+    }
+    Ok(())
+}
+
+/// Rasterizes the frames of a canvas in order, drawing only what is new in each one.
+struct Renderer<'a> {
+    canvas: &'a Canvas,
+    /// The shapes of the frames drawn so far, without the turtle.
+    drawing: tiny_skia::Pixmap,
+    /// The last frame in `drawing`.
+    frame: usize,
+}
+
+impl<'a> Renderer<'a> {
+    fn new(canvas: &'a Canvas) -> Result<Self, String> {
+        let background = canvas.svg_start() + &canvas.svg_background() + "</svg>\n";
+        Ok(Renderer {
+            canvas,
+            drawing: canvas::rasterize(&background)?,
+            frame: 0,
+        })
+    }
+
+    /// Renders a frame, which must not be before the last one rendered.
+    fn frame(&mut self, frame: usize) -> Result<tiny_skia::Pixmap, String> {
+        let shapes = || self.canvas.shapes().iter().zip(self.canvas.shape_frames());
+        // A fill goes beneath shapes drawn before it, so then everything is drawn again
+        let redraw = shapes().any(|(shape, &appears)| {
+            appears > self.frame && appears <= frame && matches!(shape, Shape::Polygon { .. })
+        });
+        let mut svg = self.canvas.svg_start();
+        if redraw {
+            svg.push_str(&self.canvas.svg_background());
+        }
+        for (shape, &appears) in shapes() {
+            if appears <= frame && (redraw || appears > self.frame) {
+                svg.push_str(&format!("    {}/>\n", shape.svg_element()));
+            }
+        }
+        svg.push_str("</svg>\n");
+        canvas::render_onto(&svg, &mut self.drawing)?;
+        self.frame = frame;
+
+        let mut pixmap = self.drawing.clone();
+        if let Some(cursor) = self.canvas.cursors().get(frame) {
+            let svg = format!(
+                "{}    {} transform=\"{}\"/>\n</svg>\n",
+                self.canvas.svg_start(),
+                Cursor::svg_arrow(),
+                cursor.svg_transform()
+            );
+            canvas::render_onto(&svg, &mut pixmap)?;
+        }
+        Ok(pixmap)
+    }
+}
+
+/// Finds the smallest rectangle containing every pixel which differs between two pixmaps.
+///
+/// # Returns
+/// The rectangle as `(left, top, right, bottom)`, exclusive of `right` and `bottom`, or
+/// `None` if they are the same.
+fn changed(before: &tiny_skia::Pixmap, after: &tiny_skia::Pixmap) -> Option<(u32, u32, u32, u32)> {
+    let width = after.width();
+    let mut bounds: Option<(u32, u32, u32, u32)> = None;
+    for (i, (a, b)) in before.pixels().iter().zip(after.pixels()).enumerate() {
+        if a != b {
+            let (x, y) = (i as u32 % width, i as u32 / width);
+            bounds = Some(match bounds {
+                None => (x, y, x + 1, y + 1),
+                Some((l, t, r, b)) => (l.min(x), t.min(y), r.max(x + 1), b.max(y + 1)),
+            });
+        }
+    }
+    bounds
+}
+
+// ===================== TESTS ============================
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use unsvg::Color;
+
+    /// Draws two lines, ending a frame after each.
+    fn two_lines() -> Canvas {
+        let mut canvas = Canvas::new(20, 20);
+        canvas.end_frame(Cursor {
+            position: (10, 10),
+            heading: 0,
+        });
+        canvas.draw_line((10, 10), (10, 0), Color::white(), 1);
+        canvas.end_frame(Cursor {
+            position: (10, 0),
+            heading: 0,
+        });
+        canvas.draw_line((10, 0), (20, 0), Color::white(), 1);
+        canvas.end_frame(Cursor {
+            position: (20, 0),
+            heading: 90,
+        });
+        canvas
+    }
+
+    // 1. Test each line appears in its own frame, with the turtle following them
+    #[test]
+    fn test_animated_svg() {
+        let svg = to_animated_svg(&two_lines(), 4);
+        assert!(svg.contains("d=\"M 10 10 L 10 0\" visibility=\"hidden\">\n        <set attributeName=\"visibility\" to=\"visible\" begin=\"0.25s\""));
+        assert!(svg.contains("d=\"M 10 0 L 20 0\" visibility=\"hidden\">\n        <set attributeName=\"visibility\" to=\"visible\" begin=\"0.5s\""));
+        assert!(svg.contains("values=\"10 10;10 0;20 0\" dur=\"0.75s\""));
+        assert!(svg.contains("values=\"0;0;90\" dur=\"0.75s\""));
+        assert!(canvas::rasterize(&svg).is_ok());
+    }
+
+    // 2. Test a GIF is written, even when it draws several frames at once
+    #[test]
+    fn test_save_gif() {
+        let path = std::env::temp_dir().join(format!("rslogo-test-{}.gif", std::process::id()));
+        save_gif(&two_lines(), &path, 100).unwrap();
+        let gif = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(gif.starts_with(b"GIF89a"));
+    }
+}
@@ -0,0 +1,198 @@
+use crate::lexer::Position;
+use std::rc::Rc;
+
+/// A single statement of a program, and where it starts.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Statement {
+    pub kind: StatementKind,
+    pub position: Position,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StatementKind {
+    PenUp,
+    PenDown,
+    /// `FORWARD`, `BACK`, `LEFT` or `RIGHT`.
+    Move {
+        direction: Direction,
+        distance: Expression,
+    },
+    Turn(Expression),
+    SetHeading(Expression),
+    SetX(Expression),
+    SetY(Expression),
+    SetPenColor(ColorExpression),
+    SetBackground(ColorExpression),
+    SetPenSize(Expression),
+    /// Moves along a circular arc, turning by `angle` on the way.
+    Arc {
+        angle: Expression,
+        radius: Expression,
+    },
+    /// Moves around a whole circle, ending where it started.
+    Circle(Expression),
+    BeginFill,
+    EndFill,
+    /// Creates a turtle with a name, in the middle of the canvas.
+    NewTurtle(Expression),
+    /// Makes the turtle with a name the one later commands move.
+    Tell(Expression),
+    /// Runs a block with the turtle with a name, then goes back to the turtle before.
+    Ask {
+        turtle: Expression,
+        body: Vec<Statement>,
+    },
+    Make {
+        name: Expression,
+        value: Expression,
+    },
+    AddAssign {
+        name: Expression,
+        value: Expression,
+    },
+    If {
+        condition: Expression,
+        body: Vec<Statement>,
+        else_body: Option<Vec<Statement>>,
+    },
+    While {
+        condition: Expression,
+        body: Vec<Statement>,
+    },
+    Repeat {
+        count: Expression,
+        body: Vec<Statement>,
+    },
+    /// `FOR "i start end step [ ... ]`, where the step is optional.
+    For {
+        variable: Expression,
+        start: Expression,
+        end: Expression,
+        step: Option<Expression>,
+        body: Vec<Statement>,
+    },
+    /// A `TO ... END` definition, which defines the procedure when it is run.
+    Procedure(Rc<Procedure>),
+    Call {
+        name: String,
+        arguments: Vec<Expression>,
+    },
+}
+
+/// A procedure definition.
+///
+/// Parameter names are expressions, evaluated when the definition is run, so
+/// `TO Box :name` names its parameter after the value `:name` has at that point.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Procedure {
+    pub name: String,
+    pub params: Vec<Expression>,
+    pub body: Vec<Statement>,
+}
+
+/// The colour given to `SETPENCOLOR` or `SETBACKGROUND`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ColorExpression {
+    /// An index into the 16 colours of `unsvg::COLORS`.
+    Palette(Expression),
+    /// Red, green and blue, each from 0 to 255.
+    Rgb(Box<[Expression; 3]>),
+}
+
+/// The way the turtle moves relative to its heading.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Forward,
+    Back,
+    Left,
+    Right,
+}
+
+impl Direction {
+    /// Returns the angle of this direction relative to the turtle's heading.
+    pub fn offset(&self) -> i32 {
+        match self {
+            Direction::Forward => 0,
+            Direction::Back => 180,
+            Direction::Left => -90,
+            Direction::Right => 90,
+        }
+    }
+}
+
+/// A prefix expression, and where it starts.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expression {
+    pub kind: ExpressionKind,
+    pub position: Position,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExpressionKind {
+    /// A quoted word such as `"50`.
+    Literal(String),
+    /// A variable such as `:size`.
+    Variable(String),
+    Query(Query),
+    Binary {
+        operator: Operator,
+        left: Box<Expression>,
+        right: Box<Expression>,
+    },
+}
+
+/// A query of the turtle's current state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Query {
+    XCor,
+    YCor,
+    Heading,
+    Color,
+    /// The number of the current iteration of the innermost `REPEAT`, counting from 1.
+    RepCount,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operator {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Eq,
+    Ne,
+    Lt,
+    Gt,
+    And,
+    Or,
+}
+
+impl Query {
+    pub fn from_word(word: &str) -> Option<Self> {
+        match word {
+            "XCOR" => Some(Query::XCor),
+            "YCOR" => Some(Query::YCor),
+            "HEADING" => Some(Query::Heading),
+            "COLOR" => Some(Query::Color),
+            "REPCOUNT" => Some(Query::RepCount),
+            _ => None,
+        }
+    }
+}
+
+impl Operator {
+    pub fn from_word(word: &str) -> Option<Self> {
+        match word {
+            "+" => Some(Operator::Add),
+            "-" => Some(Operator::Subtract),
+            "*" => Some(Operator::Multiply),
+            "/" => Some(Operator::Divide),
+            "EQ" => Some(Operator::Eq),
+            "NE" => Some(Operator::Ne),
+            "LT" => Some(Operator::Lt),
+            "GT" => Some(Operator::Gt),
+            "AND" => Some(Operator::And),
+            "OR" => Some(Operator::Or),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,462 @@
+//! The image a program draws onto, in place of `unsvg::Image`.
+//!
+//! `unsvg::Image` can only draw one pixel wide lines between whole-number points, on a black
+//! background. Pen sizes, filled shapes, `SETBACKGROUND` and arcs all need more than that, and
+//! its drawing is not exposed to build on, so this canvas keeps its own shapes instead. It
+//! still uses `unsvg::Color`, and writes plain drawings exactly as `unsvg::Image` does.
+//!
+//! PNG and GIF output is rendered with `resvg`, the same version `unsvg` renders with, so no
+//! more of it is built than before.
+
+use resvg::tiny_skia;
+use resvg::usvg::{self, TreeParsing};
+use unsvg::Color;
+
+/// Something drawn on a canvas.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Shape {
+    Line {
+        from: (i32, i32),
+        to: (i32, i32),
+        color: Color,
+        width: u32,
+    },
+    Arc {
+        arc: Arc,
+        color: Color,
+        width: u32,
+    },
+    /// A closed shape filled with a colour, without an outline.
+    Polygon {
+        start: (i32, i32),
+        edges: Vec<Edge>,
+        color: Color,
+    },
+}
+
+/// One side of a filled shape, from where the previous side ended.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Edge {
+    Line((i32, i32)),
+    Arc(Arc),
+}
+
+/// Part of a circle, with angles in degrees clockwise from straight up like turtle headings.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Arc {
+    pub center: (f64, f64),
+    pub radius: f64,
+    /// The direction from the centre to where the arc starts.
+    pub start: f64,
+    /// How far around the arc goes, clockwise if positive and anticlockwise if negative.
+    pub sweep: f64,
+}
+
+impl Arc {
+    /// Returns the point on the circle in a direction from its centre.
+    pub fn point(&self, angle: f64) -> (f64, f64) {
+        let (sin, cos) = angle.to_radians().sin_cos();
+        (
+            self.center.0 + self.radius * sin,
+            self.center.1 - self.radius * cos,
+        )
+    }
+
+    pub fn start_point(&self) -> (f64, f64) {
+        self.point(self.start)
+    }
+
+    pub fn end_point(&self) -> (f64, f64) {
+        self.point(self.start + self.sweep)
+    }
+
+    /// Writes the arc as SVG arc commands, continuing a path from its start point.
+    ///
+    /// An SVG arc cannot go all the way around, so the arc is split into quarter turns or less,
+    /// and sweeps of more than a whole turn are drawn as one turn.
+    fn svg_path(&self) -> String {
+        let sweep = self.sweep.clamp(-360.0, 360.0);
+        let pieces = (sweep.abs() / 90.0).ceil().max(1.0) as usize;
+        let flag = if sweep > 0.0 { 1 } else { 0 };
+        (1..=pieces)
+            .map(|i| {
+                let (x, y) = self.point(self.start + sweep * i as f64 / pieces as f64);
+                format!(
+                    "A {} {} 0 0 {} {} {}",
+                    number(self.radius),
+                    number(self.radius),
+                    flag,
+                    number(x),
+                    number(y)
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+/// Where the turtle is at the end of a frame of an animation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cursor {
+    pub position: (i32, i32),
+    pub heading: i32,
+}
+
+/// The image a program draws, kept as shapes until it is saved as an SVG or PNG.
+///
+/// Thin lines on a black background are written exactly as `unsvg::Image` writes them, so
+/// programs which only use the original commands draw identical images.
+///
+/// The canvas also remembers the order things were drawn in, as frames which each end with
+/// where the turtle was, so the drawing can be animated.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Canvas {
+    width: u32,
+    height: u32,
+    background: Color,
+    shapes: Vec<Shape>,
+    /// How many of the shapes are lines or arcs rather than fills.
+    segments: usize,
+    /// The frame each shape appears in, parallel to `shapes`.
+    frames: Vec<usize>,
+    cursors: Vec<Cursor>,
+}
+
+/// Formats a number for a path, to 3 decimal places at most.
+pub(crate) fn number(value: f64) -> String {
+    let rounded = format!("{:.3}", value);
+    let rounded = rounded.trim_end_matches('0').trim_end_matches('.');
+    if rounded == "-0" {
+        "0".to_string()
+    } else {
+        rounded.to_string()
+    }
+}
+
+/// Formats a colour as `#rrggbb`.
+pub(crate) fn hex(color: Color) -> String {
+    format!("#{:02x}{:02x}{:02x}", color.red, color.green, color.blue)
+}
+
+/// Formats the attribute for a line's width, which is left out for the default of 1.
+fn stroke_width(width: u32) -> String {
+    if width == 1 {
+        String::new()
+    } else {
+        format!(" stroke-width=\"{}\"", width)
+    }
+}
+
+impl Shape {
+    /// Writes the shape as an SVG path element, without closing the tag.
+    pub(crate) fn svg_element(&self) -> String {
+        match self {
+            Shape::Line {
+                from,
+                to,
+                color,
+                width,
+            } => format!(
+                "<path fill=\"none\" stroke=\"{}\"{} d=\"M {} {} L {} {}\"",
+                hex(*color),
+                stroke_width(*width),
+                from.0,
+                from.1,
+                to.0,
+                to.1
+            ),
+            Shape::Arc { arc, color, width } => {
+                let (x, y) = arc.start_point();
+                format!(
+                    "<path fill=\"none\" stroke=\"{}\"{} d=\"M {} {} {}\"",
+                    hex(*color),
+                    stroke_width(*width),
+                    number(x),
+                    number(y),
+                    arc.svg_path()
+                )
+            }
+            Shape::Polygon {
+                start,
+                edges,
+                color,
+            } => {
+                let mut path = vec![format!("M {} {}", start.0, start.1)];
+                for edge in edges {
+                    path.push(match edge {
+                        Edge::Line((x, y)) => format!("L {} {}", x, y),
+                        Edge::Arc(arc) => arc.svg_path(),
+                    });
+                }
+                format!(
+                    "<path fill=\"{}\" stroke=\"none\" d=\"{} Z\"",
+                    hex(*color),
+                    path.join(" ")
+                )
+            }
+        }
+    }
+}
+
+impl Cursor {
+    /// Writes a turtle shaped arrow, pointing up from the origin, without its transform.
+    pub(crate) fn svg_arrow() -> &'static str {
+        "<path fill=\"#808080\" stroke=\"#ffffff\" d=\"M 0 -8 L 6 6 L 0 3 L -6 6 Z\""
+    }
+
+    /// Returns the SVG transform which moves the arrow to the turtle.
+    pub(crate) fn svg_transform(&self) -> String {
+        format!(
+            "translate({} {}) rotate({})",
+            self.position.0, self.position.1, self.heading
+        )
+    }
+}
+
+impl Canvas {
+    /// Creates an empty canvas with a black background.
+    ///
+    /// # Parameters
+    /// * `width`: The width of the canvas.
+    /// * `height`: The height of the canvas.
+    pub fn new(width: u32, height: u32) -> Self {
+        Canvas {
+            width,
+            height,
+            background: Color::black(),
+            shapes: Vec::new(),
+            segments: 0,
+            frames: Vec::new(),
+            cursors: Vec::new(),
+        }
+    }
+
+    /// Returns the size of the canvas as `(width, height)`.
+    pub fn get_dimensions(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    pub fn background(&self) -> Color {
+        self.background
+    }
+
+    pub fn set_background(&mut self, color: Color) {
+        self.background = color;
+    }
+
+    /// Returns everything drawn so far, in the order it was drawn.
+    pub fn shapes(&self) -> &[Shape] {
+        &self.shapes
+    }
+
+    /// Returns how many lines and arcs have been drawn, which are always the last of those in
+    /// `shapes`, since only fills are put beneath earlier shapes.
+    pub fn segment_count(&self) -> usize {
+        self.segments
+    }
+
+    /// Returns the frame each shape appears in, in the same order as `shapes`.
+    pub fn shape_frames(&self) -> &[usize] {
+        &self.frames
+    }
+
+    /// Returns where the turtle was at the end of each frame.
+    pub fn cursors(&self) -> &[Cursor] {
+        &self.cursors
+    }
+
+    /// Ends the current frame, so anything drawn after it appears in the next one.
+    ///
+    /// # Parameters
+    /// * `cursor`: Where the turtle is at the end of the frame.
+    pub fn end_frame(&mut self, cursor: Cursor) {
+        self.cursors.push(cursor);
+    }
+
+    pub fn draw_line(&mut self, from: (i32, i32), to: (i32, i32), color: Color, width: u32) {
+        self.segments += 1;
+        self.insert(
+            self.shapes.len(),
+            Shape::Line {
+                from,
+                to,
+                color,
+                width,
+            },
+        );
+    }
+
+    pub fn draw_arc(&mut self, arc: Arc, color: Color, width: u32) {
+        self.segments += 1;
+        self.insert(self.shapes.len(), Shape::Arc { arc, color, width });
+    }
+
+    /// Fills a shape, beneath everything drawn since `index` shapes had been drawn.
+    ///
+    /// This puts a fill under the outline which was drawn while its edges were recorded.
+    ///
+    /// # Parameters
+    /// * `index`: How many shapes had been drawn when the shape was started.
+    /// * `start`: Where the first edge starts.
+    /// * `edges`: The edges of the shape, which is closed back to `start`.
+    /// * `color`: The colour to fill it with.
+    pub fn fill_polygon(
+        &mut self,
+        index: usize,
+        start: (i32, i32),
+        edges: Vec<Edge>,
+        color: Color,
+    ) {
+        // Straight edges need at least three corners to enclose anything
+        let curved = edges.iter().any(|edge| matches!(edge, Edge::Arc(_)));
+        if curved || edges.len() >= 2 {
+            let index = index.min(self.shapes.len());
+            self.insert(
+                index,
+                Shape::Polygon {
+                    start,
+                    edges,
+                    color,
+                },
+            );
+        }
+    }
+
+    /// Inserts a shape at `index`, in the current frame.
+    fn insert(&mut self, index: usize, shape: Shape) {
+        self.shapes.insert(index, shape);
+        self.frames.insert(index, self.cursors.len());
+    }
+
+    /// Writes the start of an SVG document the size of the canvas.
+    pub(crate) fn svg_start(&self) -> String {
+        let (w, h) = (self.width, self.height);
+        format!(
+            "<svg width=\"{w}\" height=\"{h}\" viewBox=\"0 0 {w} {h}\" xmlns=\"http://www.w3.org/2000/svg\">\n    <defs/>\n"
+        )
+    }
+
+    /// Writes a path element which covers the canvas with its background.
+    pub(crate) fn svg_background(&self) -> String {
+        let (w, h) = (self.width, self.height);
+        format!(
+            "    <path fill=\"{}\" stroke=\"none\" d=\"M 0 0 L {w} 0 L {w} {h} L 0 {h} Z\"/>\n",
+            hex(self.background)
+        )
+    }
+
+    /// Renders the canvas as an SVG document.
+    pub fn to_svg(&self) -> String {
+        let mut svg = self.svg_start() + &self.svg_background();
+        for shape in &self.shapes {
+            svg.push_str(&format!("    {}/>\n", shape.svg_element()));
+        }
+        svg.push_str("</svg>\n");
+        svg
+    }
+
+    pub fn save_svg<P: AsRef<std::path::Path>>(&self, path: P) -> Result<(), String> {
+        std::fs::write(path, self.to_svg()).map_err(|e| e.to_string())
+    }
+
+    pub fn save_png<P: AsRef<std::path::Path>>(&self, path: P) -> Result<(), String> {
+        self.to_pixmap()?.save_png(path).map_err(|e| e.to_string())
+    }
+
+    /// Rasterizes the canvas, at one pixel per unit.
+    pub fn to_pixmap(&self) -> Result<tiny_skia::Pixmap, String> {
+        rasterize(&self.to_svg())
+    }
+}
+
+/// Rasterizes an SVG document, at one pixel per unit.
+pub fn rasterize(svg: &str) -> Result<tiny_skia::Pixmap, String> {
+    let tree = usvg::Tree::from_str(svg, &usvg::Options::default()).map_err(|e| e.to_string())?;
+    let size = tree.size.to_int_size();
+    let mut pixmap = tiny_skia::Pixmap::new(size.width(), size.height())
+        .ok_or("the image is too large to draw")?;
+    resvg::Tree::from_usvg(&tree).render(tiny_skia::Transform::default(), &mut pixmap.as_mut());
+    Ok(pixmap)
+}
+
+/// Rasterizes an SVG document on top of what is already in a pixmap.
+pub fn render_onto(svg: &str, pixmap: &mut tiny_skia::Pixmap) -> Result<(), String> {
+    let tree = usvg::Tree::from_str(svg, &usvg::Options::default()).map_err(|e| e.to_string())?;
+    resvg::Tree::from_usvg(&tree).render(tiny_skia::Transform::default(), &mut pixmap.as_mut());
+    Ok(())
+}
+
+// ===================== TESTS ============================
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 1. Test fills go beneath the outline drawn around them, and wide lines keep their width
+    #[test]
+    fn test_to_svg() {
+        let red = Color::new_rgb(255, 0, 0);
+        let mut canvas = Canvas::new(10, 10);
+        canvas.set_background(Color::white());
+        canvas.draw_line((0, 0), (5, 0), red, 1);
+        canvas.draw_line((5, 0), (5, 5), red, 3);
+        let edges = vec![Edge::Line((5, 0)), Edge::Line((5, 5))];
+        canvas.fill_polygon(0, (0, 0), edges, Color::black());
+        canvas.fill_polygon(0, (0, 0), vec![Edge::Line((5, 0))], Color::black());
+
+        let svg = canvas.to_svg();
+        let lines: Vec<_> = svg.lines().map(str::trim).collect();
+        assert_eq!(
+            lines[2..6],
+            [
+                "<path fill=\"#ffffff\" stroke=\"none\" d=\"M 0 0 L 10 0 L 10 10 L 0 10 Z\"/>",
+                "<path fill=\"#000000\" stroke=\"none\" d=\"M 0 0 L 5 0 L 5 5 Z\"/>",
+                "<path fill=\"none\" stroke=\"#ff0000\" d=\"M 0 0 L 5 0\"/>",
+                "<path fill=\"none\" stroke=\"#ff0000\" stroke-width=\"3\" d=\"M 5 0 L 5 5\"/>",
+            ]
+        );
+        assert!(canvas.to_pixmap().is_ok());
+    }
+
+    // 2. Test arcs are split into quarter turns, so a whole circle can be drawn
+    #[test]
+    fn test_arc_path() {
+        let circle = Arc {
+            center: (10.0, 10.0),
+            radius: 5.0,
+            start: 0.0,
+            sweep: 360.0,
+        };
+        assert_eq!(
+            circle.svg_path(),
+            "A 5 5 0 0 1 15 10 A 5 5 0 0 1 10 15 A 5 5 0 0 1 5 10 A 5 5 0 0 1 10 5"
+        );
+
+        let back = Arc {
+            sweep: -45.0,
+            ..circle
+        };
+        assert_eq!(back.svg_path(), "A 5 5 0 0 0 6.464 6.464");
+    }
+
+    // 3. Test plain drawings are written exactly as `unsvg::Image` writes them
+    #[test]
+    fn test_matches_unsvg() {
+        let mut image = unsvg::Image::new(200, 100);
+        let mut canvas = Canvas::new(200, 100);
+        let mut position = (20, 80);
+        for (direction, length, color) in [(90, 50, 1), (0, 40, 7), (225, 30, 15)] {
+            let color = unsvg::COLORS[color];
+            let end = image
+                .draw_simple_line(position.0, position.1, direction, length, color)
+                .unwrap();
+            canvas.draw_line(position, end, color, 1);
+            position = end;
+        }
+
+        let path = std::env::temp_dir().join(format!("rslogo_unsvg_{}.svg", std::process::id()));
+        image.save_svg(&path).unwrap();
+        let expected = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(canvas.to_svg(), expected);
+    }
+}
@@ -0,0 +1,440 @@
+use crate::ast::{
+    ColorExpression, Direction, Expression, ExpressionKind, Operator, Query, Statement,
+    StatementKind,
+};
+use crate::lexer::Position;
+use crate::value::Value;
+use std::rc::Rc;
+
+/// A single instruction of the stack machine in `vm`.
+///
+/// Instructions which take values pop them off the stack, with the last value on top.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Instruction {
+    Push(Value),
+    /// Pushes the value of a variable.
+    Load(String),
+    /// Pushes the turtle's answer to a query.
+    Query(Query),
+    /// Pops two operands and pushes the result.
+    Binary(Operator),
+    PenUp,
+    PenDown,
+    /// Pops a distance and moves that far.
+    Move(Direction),
+    Turn,
+    SetHeading,
+    SetX,
+    SetY,
+    /// Pops red, green and blue if `rgb`, or else an index into the palette, and sets the pen
+    /// colour to it.
+    SetPenColor {
+        rgb: bool,
+    },
+    /// Pops a colour like `SetPenColor`, and sets the background to it.
+    SetBackground {
+        rgb: bool,
+    },
+    SetPenSize,
+    /// Pops a radius, then an angle, and moves along an arc.
+    Arc,
+    BeginFill,
+    EndFill,
+    /// Pops a name, and creates a turtle with it.
+    NewTurtle,
+    /// Pops a name, and switches to the turtle with it.
+    Tell,
+    /// Pops a name, and switches to the turtle with it until the matching `AskEnd`.
+    AskStart,
+    /// Switches back to the turtle from before the innermost `ASK`.
+    AskEnd,
+    /// Pops a value, then a name, and sets the variable with that name.
+    Make,
+    /// Pops a number, then a name, and adds the number to the variable with that name.
+    AddAssign,
+    /// Pops a value, and sets the variable with the given name, for a `MAKE` whose name is
+    /// written as a word.
+    Store(String),
+    /// Pops a number, and adds it to the variable with the given name, for an `ADDASSIGN` whose
+    /// name is written as a word.
+    AddTo(String),
+    /// Jumps to an instruction.
+    Jump(usize),
+    /// Pops a boolean, and jumps to an instruction if it is false.
+    JumpUnless(usize),
+    /// Pops a count, and starts a `REPEAT` loop.
+    RepeatStart,
+    /// Starts the next iteration of the innermost `REPEAT`, or ends it and jumps to an
+    /// instruction if it has run enough times.
+    RepeatNext(usize),
+    /// Pops the step if there is one, the end, the start and the name of the variable, and
+    /// starts a `FOR` loop.
+    ForStart {
+        step: bool,
+    },
+    /// Sets the variable of the innermost `FOR` to its next value, or ends it and jumps to an
+    /// instruction if it is past the end.
+    ForNext(usize),
+    /// Pops the names of the procedure's parameters, and defines it.
+    Define(Rc<CompiledProcedure>),
+    /// Pops `arguments` values, and calls the procedure with them.
+    Call {
+        name: String,
+        arguments: usize,
+    },
+}
+
+/// A compiled list of statements, such as a whole program or the body of a procedure.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Chunk {
+    pub code: Vec<Instruction>,
+    /// Where in the program each instruction came from, for errors.
+    pub positions: Vec<Position>,
+    /// Whether each instruction starts a statement or an iteration of a loop, which is where
+    /// a debugger stops.
+    pub stops: Vec<bool>,
+}
+
+/// A compiled `TO ... END` definition.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompiledProcedure {
+    pub name: String,
+    /// How many parameters the procedure has, whose names are on the stack when it is defined.
+    pub params: usize,
+    pub body: Rc<Chunk>,
+}
+
+/// Compiles statements to instructions for `vm::Vm`.
+///
+/// Blocks become jumps within the chunk, and each procedure body becomes a chunk of its own.
+///
+/// # Parameters
+/// * `statements`: The statements from `parser::parse`.
+///
+/// # Returns
+/// The compiled statements.
+pub fn compile(statements: &[Statement]) -> Chunk {
+    let mut chunk = Chunk::default();
+    chunk.statements(statements);
+    chunk
+}
+
+impl Chunk {
+    fn emit(&mut self, instruction: Instruction, position: Position) -> usize {
+        self.code.push(instruction);
+        self.positions.push(position);
+        self.stops.push(false);
+        self.code.len() - 1
+    }
+
+    /// Points the jump at `index` to the next instruction to be emitted.
+    fn patch(&mut self, index: usize) {
+        let target = self.code.len();
+        match &mut self.code[index] {
+            Instruction::Jump(to)
+            | Instruction::JumpUnless(to)
+            | Instruction::RepeatNext(to)
+            | Instruction::ForNext(to) => *to = target,
+            other => unreachable!("cannot patch {:?}", other),
+        }
+    }
+
+    fn statements(&mut self, statements: &[Statement]) {
+        for statement in statements {
+            self.statement(statement);
+        }
+    }
+
+    fn statement(&mut self, statement: &Statement) {
+        // Every statement emits at least one instruction
+        let start = self.code.len();
+        self.statement_code(statement);
+        self.stops[start] = true;
+    }
+
+    fn statement_code(&mut self, statement: &Statement) {
+        let position = statement.position;
+        let instruction = match &statement.kind {
+            StatementKind::PenUp => Instruction::PenUp,
+            StatementKind::PenDown => Instruction::PenDown,
+            StatementKind::Move {
+                direction,
+                distance,
+            } => {
+                self.expression(distance);
+                Instruction::Move(*direction)
+            }
+            StatementKind::Turn(angle) => {
+                self.expression(angle);
+                Instruction::Turn
+            }
+            StatementKind::SetHeading(heading) => {
+                self.expression(heading);
+                Instruction::SetHeading
+            }
+            StatementKind::SetX(x) => {
+                self.expression(x);
+                Instruction::SetX
+            }
+            StatementKind::SetY(y) => {
+                self.expression(y);
+                Instruction::SetY
+            }
+            StatementKind::SetPenColor(color) => Instruction::SetPenColor {
+                rgb: self.color(color),
+            },
+            StatementKind::SetBackground(color) => Instruction::SetBackground {
+                rgb: self.color(color),
+            },
+            StatementKind::SetPenSize(size) => {
+                self.expression(size);
+                Instruction::SetPenSize
+            }
+            StatementKind::Arc { angle, radius } => {
+                self.expression(angle);
+                self.expression(radius);
+                Instruction::Arc
+            }
+            StatementKind::Circle(radius) => {
+                self.emit(Instruction::Push(Value::Number(360)), position);
+                self.expression(radius);
+                Instruction::Arc
+            }
+            StatementKind::BeginFill => Instruction::BeginFill,
+            StatementKind::EndFill => Instruction::EndFill,
+            StatementKind::NewTurtle(name) => {
+                self.expression(name);
+                Instruction::NewTurtle
+            }
+            StatementKind::Tell(name) => {
+                self.expression(name);
+                Instruction::Tell
+            }
+            StatementKind::Ask { turtle, body } => {
+                self.expression(turtle);
+                self.emit(Instruction::AskStart, position);
+                self.statements(body);
+                Instruction::AskEnd
+            }
+            // Names are almost always written as words, which need not go through the stack
+            StatementKind::Make { name, value } => match word(name) {
+                Some(name) => {
+                    self.expression(value);
+                    Instruction::Store(name)
+                }
+                None => {
+                    self.expression(name);
+                    self.expression(value);
+                    Instruction::Make
+                }
+            },
+            StatementKind::AddAssign { name, value } => match word(name) {
+                Some(word) => {
+                    self.expression(value);
+                    // An unknown variable is reported where its name is
+                    self.emit(Instruction::AddTo(word), name.position);
+                    return;
+                }
+                None => {
+                    self.expression(name);
+                    self.expression(value);
+                    Instruction::AddAssign
+                }
+            },
+            StatementKind::If {
+                condition,
+                body,
+                else_body,
+            } => {
+                self.expression(condition);
+                let skip = self.emit(Instruction::JumpUnless(0), position);
+                self.statements(body);
+                if let Some(else_body) = else_body {
+                    let end = self.emit(Instruction::Jump(0), position);
+                    self.patch(skip);
+                    self.statements(else_body);
+                    self.patch(end);
+                } else {
+                    self.patch(skip);
+                }
+                return;
+            }
+            StatementKind::While { condition, body } => {
+                let start = self.code.len();
+                self.expression(condition);
+                let exit = self.emit(Instruction::JumpUnless(0), position);
+                self.statements(body);
+                self.emit(Instruction::Jump(start), position);
+                self.patch(exit);
+                return;
+            }
+            StatementKind::Repeat { count, body } => {
+                self.expression(count);
+                self.emit(Instruction::RepeatStart, position);
+                self.looped(Instruction::RepeatNext(0), body, position);
+                return;
+            }
+            StatementKind::For {
+                variable,
+                start,
+                end,
+                step,
+                body,
+            } => {
+                self.expression(variable);
+                self.expression(start);
+                self.expression(end);
+                if let Some(step) = step {
+                    self.expression(step);
+                }
+                self.emit(
+                    Instruction::ForStart {
+                        step: step.is_some(),
+                    },
+                    position,
+                );
+                self.looped(Instruction::ForNext(0), body, position);
+                return;
+            }
+            StatementKind::Procedure(procedure) => {
+                for param in &procedure.params {
+                    self.expression(param);
+                }
+                Instruction::Define(Rc::new(CompiledProcedure {
+                    name: procedure.name.clone(),
+                    params: procedure.params.len(),
+                    body: Rc::new(compile(&procedure.body)),
+                }))
+            }
+            StatementKind::Call { name, arguments } => {
+                for argument in arguments {
+                    self.expression(argument);
+                }
+                Instruction::Call {
+                    name: name.clone(),
+                    arguments: arguments.len(),
+                }
+            }
+        };
+        self.emit(instruction, position);
+    }
+
+    /// Emits the expressions of a colour.
+    ///
+    /// # Returns
+    /// Whether the colour is given as red, green and blue.
+    fn color(&mut self, color: &ColorExpression) -> bool {
+        match color {
+            ColorExpression::Palette(index) => {
+                self.expression(index);
+                false
+            }
+            ColorExpression::Rgb(components) => {
+                for component in components.iter() {
+                    self.expression(component);
+                }
+                true
+            }
+        }
+    }
+
+    /// Emits a loop which runs `next` before each iteration of `body`.
+    fn looped(&mut self, next: Instruction, body: &[Statement], position: Position) {
+        let start = self.emit(next, position);
+        self.stops[start] = true;
+        self.statements(body);
+        self.emit(Instruction::Jump(start), position);
+        self.patch(start);
+    }
+
+    fn expression(&mut self, expression: &Expression) {
+        let instruction = match &expression.kind {
+            ExpressionKind::Literal(literal) => Instruction::Push(Value::from_word(literal)),
+            ExpressionKind::Variable(name) => Instruction::Load(name.clone()),
+            ExpressionKind::Query(query) => Instruction::Query(*query),
+            ExpressionKind::Binary {
+                operator,
+                left,
+                right,
+            } => {
+                self.expression(left);
+                self.expression(right);
+                Instruction::Binary(*operator)
+            }
+        };
+        self.emit(instruction, expression.position);
+    }
+}
+
+/// Returns the name an expression gives, if it is written as a word rather than computed.
+fn word(expression: &Expression) -> Option<String> {
+    match &expression.kind {
+        ExpressionKind::Literal(literal) => Some(Value::from_word(literal).to_string()),
+        _ => None,
+    }
+}
+
+// ===================== TESTS ============================
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::tokenize;
+    use crate::parser::parse;
+
+    // 1. Test a WHILE loop compiles to a condition, a body and a jump back
+    #[test]
+    fn test_compile_while() {
+        let program = "WHILE LT :i \"3 [\n  FORWARD \"1\n]\n";
+        let chunk = compile(&parse(&tokenize(program)).unwrap());
+        assert_eq!(
+            chunk.code,
+            vec![
+                Instruction::Load("i".to_string()),
+                Instruction::Push(Value::Number(3)),
+                Instruction::Binary(Operator::Lt),
+                Instruction::JumpUnless(7),
+                Instruction::Push(Value::Number(1)),
+                Instruction::Move(Direction::Forward),
+                Instruction::Jump(0),
+            ]
+        );
+        assert_eq!(
+            chunk.stops,
+            vec![true, false, false, false, true, false, false]
+        );
+        assert_eq!(
+            chunk.positions[4],
+            Position {
+                line: 2,
+                column: 11
+            }
+        );
+    }
+
+    // 2. Test names written as words are stored directly, while computed names go on the stack
+    #[test]
+    fn test_compile_names() {
+        let program = "MAKE \"x \"1\nADDASSIGN \"x \"2\nMAKE :x \"3\n";
+        let chunk = compile(&parse(&tokenize(program)).unwrap());
+        assert_eq!(
+            chunk.code,
+            vec![
+                Instruction::Push(Value::Number(1)),
+                Instruction::Store("x".to_string()),
+                Instruction::Push(Value::Number(2)),
+                Instruction::AddTo("x".to_string()),
+                Instruction::Load("x".to_string()),
+                Instruction::Push(Value::Number(3)),
+                Instruction::Make,
+            ]
+        );
+        // An unknown variable is reported at its name
+        assert_eq!(
+            chunk.positions[3],
+            Position {
+                line: 2,
+                column: 11
+            }
+        );
+    }
+}
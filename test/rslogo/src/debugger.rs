@@ -0,0 +1,233 @@
+use crate::lexer::Position;
+use crate::turtle::PenColor;
+use crate::vm::{Observer, Vm};
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, Write};
+
+/// The commands the debugger understands.
+const HELP: &str = "commands:
+  step, s          run to the next statement, going into procedure calls
+  next, n          run to the next statement, stepping over procedure calls
+  continue, c      run until a breakpoint is reached
+  break, b <line>  pause whenever a statement on a line runs
+  print, p :name   show the value of a variable
+  turtle, t        show which turtle is moving, where it is and the state of its pen
+  quit, q          stop the program";
+
+/// How the program runs until the debugger next pauses it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    /// Pause at the next statement.
+    Step,
+    /// Pause at the next statement which is not in a procedure called from `depth`.
+    Next { depth: usize },
+    /// Pause only at breakpoints.
+    Continue,
+}
+
+/// A debugger which pauses a program and reads commands to inspect and resume it.
+///
+/// It pauses before the first statement. Commands are read from `input` and answered on
+/// `output`, and if the input runs out the program runs to the end.
+pub struct Debugger<'s, R, W> {
+    lines: Vec<&'s str>,
+    input: R,
+    output: W,
+    mode: Mode,
+    breakpoints: BTreeSet<usize>,
+    /// The line of the last statement, so a breakpoint is not hit again by the other
+    /// statements on its line.
+    last_line: usize,
+}
+
+impl<'s, R: BufRead, W: Write> Debugger<'s, R, W> {
+    /// Creates a debugger for a program.
+    ///
+    /// # Parameters
+    /// * `source`: The source of the program, to show its lines.
+    /// * `input`: Where to read commands from.
+    /// * `output`: Where to write answers to.
+    pub fn new(source: &'s str, input: R, output: W) -> Self {
+        Debugger {
+            lines: source.lines().collect(),
+            input,
+            output,
+            mode: Mode::Step,
+            breakpoints: BTreeSet::new(),
+            last_line: 0,
+        }
+    }
+
+    /// Shows where the program is, then reads commands until one resumes it.
+    ///
+    /// # Returns
+    /// Whether to carry on running the program.
+    fn pause(&mut self, vm: &Vm, position: Position) -> io::Result<bool> {
+        writeln!(self.output, "stopped at line {}", position.line)?;
+        self.show_line(position.line)?;
+        for frame in vm.call_stack().iter().rev() {
+            writeln!(
+                self.output,
+                "    in {}, called from line {}",
+                frame.procedure, frame.position.line
+            )?;
+            self.show_line(frame.position.line)?;
+        }
+
+        loop {
+            write!(self.output, "(debug) ")?;
+            self.output.flush()?;
+            let mut command = String::new();
+            if self.input.read_line(&mut command)? == 0 {
+                writeln!(self.output)?;
+                self.mode = Mode::Continue;
+                self.breakpoints.clear();
+                return Ok(true);
+            }
+
+            let mut words = command.split_whitespace();
+            match (words.next(), words.next()) {
+                (None, _) => {}
+                (Some("step" | "s"), None) => {
+                    self.mode = Mode::Step;
+                    return Ok(true);
+                }
+                (Some("next" | "n"), None) => {
+                    self.mode = Mode::Next { depth: vm.depth() };
+                    return Ok(true);
+                }
+                (Some("continue" | "c"), None) => {
+                    self.mode = Mode::Continue;
+                    return Ok(true);
+                }
+                (Some("break" | "b"), Some(line)) => match line.parse::<usize>() {
+                    Ok(line) if line > 0 => {
+                        self.breakpoints.insert(line);
+                        writeln!(self.output, "breakpoint at line {}", line)?;
+                    }
+                    _ => writeln!(self.output, "{} is not a line number", line)?,
+                },
+                (Some("print" | "p"), Some(name)) => {
+                    let name = name.trim_start_matches(':');
+                    match vm.variable(name) {
+                        Some(value) => writeln!(self.output, ":{} = {}", name, value)?,
+                        None => writeln!(self.output, "variable :{} is not defined", name)?,
+                    }
+                }
+                (Some("turtle" | "t"), None) => self.show_turtle(vm)?,
+                (Some("quit" | "q"), None) => return Ok(false),
+                (Some("help" | "h"), _) => writeln!(self.output, "{}", HELP)?,
+                _ => writeln!(self.output, "unknown command, type help for a list")?,
+            }
+        }
+    }
+
+    fn show_line(&mut self, line: usize) -> io::Result<()> {
+        let text = self.lines.get(line.wrapping_sub(1)).unwrap_or(&"");
+        writeln!(self.output, "{:>5} | {}", line, text)
+    }
+
+    fn show_turtle(&mut self, vm: &Vm) -> io::Result<()> {
+        let turtle = vm.turtle();
+        let color = match turtle.color {
+            PenColor::Palette(index) => index.to_string(),
+            PenColor::Rgb(red, green, blue) => format!("{} {} {}", red, green, blue),
+        };
+        writeln!(
+            self.output,
+            "turtle {}: x {}, y {}, heading {}, pen {}, colour {}, pen size {}{}",
+            vm.turtle_name(),
+            turtle.x,
+            turtle.y,
+            turtle.heading,
+            if turtle.pen_down { "down" } else { "up" },
+            color,
+            turtle.pen_size,
+            if turtle.fill.is_some() {
+                ", filling"
+            } else {
+                ""
+            }
+        )
+    }
+}
+
+impl<R: BufRead, W: Write> Observer for Debugger<'_, R, W> {
+    fn stop(&mut self, vm: &Vm, position: Position) -> bool {
+        let pause = match self.mode {
+            Mode::Step => true,
+            Mode::Next { depth } => vm.depth() <= depth,
+            Mode::Continue => {
+                position.line != self.last_line && self.breakpoints.contains(&position.line)
+            }
+        };
+        self.last_line = position.line;
+
+        // If the terminal has gone, there is no one to ask, so the program carries on
+        !pause || self.pause(vm, position).unwrap_or(true)
+    }
+}
+
+// ===================== TESTS ============================
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canvas::Canvas;
+    use crate::compiler::compile;
+    use crate::lexer::tokenize;
+    use crate::parser::parse;
+    use std::rc::Rc;
+
+    /// Runs a program under the debugger, returning what the debugger wrote.
+    fn debug(program: &str, commands: &str) -> String {
+        let mut canvas = Canvas::new(100, 100);
+        let mut vm = Vm::new(&mut canvas);
+        let mut output = Vec::new();
+        let mut debugger = Debugger::new(program, commands.as_bytes(), &mut output);
+        let chunk = Rc::new(compile(&parse(&tokenize(program)).unwrap()));
+        vm.run_observed(&chunk, &mut debugger).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    // 1. Test breakpoints, stepping into and over calls, and the call stack
+    #[test]
+    fn test_debugger() {
+        let program = "TO Line \"n
+PENDOWN
+FORWARD :n
+END
+MAKE \"size \"5
+Line :size
+Line \"7
+MAKE \"size \"9
+";
+        let output = debug(program, "b 3\nc\np :n\nt\nc\nn\np :size\np :n\nq\n");
+        let expected = [
+            "stopped at line 1",
+            "breakpoint at line 3",
+            "stopped at line 3",
+            "    3 | FORWARD :n",
+            "    in Line, called from line 6",
+            "    6 | Line :size",
+            ":n = 5",
+            "turtle main: x 50, y 50, heading 0, pen down, colour 7, pen size 1",
+            "stopped at line 3",
+            "    in Line, called from line 7",
+            // Stepping over the end of the procedure stops back in the caller
+            "stopped at line 8",
+            ":size = 5",
+            "variable :n is not defined",
+        ];
+        let mut rest = output.as_str();
+        for line in expected {
+            let found = rest
+                .find(line)
+                .unwrap_or_else(|| panic!("{:?} not in {:?}", line, rest));
+            rest = &rest[found + line.len()..];
+        }
+
+        // Without any commands, the program runs to the end
+        let output = debug(program, "");
+        assert_eq!(output.matches("stopped").count(), 1);
+    }
+}
@@ -0,0 +1,367 @@
+use crate::lexer::Position;
+use std::fmt;
+
+/// Everything that can go wrong while parsing or running a program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// A command was given more arguments than it takes.
+    TooManyArguments {
+        command: String,
+        expected: usize,
+        given: usize,
+    },
+    /// A command was given fewer arguments than it takes.
+    NotEnoughArguments {
+        command: String,
+        expected: usize,
+        given: usize,
+    },
+    /// A value was used as the wrong type, such as `FORWARD "hello`.
+    WrongType {
+        expected: &'static str,
+        found: String,
+    },
+    UnknownVariable(String),
+    /// A `[` which is never closed with `]`.
+    UnclosedBlock {
+        command: String,
+    },
+    /// A call to a procedure, or command, which does not exist.
+    UnknownProcedure(String),
+    /// A command such as `IF` or `REPEAT` without a `[` starting its block.
+    MissingBracket {
+        command: String,
+        found: String,
+    },
+    /// A `]` without a `[` before it.
+    UnmatchedBracket,
+    /// A `TO` without an `END`.
+    MissingEnd {
+        procedure: String,
+    },
+    /// An `END` without a `TO`.
+    UnmatchedEnd,
+    /// A token where a command was expected.
+    ExpectedCommand {
+        found: String,
+    },
+    /// A token where a value was expected.
+    ExpectedValue {
+        found: String,
+    },
+    /// A `TO` inside another procedure, or without a name.
+    InvalidProcedure(String),
+    DivisionByZero,
+    /// Arithmetic whose result does not fit in a number.
+    Overflow,
+    InvalidColor(i32),
+    /// A `FOR` loop with a step of 0, which would never end.
+    ZeroStep,
+    /// Procedures called each other more deeply than allowed, usually by recursing forever.
+    TooDeep {
+        procedure: String,
+        max_depth: usize,
+    },
+    /// The image could not be drawn on.
+    Draw(String),
+    /// A red, green or blue component of a colour outside 0 to 255.
+    InvalidRgb(i32),
+    InvalidPenSize(i32),
+    EndFillWithoutBegin,
+    InvalidRadius(i32),
+    /// `NEWTURTLE` with the name of a turtle which already exists.
+    TurtleExists(String),
+    /// `TELL` or `ASK` with a name no turtle has.
+    UnknownTurtle(String),
+}
+
+/// An error, and where in the program it happened.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogoError {
+    pub kind: ErrorKind,
+    pub position: Position,
+}
+
+impl LogoError {
+    pub fn new(kind: ErrorKind, position: Position) -> Self {
+        LogoError { kind, position }
+    }
+
+    /// Returns the stable code of the error, such as `E0001`.
+    pub fn code(&self) -> &'static str {
+        match self.kind {
+            ErrorKind::TooManyArguments { .. } => "E0001",
+            ErrorKind::NotEnoughArguments { .. } => "E0002",
+            ErrorKind::WrongType { .. } => "E0003",
+            ErrorKind::UnknownVariable(_) => "E0004",
+            ErrorKind::UnclosedBlock { .. } => "E0005",
+            ErrorKind::UnknownProcedure(_) => "E0006",
+            ErrorKind::MissingBracket { .. } => "E0007",
+            ErrorKind::UnmatchedBracket => "E0008",
+            ErrorKind::MissingEnd { .. } => "E0009",
+            ErrorKind::UnmatchedEnd => "E0010",
+            ErrorKind::ExpectedCommand { .. } => "E0011",
+            ErrorKind::ExpectedValue { .. } => "E0012",
+            ErrorKind::InvalidProcedure(_) => "E0013",
+            ErrorKind::DivisionByZero => "E0014",
+            ErrorKind::InvalidColor(_) => "E0015",
+            ErrorKind::Draw(_) => "E0016",
+            ErrorKind::Overflow => "E0017",
+            ErrorKind::TooDeep { .. } => "E0018",
+            ErrorKind::ZeroStep => "E0019",
+            ErrorKind::InvalidPenSize(_) => "E0020",
+            ErrorKind::EndFillWithoutBegin => "E0021",
+            ErrorKind::InvalidRadius(_) => "E0022",
+            ErrorKind::TurtleExists(_) => "E0023",
+            ErrorKind::UnknownTurtle(_) => "E0024",
+            ErrorKind::InvalidRgb(_) => "E0025",
+        }
+    }
+
+    /// Suggests how to fix the error.
+    pub fn hint(&self) -> Option<String> {
+        match &self.kind {
+            ErrorKind::TooManyArguments {
+                command, expected, ..
+            } => Some(format!(
+                "{} takes {} argument(s); each command must be on its own line",
+                command, expected
+            )),
+            ErrorKind::NotEnoughArguments {
+                command,
+                expected,
+                given,
+            } => Some(format!(
+                "add {} more argument(s) after {}",
+                expected.saturating_sub(*given),
+                command
+            )),
+            ErrorKind::WrongType { expected, .. } => Some(if *expected == "a number" {
+                "numbers are written like \"10, or come from a query such as XCOR".to_string()
+            } else {
+                "booleans are written \"TRUE or \"FALSE, or come from EQ, LT, AND and so on"
+                    .to_string()
+            }),
+            ErrorKind::UnknownVariable(name) => Some(format!(
+                "create the variable first with MAKE \"{} ...",
+                name
+            )),
+            ErrorKind::UnclosedBlock { .. } => {
+                Some("add a line containing ] after the last line of the block".to_string())
+            }
+            ErrorKind::UnknownProcedure(name) => Some(format!(
+                "define it with TO {} ... END before calling it",
+                name
+            )),
+            ErrorKind::MissingBracket { command, .. } => Some(format!(
+                "end the {} line with [, then close the block with ] on a later line",
+                command
+            )),
+            ErrorKind::UnmatchedBracket => Some(
+                "remove it, or open a block with [ after an IF, WHILE, REPEAT or FOR".to_string(),
+            ),
+            ErrorKind::MissingEnd { .. } => {
+                Some("add END after the last line of the procedure".to_string())
+            }
+            ErrorKind::UnmatchedEnd => {
+                Some("END only closes a procedure started with TO".to_string())
+            }
+            ErrorKind::ExpectedCommand { .. } => None,
+            ErrorKind::ExpectedValue { .. } => {
+                Some("values are written like \"10, :name, XCOR, or + \"1 \"2".to_string())
+            }
+            ErrorKind::InvalidProcedure(_) => None,
+            ErrorKind::DivisionByZero => {
+                Some("check the divisor is not 0 before dividing".to_string())
+            }
+            ErrorKind::Overflow => Some(format!(
+                "numbers must be between {} and {}",
+                i32::MIN,
+                i32::MAX
+            )),
+            ErrorKind::InvalidColor(_) => Some("colours are numbered 0 to 15".to_string()),
+            ErrorKind::ZeroStep => Some(
+                "leave the step out to count by 1 towards the end, or -1 if it is lower"
+                    .to_string(),
+            ),
+            ErrorKind::TooDeep { procedure, .. } => Some(format!(
+                "check {} stops calling itself, or allow deeper calls with --max-depth",
+                procedure
+            )),
+            ErrorKind::Draw(_) => None,
+            ErrorKind::InvalidRgb(_) => {
+                Some("give the red, green and blue of the colour, each from 0 to 255".to_string())
+            }
+            ErrorKind::InvalidPenSize(_) => {
+                Some("the pen size is the width of a line, from 1 up".to_string())
+            }
+            ErrorKind::EndFillWithoutBegin => {
+                Some("use BEGINFILL where the filled shape starts".to_string())
+            }
+            ErrorKind::InvalidRadius(_) => Some(
+                "a negative angle turns the other way, so the radius is never negative".to_string(),
+            ),
+            ErrorKind::TurtleExists(name) => {
+                Some(format!("use TELL \"{} to draw with the turtle", name))
+            }
+            ErrorKind::UnknownTurtle(name) => {
+                Some(format!("create it first with NEWTURTLE \"{}", name))
+            }
+        }
+    }
+
+    /// Renders the error with the line it happened on, a caret under the offending token and
+    /// a hint.
+    ///
+    /// # Parameters
+    /// * `path`: The path of the program, to show where the error is.
+    /// * `source`: The source of the program.
+    ///
+    /// # Returns
+    /// The rendered diagnostic, over several lines.
+    pub fn render(&self, path: &str, source: &str) -> String {
+        let Position { line, column } = self.position;
+        let mut rendered = format!(
+            "error[{}]: {}\n --> {}:{}:{}\n",
+            self.code(),
+            self,
+            path,
+            line,
+            column
+        );
+
+        if let Some(text) = source.lines().nth(line.saturating_sub(1)) {
+            let gutter = " ".repeat(line.to_string().len());
+            let chars: Vec<char> = text.chars().collect();
+            let start = column.saturating_sub(1).min(chars.len());
+            let width = chars[start..]
+                .iter()
+                .take_while(|c| !c.is_whitespace() && !"[]".contains(**c))
+                .count()
+                .max(1);
+
+            // Tabs are kept, so the caret still lines up under them
+            let indent: String = chars[..start]
+                .iter()
+                .map(|c| if *c == '\t' { '\t' } else { ' ' })
+                .collect();
+            rendered.push_str(&format!("{} |\n", gutter));
+            rendered.push_str(&format!("{} | {}\n", line, text));
+            rendered.push_str(&format!("{} | {}{}\n", gutter, indent, "^".repeat(width)));
+        }
+
+        if let Some(hint) = self.hint() {
+            rendered.push_str(&format!(" = hint: {}\n", hint));
+        }
+        rendered
+    }
+}
+
+impl fmt::Display for LogoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            ErrorKind::TooManyArguments {
+                command,
+                expected,
+                given,
+            }
+            | ErrorKind::NotEnoughArguments {
+                command,
+                expected,
+                given,
+            } => write!(
+                f,
+                "{} expects {} argument(s) but was given {}",
+                command, expected, given
+            ),
+            ErrorKind::WrongType { expected, found } => {
+                write!(f, "expected {} but found \"{}\"", expected, found)
+            }
+            ErrorKind::UnknownVariable(name) => write!(f, "variable :{} is not defined", name),
+            ErrorKind::UnclosedBlock { command } => {
+                write!(f, "the {} block is never closed with ]", command)
+            }
+            ErrorKind::UnknownProcedure(name) => {
+                write!(f, "no command or procedure is called {}", name)
+            }
+            ErrorKind::MissingBracket { command, found } => write!(
+                f,
+                "expected [ to start the {} block but found {}",
+                command, found
+            ),
+            ErrorKind::UnmatchedBracket => write!(f, "found ] without a matching ["),
+            ErrorKind::MissingEnd { procedure } => {
+                write!(f, "procedure {} is never closed with END", procedure)
+            }
+            ErrorKind::UnmatchedEnd => write!(f, "found END without a matching TO"),
+            ErrorKind::ExpectedCommand { found } => {
+                write!(f, "expected a command but found {}", found)
+            }
+            ErrorKind::ExpectedValue { found } => write!(f, "expected a value but found {}", found),
+            ErrorKind::InvalidProcedure(message) => write!(f, "{}", message),
+            ErrorKind::DivisionByZero => write!(f, "division by zero"),
+            ErrorKind::Overflow => write!(f, "the result is too large to be a number"),
+            ErrorKind::InvalidColor(color) => {
+                write!(f, "colour must be between 0 and 15, not {}", color)
+            }
+            ErrorKind::ZeroStep => write!(f, "the step of a FOR loop cannot be 0"),
+            ErrorKind::TooDeep {
+                procedure,
+                max_depth,
+            } => write!(
+                f,
+                "calling {} would nest more than {} procedure calls",
+                procedure, max_depth
+            ),
+            ErrorKind::Draw(message) => write!(f, "could not draw: {}", message),
+            ErrorKind::InvalidRgb(value) => write!(
+                f,
+                "colour components must be between 0 and 255, not {}",
+                value
+            ),
+            ErrorKind::InvalidPenSize(size) => {
+                write!(f, "pen size must be at least 1, not {}", size)
+            }
+            ErrorKind::EndFillWithoutBegin => write!(f, "found ENDFILL without a BEGINFILL"),
+            ErrorKind::InvalidRadius(radius) => {
+                write!(f, "radius must not be negative, not {}", radius)
+            }
+            ErrorKind::TurtleExists(name) => write!(f, "there is already a turtle called {}", name),
+            ErrorKind::UnknownTurtle(name) => write!(f, "there is no turtle called {}", name),
+        }
+    }
+}
+
+// ===================== TESTS ============================
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 1. Test rendering the offending line with a caret under the token and a hint
+    #[test]
+    fn test_render() {
+        let error = LogoError::new(
+            ErrorKind::UnknownVariable("size".to_string()),
+            Position { line: 2, column: 9 },
+        );
+        let rendered = error.render("box.lg", "PENDOWN\nFORWARD :size\n");
+        assert_eq!(
+            rendered,
+            "error[E0004]: variable :size is not defined\n \
+             --> box.lg:2:9\n  \
+             |\n\
+             2 | FORWARD :size\n  \
+             |         ^^^^^\n \
+             = hint: create the variable first with MAKE \"size ...\n"
+        );
+    }
+
+    // 2. Test codes stay with their errors as new errors are added
+    #[test]
+    fn test_codes() {
+        let code = |kind| LogoError::new(kind, Position { line: 1, column: 1 }).code();
+        assert_eq!(code(ErrorKind::Draw("full".to_string())), "E0016");
+        assert_eq!(code(ErrorKind::UnknownTurtle("a".to_string())), "E0024");
+        assert_eq!(code(ErrorKind::InvalidRgb(256)), "E0025");
+    }
+}
@@ -0,0 +1,113 @@
+use std::fmt;
+
+/// Where a token starts in the program, counting lines and columns from 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TokenKind {
+    /// A bare word, such as a command, query, operator or procedure name.
+    Word(String),
+    /// A quoted word such as `"50`, without its quote.
+    Literal(String),
+    /// A variable such as `:size`, without its colon.
+    Variable(String),
+    OpenBracket,
+    CloseBracket,
+    /// The end of a line, which also ends the statement on it.
+    Newline,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub position: Position,
+}
+
+impl fmt::Display for TokenKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TokenKind::Word(word) => write!(f, "{}", word),
+            TokenKind::Literal(literal) => write!(f, "\"{}", literal),
+            TokenKind::Variable(name) => write!(f, ":{}", name),
+            TokenKind::OpenBracket => write!(f, "["),
+            TokenKind::CloseBracket => write!(f, "]"),
+            TokenKind::Newline => write!(f, "the end of the line"),
+        }
+    }
+}
+
+/// Splits a program into tokens, dropping whitespace and `//` comments.
+///
+/// Brackets are always tokens of their own, so `[FORWARD "5]` is four tokens. Every line,
+/// including the last, ends with a `Newline` token.
+///
+/// # Parameters
+/// * `source`: The source of the program.
+///
+/// # Returns
+/// The tokens of the program, in order.
+pub fn tokenize(source: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+
+    for (line_index, line) in source.lines().enumerate() {
+        let chars: Vec<char> = line.chars().collect();
+        let mut index = 0;
+
+        while index < chars.len() {
+            let position = Position {
+                line: line_index + 1,
+                column: index + 1,
+            };
+            let c = chars[index];
+
+            if c.is_whitespace() {
+                index += 1;
+                continue;
+            }
+            if c == '/' && chars.get(index + 1) == Some(&'/') {
+                break;
+            }
+            if c == '[' || c == ']' {
+                let kind = if c == '[' {
+                    TokenKind::OpenBracket
+                } else {
+                    TokenKind::CloseBracket
+                };
+                tokens.push(Token { kind, position });
+                index += 1;
+                continue;
+            }
+
+            let start = index;
+            while index < chars.len()
+                && !chars[index].is_whitespace()
+                && !"[]".contains(chars[index])
+            {
+                index += 1;
+            }
+            let word: String = chars[start..index].iter().collect();
+            let kind = if let Some(literal) = word.strip_prefix('"') {
+                TokenKind::Literal(literal.to_string())
+            } else if let Some(name) = word.strip_prefix(':') {
+                TokenKind::Variable(name.to_string())
+            } else {
+                TokenKind::Word(word)
+            };
+            tokens.push(Token { kind, position });
+        }
+
+        tokens.push(Token {
+            kind: TokenKind::Newline,
+            position: Position {
+                line: line_index + 1,
+                column: chars.len() + 1,
+            },
+        });
+    }
+
+    tokens
+}
@@ -0,0 +1,16 @@
+//! Parses Logo programs, compiles them to bytecode, and runs them to draw images.
+
+pub mod animation;
+pub mod ast;
+pub mod canvas;
+pub mod compiler;
+pub mod debugger;
+pub mod error;
+pub mod lexer;
+pub mod parser;
+pub mod plotter;
+pub mod repl;
+pub mod trace;
+pub mod turtle;
+pub mod value;
+pub mod vm;
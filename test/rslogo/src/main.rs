@@ -1,13 +1,22 @@
 use clap::Parser;
-use unsvg::Image;
+use rslogo::canvas::Canvas;
+use rslogo::debugger::Debugger;
+use rslogo::error::LogoError;
+use rslogo::plotter::{self, Bed};
+use rslogo::repl::{Entry, Repl};
+use rslogo::trace::Trace;
+use rslogo::vm::{self, Vm};
+use rslogo::{animation, compiler, lexer, parser};
+use std::io::Write;
+use std::rc::Rc;
 
 /// A simple program to parse four arguments using clap.
 #[derive(Parser)]
 struct Args {
-    /// Path to a file
+    /// Path to a file, which with --repl is run before the first command if it exists
     file_path: std::path::PathBuf,
 
-    /// Path to an svg or png image
+    /// Path to an svg, png or gif image, or gcode or hpgl for a pen plotter
     image_path: std::path::PathBuf,
 
     /// Height
@@ -15,34 +24,212 @@ struct Args {
 
     /// Width
     width: u32,
+
+    /// How deeply procedures can call each other, including recursively
+    #[arg(long, default_value_t = vm::DEFAULT_MAX_DEPTH)]
+    max_depth: usize,
+
+    /// Animate the drawing one segment at a time, when saving an svg (a gif is always animated)
+    #[arg(long)]
+    animate: bool,
+
+    /// The width of the plotter's bed in millimetres, which gcode and hpgl are scaled to fit
+    #[arg(long, default_value_t = plotter::DEFAULT_BED_WIDTH)]
+    bed_width: f64,
+
+    /// The height of the plotter's bed in millimetres, which gcode and hpgl are scaled to fit
+    #[arg(long, default_value_t = plotter::DEFAULT_BED_HEIGHT)]
+    bed_height: f64,
+
+    /// Read commands one at a time from standard input, saving the image after each one
+    #[arg(long)]
+    repl: bool,
+
+    /// Pause before the first statement, and read debugger commands from standard input
+    #[arg(long, conflicts_with = "repl")]
+    debug: bool,
+
+    /// Write what the program drew and how it changed the turtle to a JSON file
+    #[arg(long, value_name = "PATH", conflicts_with = "repl")]
+    trace: Option<std::path::PathBuf>,
+
+    /// How many segments to draw per second when animating
+    #[arg(long, default_value_t = animation::DEFAULT_SPEED, value_parser = clap::value_parser!(u32).range(1..))]
+    speed: u32,
 }
 
 fn main() -> Result<(), ()> {
     let args: Args = Args::parse();
 
-    // Access the parsed arguments
-    let file_path = args.file_path;
-    let image_path = args.image_path;
-    let height = args.height;
-    let width = args.width;
+    // A session can start without a program, so a missing file is only an error without one
+    if args.repl && !args.file_path.exists() {
+        return repl(&args, "");
+    }
 
-    let image = Image::new(width, height);
+    let program = match std::fs::read_to_string(&args.file_path) {
+        Ok(program) => program,
+        Err(e) => {
+            eprintln!("Error reading {}: {e}", args.file_path.display());
+            return Err(());
+        }
+    };
+
+    if args.repl {
+        repl(&args, &program)
+    } else {
+        draw(&args, &program)
+    }
+}
+
+/// Runs the program and saves the image it draws, printing any errors.
+///
+/// # Parameters
+/// * `args`: The command line arguments.
+/// * `program`: The source of the program.
+fn draw(args: &Args, program: &str) -> Result<(), ()> {
+    let mut image = Canvas::new(args.width, args.height);
+
+    let tokens = lexer::tokenize(program);
+    let result = parser::parse(&tokens).and_then(|statements| {
+        let chunk = Rc::new(compiler::compile(&statements));
+        let mut vm = Vm::new(&mut image).with_max_depth(args.max_depth);
+        let trace = args.trace.as_ref().map(|_| Trace::new(&vm));
+        let debugger = args
+            .debug
+            .then(|| Debugger::new(program, std::io::stdin().lock(), std::io::stdout()));
+        let mut observer = (trace, debugger);
+        let result = vm.run_observed(&chunk, &mut observer);
+
+        // The trace is written even if the program fails, to show how far it got
+        if let (Some(trace), Some(path)) = (observer.0, &args.trace) {
+            if let Err(e) = std::fs::write(path, trace.finish(&vm)) {
+                eprintln!("Error saving trace: {e}");
+            }
+        }
+        result.map_err(|e| vec![e])
+    });
+    if let Err(errors) = result {
+        report(&args.file_path.display().to_string(), program, &errors);
+        return Err(());
+    }
+
+    save(args, &image)
+}
+
+/// Reads commands from standard input and runs them one at a time, saving the image after each.
+///
+/// # Parameters
+/// * `args`: The command line arguments.
+/// * `program`: The source of a program to run before the first command.
+fn repl(args: &Args, program: &str) -> Result<(), ()> {
+    let path = args.file_path.display().to_string();
+    let mut image = Canvas::new(args.width, args.height);
+    let mut repl = Repl::new(Vm::new(&mut image).with_max_depth(args.max_depth));
+
+    let mut entry = repl.line(program);
+    if let Entry::Incomplete = entry {
+        entry = repl.finish();
+    }
+    let stdin = std::io::stdin();
+    loop {
+        if let Entry::Ran(result) = entry {
+            if let Err(errors) = result {
+                report(&path, repl.source(), &errors);
+            }
+            // Errors saving are printed, but the session carries on
+            let _ = save(args, repl.canvas());
+        }
 
+        print!("{}", if repl.is_pending() { "> " } else { "? " });
+        let _ = std::io::stdout().flush();
+        let mut line = String::new();
+        entry = match stdin.read_line(&mut line) {
+            Ok(0) => break,
+            Ok(_) => repl.line(&line),
+            Err(e) => {
+                eprintln!("Error reading input: {e}");
+                return Err(());
+            }
+        };
+    }
+
+    println!();
+    if let Entry::Ran(Err(errors)) = repl.finish() {
+        report(&path, repl.source(), &errors);
+        let _ = save(args, repl.canvas());
+    }
+    Ok(())
+}
+
+/// Prints errors, with the lines of the program they are on.
+///
+/// # Parameters
+/// * `path`: The path to show for the program.
+/// * `program`: The source of the program.
+/// * `errors`: The errors to print.
+fn report(path: &str, program: &str, errors: &[LogoError]) {
+    for e in errors {
+        eprintln!("{}", e.render(path, program));
+    }
+    eprintln!("{} error(s) in {}", errors.len(), path);
+}
+
+/// Saves the image in the format given by the extension of its path, printing any error.
+///
+/// # Parameters
+/// * `args`: The command line arguments.
+/// * `image`: The canvas to save.
+fn save(args: &Args, image: &Canvas) -> Result<(), ()> {
+    let image_path = &args.image_path;
     match image_path.extension().and_then(|s| s.to_str()) {
+        Some("svg") if args.animate => {
+            let svg = animation::to_animated_svg(image, args.speed);
+            if let Err(e) = std::fs::write(image_path, svg) {
+                eprintln!("Error saving svg: {e}");
+                return Err(());
+            }
+        }
         Some("svg") => {
-            let res = image.save_svg(&image_path);
+            let res = image.save_svg(image_path);
             if let Err(e) = res {
                 eprintln!("Error saving svg: {e}");
                 return Err(());
             }
         }
+        Some("png") if args.animate => {
+            eprintln!("Error saving png: a png cannot be animated, save an svg or gif instead");
+            return Err(());
+        }
         Some("png") => {
-            let res = image.save_png(&image_path);
+            let res = image.save_png(image_path);
             if let Err(e) = res {
                 eprintln!("Error saving png: {e}");
                 return Err(());
             }
         }
+        Some("gif") => {
+            let res = animation::save_gif(image, image_path, args.speed);
+            if let Err(e) = res {
+                eprintln!("Error saving gif: {e}");
+                return Err(());
+            }
+        }
+        Some(extension @ ("gcode" | "hpgl")) => {
+            let bed = Bed {
+                width: args.bed_width,
+                height: args.bed_height,
+            };
+            let res = if extension == "gcode" {
+                plotter::to_gcode(image, bed)
+            } else {
+                plotter::to_hpgl(image, bed)
+            }
+            .and_then(|output| std::fs::write(image_path, output).map_err(|e| e.to_string()));
+            if let Err(e) = res {
+                eprintln!("Error saving {extension}: {e}");
+                return Err(());
+            }
+        }
         _ => {
             eprintln!("File extension not supported");
             return Err(());
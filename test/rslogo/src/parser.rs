@@ -0,0 +1,630 @@
+use crate::ast::{
+    ColorExpression, Direction, Expression, ExpressionKind, Operator, Procedure, Query, Statement,
+    StatementKind,
+};
+use crate::error::{ErrorKind, LogoError};
+use crate::lexer::{Position, Token, TokenKind};
+use std::rc::Rc;
+
+/// A recursive descent parser over the tokens of a program.
+///
+/// Each statement takes up the rest of its line, up to a `]` closing its block. A statement
+/// with an error is skipped, so the rest of the program is still checked.
+struct Parser<'a> {
+    tokens: &'a [Token],
+    index: usize,
+    /// Whether a `TO` is being parsed, where `END` closes the body.
+    in_procedure: bool,
+    errors: Vec<LogoError>,
+}
+
+/// Parses the tokens of a whole program.
+///
+/// # Parameters
+/// * `tokens`: The tokens from `lexer::tokenize`.
+///
+/// # Returns
+/// The statements of the program, or every syntax error in it.
+pub fn parse(tokens: &[Token]) -> Result<Vec<Statement>, Vec<LogoError>> {
+    let mut parser = Parser {
+        tokens,
+        index: 0,
+        in_procedure: false,
+        errors: Vec::new(),
+    };
+
+    let mut statements = Vec::new();
+    while parser.skip_newlines() {
+        match parser.statement() {
+            Ok(statement) => statements.push(statement),
+            Err(e) => parser.recover(e),
+        }
+    }
+
+    if parser.errors.is_empty() {
+        Ok(statements)
+    } else {
+        Err(parser.errors)
+    }
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&'a Token> {
+        self.tokens.get(self.index)
+    }
+
+    fn next(&mut self) -> Option<&'a Token> {
+        let token = self.tokens.get(self.index);
+        self.index += 1;
+        token
+    }
+
+    /// The position just after the last token, for errors at the end of the program.
+    fn end_position(&self) -> Position {
+        self.tokens
+            .last()
+            .map(|token| token.position)
+            .unwrap_or_default()
+    }
+
+    /// Skips blank lines.
+    ///
+    /// # Returns
+    /// Whether there are any tokens left.
+    fn skip_newlines(&mut self) -> bool {
+        while let Some(Token {
+            kind: TokenKind::Newline,
+            ..
+        }) = self.peek()
+        {
+            self.index += 1;
+        }
+        self.peek().is_some()
+    }
+
+    /// Records an error and skips the rest of the statement it happened in.
+    ///
+    /// Blocks opened on the line are skipped along with it, stopping at the end of the line or
+    /// at a `]` or `END` which closes an enclosing block.
+    fn recover(&mut self, error: LogoError) {
+        self.errors.push(error);
+
+        let mut depth = 0usize;
+        while let Some(token) = self.peek() {
+            match &token.kind {
+                TokenKind::Newline if depth == 0 => break,
+                TokenKind::CloseBracket if depth == 0 => break,
+                TokenKind::Word(word) if word == "END" && depth == 0 && self.in_procedure => break,
+                TokenKind::OpenBracket => depth += 1,
+                TokenKind::CloseBracket => depth -= 1,
+                _ => {}
+            }
+            self.index += 1;
+        }
+    }
+
+    /// Returns whether the current statement has ended, at the end of a line or block.
+    fn at_statement_end(&self) -> bool {
+        matches!(
+            self.peek().map(|token| &token.kind),
+            None | Some(TokenKind::Newline | TokenKind::CloseBracket)
+        )
+    }
+
+    fn statement(&mut self) -> Result<Statement, LogoError> {
+        let token = self.next().expect("caller checked a token is left");
+        let position = token.position;
+        let word = match &token.kind {
+            TokenKind::Word(word) => word,
+            TokenKind::CloseBracket => {
+                return Err(LogoError::new(ErrorKind::UnmatchedBracket, position))
+            }
+            other => {
+                return Err(LogoError::new(
+                    ErrorKind::ExpectedCommand {
+                        found: other.to_string(),
+                    },
+                    position,
+                ))
+            }
+        };
+
+        let kind = match word.as_str() {
+            "PENUP" | "PENDOWN" | "BEGINFILL" | "ENDFILL" => {
+                self.arguments(token, 0)?;
+                match word.as_str() {
+                    "PENUP" => StatementKind::PenUp,
+                    "PENDOWN" => StatementKind::PenDown,
+                    "BEGINFILL" => StatementKind::BeginFill,
+                    _ => StatementKind::EndFill,
+                }
+            }
+            "FORWARD" | "BACK" | "LEFT" | "RIGHT" => {
+                let direction = match word.as_str() {
+                    "FORWARD" => Direction::Forward,
+                    "BACK" => Direction::Back,
+                    "LEFT" => Direction::Left,
+                    _ => Direction::Right,
+                };
+                let distance = self.argument(token)?;
+                StatementKind::Move {
+                    direction,
+                    distance,
+                }
+            }
+            "TURN" => StatementKind::Turn(self.argument(token)?),
+            "SETHEADING" => StatementKind::SetHeading(self.argument(token)?),
+            "SETX" => StatementKind::SetX(self.argument(token)?),
+            "SETY" => StatementKind::SetY(self.argument(token)?),
+            "SETPENCOLOR" => StatementKind::SetPenColor(self.color_argument(token)?),
+            "SETBACKGROUND" => StatementKind::SetBackground(self.color_argument(token)?),
+            "SETPENSIZE" => StatementKind::SetPenSize(self.argument(token)?),
+            "ARC" => {
+                let mut arguments = self.arguments(token, 2)?;
+                let radius = arguments.pop().expect("two arguments");
+                let angle = arguments.pop().expect("two arguments");
+                StatementKind::Arc { angle, radius }
+            }
+            "CIRCLE" => StatementKind::Circle(self.argument(token)?),
+            "NEWTURTLE" => StatementKind::NewTurtle(self.argument(token)?),
+            "TELL" => StatementKind::Tell(self.argument(token)?),
+            "MAKE" | "ADDASSIGN" => {
+                let mut arguments = self.arguments(token, 2)?;
+                let value = arguments.pop().expect("two arguments");
+                let name = arguments.pop().expect("two arguments");
+                if word == "MAKE" {
+                    StatementKind::Make { name, value }
+                } else {
+                    StatementKind::AddAssign { name, value }
+                }
+            }
+            "IF" | "WHILE" | "REPEAT" | "ASK" => {
+                if self.at_statement_end() {
+                    return Err(LogoError::new(
+                        ErrorKind::NotEnoughArguments {
+                            command: word.clone(),
+                            expected: 1,
+                            given: 0,
+                        },
+                        position,
+                    ));
+                }
+                let condition = self.expression()?;
+                let body = self.block(token)?;
+                match word.as_str() {
+                    "IF" => StatementKind::If {
+                        condition,
+                        body,
+                        else_body: self.else_block()?,
+                    },
+                    "WHILE" => StatementKind::While { condition, body },
+                    "ASK" => StatementKind::Ask {
+                        turtle: condition,
+                        body,
+                    },
+                    _ => StatementKind::Repeat {
+                        count: condition,
+                        body,
+                    },
+                }
+            }
+            "FOR" => {
+                let mut arguments = Vec::new();
+                while !self.at_statement_end() && !self.at_block_start() {
+                    arguments.push(self.expression()?);
+                }
+                if arguments.len() < 3 {
+                    return Err(LogoError::new(
+                        ErrorKind::NotEnoughArguments {
+                            command: word.clone(),
+                            expected: 3,
+                            given: arguments.len(),
+                        },
+                        position,
+                    ));
+                }
+                if arguments.len() > 4 {
+                    return Err(LogoError::new(
+                        ErrorKind::TooManyArguments {
+                            command: word.clone(),
+                            expected: 4,
+                            given: arguments.len(),
+                        },
+                        arguments[4].position,
+                    ));
+                }
+                let step = (arguments.len() == 4).then(|| arguments.pop().expect("four arguments"));
+                let end = arguments.pop().expect("three arguments");
+                let start = arguments.pop().expect("three arguments");
+                let variable = arguments.pop().expect("three arguments");
+                StatementKind::For {
+                    variable,
+                    start,
+                    end,
+                    step,
+                    body: self.block(token)?,
+                }
+            }
+            "TO" => StatementKind::Procedure(Rc::new(self.procedure(token)?)),
+            "END" => return Err(LogoError::new(ErrorKind::UnmatchedEnd, position)),
+            "ELSE" => {
+                return Err(LogoError::new(
+                    ErrorKind::ExpectedCommand {
+                        found: "ELSE without an IF before it".to_string(),
+                    },
+                    position,
+                ))
+            }
+            name => {
+                let mut arguments = Vec::new();
+                while !self.at_statement_end() {
+                    arguments.push(self.expression()?);
+                }
+                StatementKind::Call {
+                    name: name.to_string(),
+                    arguments,
+                }
+            }
+        };
+
+        Ok(Statement { kind, position })
+    }
+
+    /// Parses exactly `count` arguments, which must be all that is left of the statement.
+    fn arguments(&mut self, command: &Token, count: usize) -> Result<Vec<Expression>, LogoError> {
+        let mut arguments = Vec::new();
+        for _ in 0..count {
+            if self.at_statement_end() {
+                return Err(LogoError::new(
+                    ErrorKind::NotEnoughArguments {
+                        command: command.kind.to_string(),
+                        expected: count,
+                        given: arguments.len(),
+                    },
+                    command.position,
+                ));
+            }
+            arguments.push(self.expression()?);
+        }
+
+        if !self.at_statement_end() {
+            let extra = self.peek().expect("statement has not ended").position;
+            // Count the extra arguments for the error, stopping at any that do not parse
+            let mut given = count;
+            while !self.at_statement_end() && self.expression().is_ok() {
+                given += 1;
+            }
+            return Err(LogoError::new(
+                ErrorKind::TooManyArguments {
+                    command: command.kind.to_string(),
+                    expected: count,
+                    given: given.max(count + 1),
+                },
+                extra,
+            ));
+        }
+        Ok(arguments)
+    }
+
+    fn argument(&mut self, command: &Token) -> Result<Expression, LogoError> {
+        Ok(self.arguments(command, 1)?.remove(0))
+    }
+
+    fn at_block_start(&self) -> bool {
+        matches!(
+            self.peek(),
+            Some(Token {
+                kind: TokenKind::OpenBracket,
+                ..
+            })
+        )
+    }
+
+    /// Parses the `ELSE [ ... ]` block after an `IF` block, if there is one.
+    ///
+    /// `ELSE` can follow the `]` of the `IF` block, or start the next line.
+    fn else_block(&mut self) -> Result<Option<Vec<Statement>>, LogoError> {
+        let mut index = self.index;
+        while let Some(Token {
+            kind: TokenKind::Newline,
+            ..
+        }) = self.tokens.get(index)
+        {
+            index += 1;
+        }
+        match self.tokens.get(index) {
+            Some(
+                token @ Token {
+                    kind: TokenKind::Word(word),
+                    ..
+                },
+            ) if word == "ELSE" => {
+                self.index = index + 1;
+                Ok(Some(self.block(token)?))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Parses a colour, given either as an index into the palette or as red, green and blue.
+    fn color_argument(&mut self, command: &Token) -> Result<ColorExpression, LogoError> {
+        let mut arguments = Vec::new();
+        while !self.at_statement_end() {
+            arguments.push(self.expression()?);
+        }
+        match arguments.len() {
+            1 => Ok(ColorExpression::Palette(arguments.remove(0))),
+            3 => {
+                let [red, green, blue]: [Expression; 3] =
+                    arguments.try_into().expect("three arguments");
+                Ok(ColorExpression::Rgb(Box::new([red, green, blue])))
+            }
+            given if given < 3 => Err(LogoError::new(
+                ErrorKind::NotEnoughArguments {
+                    command: command.kind.to_string(),
+                    expected: if given == 0 { 1 } else { 3 },
+                    given,
+                },
+                command.position,
+            )),
+            given => Err(LogoError::new(
+                ErrorKind::TooManyArguments {
+                    command: command.kind.to_string(),
+                    expected: 3,
+                    given,
+                },
+                arguments[3].position,
+            )),
+        }
+    }
+
+    /// Parses the `[ ... ]` block ending the statement started by `command`.
+    fn block(&mut self, command: &Token) -> Result<Vec<Statement>, LogoError> {
+        let open = match self.next() {
+            Some(
+                token @ Token {
+                    kind: TokenKind::OpenBracket,
+                    ..
+                },
+            ) => token,
+            other => {
+                let position = other.map_or(self.end_position(), |token| token.position);
+                let found = other.map_or("the end of the program".to_string(), |token| {
+                    token.kind.to_string()
+                });
+                return Err(LogoError::new(
+                    ErrorKind::MissingBracket {
+                        command: command.kind.to_string(),
+                        found,
+                    },
+                    position,
+                ));
+            }
+        };
+        let unclosed = LogoError::new(
+            ErrorKind::UnclosedBlock {
+                command: command.kind.to_string(),
+            },
+            open.position,
+        );
+
+        let mut body = Vec::new();
+        loop {
+            if !self.skip_newlines() {
+                return Err(unclosed);
+            }
+            let token = self.peek().expect("a token is left");
+            match &token.kind {
+                TokenKind::CloseBracket => {
+                    self.index += 1;
+                    return Ok(body);
+                }
+                TokenKind::Word(word) if word == "END" && self.in_procedure => {
+                    return Err(unclosed);
+                }
+                _ => match self.statement() {
+                    Ok(statement) => body.push(statement),
+                    Err(e) => self.recover(e),
+                },
+            }
+        }
+    }
+
+    /// Parses a `TO name params ... END` definition.
+    fn procedure(&mut self, to: &Token) -> Result<Procedure, LogoError> {
+        if self.in_procedure {
+            return Err(LogoError::new(
+                ErrorKind::InvalidProcedure(
+                    "procedures cannot be defined inside another procedure".to_string(),
+                ),
+                to.position,
+            ));
+        }
+
+        let name = match self.peek() {
+            Some(Token {
+                kind: TokenKind::Word(name),
+                ..
+            }) => {
+                self.index += 1;
+                name.clone()
+            }
+            _ => {
+                return Err(LogoError::new(
+                    ErrorKind::InvalidProcedure("TO needs the name of the procedure".to_string()),
+                    to.position,
+                ))
+            }
+        };
+
+        let mut params = Vec::new();
+        while !self.at_statement_end() {
+            match self.expression() {
+                Ok(param) => params.push(param),
+                // Still parse the body, so its END does not look unmatched
+                Err(e) => self.recover(e),
+            }
+        }
+
+        self.in_procedure = true;
+        let mut body = Vec::new();
+        loop {
+            if !self.skip_newlines() {
+                self.errors.push(LogoError::new(
+                    ErrorKind::MissingEnd {
+                        procedure: name.clone(),
+                    },
+                    to.position,
+                ));
+                break;
+            }
+            let token = self.peek().expect("a token is left");
+            match &token.kind {
+                TokenKind::Word(word) if word == "END" => {
+                    self.index += 1;
+                    if !self.at_statement_end() {
+                        let extra = self.peek().expect("statement has not ended").position;
+                        self.recover(LogoError::new(
+                            ErrorKind::TooManyArguments {
+                                command: "END".to_string(),
+                                expected: 0,
+                                given: 1,
+                            },
+                            extra,
+                        ));
+                    }
+                    break;
+                }
+                TokenKind::CloseBracket => {
+                    self.index += 1;
+                    self.recover(LogoError::new(ErrorKind::UnmatchedBracket, token.position));
+                }
+                _ => match self.statement() {
+                    Ok(statement) => body.push(statement),
+                    Err(e) => self.recover(e),
+                },
+            }
+        }
+        self.in_procedure = false;
+
+        Ok(Procedure { name, params, body })
+    }
+
+    /// Parses a prefix expression, including any expressions it is applied to.
+    fn expression(&mut self) -> Result<Expression, LogoError> {
+        let Some(token) = self.next() else {
+            return Err(LogoError::new(
+                ErrorKind::ExpectedValue {
+                    found: "the end of the program".to_string(),
+                },
+                self.end_position(),
+            ));
+        };
+        let position = token.position;
+
+        let kind = match &token.kind {
+            TokenKind::Literal(literal) => ExpressionKind::Literal(literal.clone()),
+            TokenKind::Variable(name) => ExpressionKind::Variable(name.clone()),
+            TokenKind::Word(word) => {
+                if let Some(query) = Query::from_word(word) {
+                    ExpressionKind::Query(query)
+                } else if let Some(operator) = Operator::from_word(word) {
+                    let left = self.operand(token, 0)?;
+                    let right = self.operand(token, 1)?;
+                    ExpressionKind::Binary {
+                        operator,
+                        left: Box::new(left),
+                        right: Box::new(right),
+                    }
+                } else {
+                    return Err(LogoError::new(
+                        ErrorKind::ExpectedValue {
+                            found: word.clone(),
+                        },
+                        position,
+                    ));
+                }
+            }
+            other => {
+                return Err(LogoError::new(
+                    ErrorKind::ExpectedValue {
+                        found: other.to_string(),
+                    },
+                    position,
+                ))
+            }
+        };
+
+        Ok(Expression { kind, position })
+    }
+
+    /// Parses an operand of `operator`, which already has `given` operands.
+    fn operand(&mut self, operator: &Token, given: usize) -> Result<Expression, LogoError> {
+        if self.at_statement_end() {
+            return Err(LogoError::new(
+                ErrorKind::NotEnoughArguments {
+                    command: operator.kind.to_string(),
+                    expected: 2,
+                    given,
+                },
+                operator.position,
+            ));
+        }
+        self.expression()
+    }
+}
+
+// ===================== TESTS ============================
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::tokenize;
+
+    // 1. Test parsing nested blocks across lines, with positions
+    #[test]
+    fn test_parse_nested_blocks() {
+        let program = "WHILE EQ :a \"1 [\n  IF GT XCOR \"5 [ FORWARD \"1 ]\n  BACK + \"1 \"2\n]\n";
+        let statements = parse(&tokenize(program)).unwrap();
+        assert_eq!(statements.len(), 1);
+
+        let StatementKind::While { body, .. } = &statements[0].kind else {
+            panic!("expected a WHILE");
+        };
+        assert_eq!(body.len(), 2);
+        assert_eq!(body[0].position, Position { line: 2, column: 3 });
+        let StatementKind::Move { distance, .. } = &body[1].kind else {
+            panic!("expected a move");
+        };
+        assert_eq!(distance.position, Position { line: 3, column: 8 });
+    }
+
+    // 2. Test that malformed programs are rejected
+    #[test]
+    fn test_parse_errors() {
+        assert!(parse(&tokenize("FORWARD \"1 \"2")).is_err());
+        assert!(parse(&tokenize("IF EQ \"1 \"1\nEND")).is_err());
+        assert!(parse(&tokenize("WHILE EQ \"1 \"1 [\nPENDOWN")).is_err());
+        assert!(parse(&tokenize("TO Box\nFORWARD \"1")).is_err());
+        assert!(parse(&tokenize("TO Box \"a\nFORWARD :a\nEND\nBox \"1")).is_ok());
+    }
+
+    // 3. Test that parsing carries on after an error, to report every error in the program
+    #[test]
+    fn test_parse_reports_every_error() {
+        let program = "FORWARD \"1 \"2\nIF EQ \"1 \"1 [\n  PENUP \"1\n]\n]\nWHILE \"TRUE [\n";
+        let errors = parse(&tokenize(program)).unwrap_err();
+        let found: Vec<_> = errors
+            .iter()
+            .map(|e| (e.code(), e.position.line, e.position.column))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                ("E0001", 1, 12),
+                ("E0001", 3, 9),
+                ("E0008", 5, 1),
+                ("E0005", 6, 13)
+            ]
+        );
+    }
+}
@@ -0,0 +1,293 @@
+use crate::canvas::{self, Canvas, Shape};
+
+/// The width of the bed in millimetres by default, which fits A4 paper.
+pub const DEFAULT_BED_WIDTH: f64 = 210.0;
+
+/// The height of the bed in millimetres by default, which fits A4 paper.
+pub const DEFAULT_BED_HEIGHT: f64 = 297.0;
+
+/// How high the pen is lifted above the paper in G-code, in millimetres.
+const PEN_UP_Z: f64 = 5.0;
+
+/// How fast the pen draws in G-code, in millimetres per minute.
+const FEED_RATE: u32 = 1500;
+
+/// How many HPGL plotter units there are in a millimetre.
+const HPGL_UNITS_PER_MM: f64 = 40.0;
+
+/// How far apart two points on the canvas can be and still be drawn without lifting the pen,
+/// such as where a line starts at the rounded end of an arc.
+const JOIN_DISTANCE: f64 = 1.0;
+
+/// The size of a plotter's bed, in millimetres.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bed {
+    pub width: f64,
+    pub height: f64,
+}
+
+/// A movement of the pen while it is down, in millimetres from the bottom left of the bed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Stroke {
+    Line((f64, f64)),
+    /// An arc of a quarter turn or less, anticlockwise on the bed if `sweep` is positive.
+    Arc {
+        center: (f64, f64),
+        sweep: f64,
+        to: (f64, f64),
+    },
+}
+
+/// Strokes drawn one after another without lifting the pen.
+#[derive(Debug, Clone, PartialEq)]
+struct Path {
+    start: (f64, f64),
+    strokes: Vec<Stroke>,
+}
+
+/// Converts the lines and arcs on a canvas into paths for a plotter, joining those which meet.
+///
+/// The drawing is scaled to fit the bed, keeping its proportions, and flipped so the bottom
+/// left of the canvas is the origin of the bed. Fills are left out, since a pen cannot fill.
+///
+/// # Returns
+/// The paths, or an error if the bed has no size, or if the drawing goes outside the canvas,
+/// where the pen could run off the bed.
+fn paths(canvas: &Canvas, bed: Bed) -> Result<Vec<Path>, String> {
+    if !(bed.width > 0.0 && bed.height > 0.0) {
+        return Err(format!(
+            "the bed must be larger than 0mm, not {}x{}mm",
+            bed.width, bed.height
+        ));
+    }
+    let (width, height) = canvas.get_dimensions();
+    let (width, height) = (f64::from(width), f64::from(height));
+    let scale = (bed.width / width).min(bed.height / height);
+    let to_bed = |(x, y): (f64, f64)| -> Result<(f64, f64), String> {
+        // Allow for arcs ending a little past a rounded edge
+        let margin = 1e-6;
+        if x < -margin || y < -margin || x > width + margin || y > height + margin {
+            return Err(format!(
+                "the drawing goes outside the {}x{} canvas, so it would not fit on the bed",
+                width, height
+            ));
+        }
+        Ok((x * scale, (height - y) * scale))
+    };
+    let point = |(x, y): (i32, i32)| (f64::from(x), f64::from(y));
+
+    let mut paths: Vec<Path> = Vec::new();
+    let mut end: Option<(f64, f64)> = None;
+    for shape in canvas.shapes() {
+        let (start, strokes) = match shape {
+            Shape::Line { from, to, .. } => (point(*from), vec![Stroke::Line(to_bed(point(*to))?)]),
+            Shape::Arc { arc, .. } => {
+                let sweep = arc.sweep.clamp(-360.0, 360.0);
+                let pieces = (sweep.abs() / 90.0).ceil().max(1.0) as usize;
+                let center = (arc.center.0 * scale, (height - arc.center.1) * scale);
+                let mut strokes = Vec::new();
+                for i in 1..=pieces {
+                    // Check the middle of each piece too, which is where it bulges furthest
+                    let middle = arc.start + sweep * (i as f64 - 0.5) / pieces as f64;
+                    to_bed(arc.point(middle))?;
+                    let angle = arc.start + sweep * i as f64 / pieces as f64;
+                    // Flipping the y axis turns a clockwise sweep into a negative angle
+                    strokes.push(Stroke::Arc {
+                        center,
+                        sweep: -sweep / pieces as f64,
+                        to: to_bed(arc.point(angle))?,
+                    });
+                }
+                (arc.start_point(), strokes)
+            }
+            Shape::Polygon { .. } => continue,
+        };
+
+        let start_on_bed = to_bed(start)?;
+        let joined = end.is_some_and(|(x, y)| {
+            (x - start_on_bed.0).hypot(y - start_on_bed.1) <= JOIN_DISTANCE * scale
+        });
+        let last = match paths.last_mut() {
+            Some(path) if joined => path,
+            _ => {
+                paths.push(Path {
+                    start: start_on_bed,
+                    strokes: Vec::new(),
+                });
+                paths.last_mut().expect("a path was just added")
+            }
+        };
+        if joined && end != Some(start_on_bed) {
+            last.strokes.push(Stroke::Line(start_on_bed));
+        }
+        last.strokes.extend(strokes);
+        end = last.strokes.last().map(|stroke| match *stroke {
+            Stroke::Line(to) | Stroke::Arc { to, .. } => to,
+        });
+    }
+    Ok(paths)
+}
+
+/// Writes the drawing as G-code for a pen plotter, which lifts the pen by moving along Z.
+///
+/// Coordinates are absolute, in millimetres from the bottom left of the bed, and arcs are
+/// drawn with `G2` and `G3`.
+///
+/// # Parameters
+/// * `canvas`: The canvas, after a program has drawn on it.
+/// * `bed`: The size of the plotter's bed.
+pub fn to_gcode(canvas: &Canvas, bed: Bed) -> Result<String, String> {
+    let n = canvas::number;
+    let mut gcode = vec![
+        "; Drawn by rslogo".to_string(),
+        "G21 ; millimetres".to_string(),
+        "G90 ; absolute coordinates".to_string(),
+        format!("G0 Z{}", n(PEN_UP_Z)),
+    ];
+    for path in paths(canvas, bed)? {
+        gcode.push(format!("G0 X{} Y{}", n(path.start.0), n(path.start.1)));
+        gcode.push(format!("G1 Z0 F{}", FEED_RATE));
+        let mut from = path.start;
+        for stroke in path.strokes {
+            match stroke {
+                Stroke::Line(to) => {
+                    gcode.push(format!("G1 X{} Y{}", n(to.0), n(to.1)));
+                    from = to;
+                }
+                Stroke::Arc { center, sweep, to } => {
+                    gcode.push(format!(
+                        "{} X{} Y{} I{} J{}",
+                        if sweep > 0.0 { "G3" } else { "G2" },
+                        n(to.0),
+                        n(to.1),
+                        n(center.0 - from.0),
+                        n(center.1 - from.1)
+                    ));
+                    from = to;
+                }
+            }
+        }
+        gcode.push(format!("G0 Z{}", n(PEN_UP_Z)));
+    }
+    gcode.push("G0 X0 Y0".to_string());
+    gcode.push("M2".to_string());
+    Ok(gcode.join("\n") + "\n")
+}
+
+/// Writes the drawing as HPGL for a pen plotter, which lifts the pen with `PU` and lowers it
+/// with `PD`.
+///
+/// Coordinates are in plotter units of 0.025 millimetres, from the bottom left of the bed, and
+/// arcs are drawn with `AA`.
+///
+/// # Parameters
+/// * `canvas`: The canvas, after a program has drawn on it.
+/// * `bed`: The size of the plotter's bed.
+pub fn to_hpgl(canvas: &Canvas, bed: Bed) -> Result<String, String> {
+    let units = |(x, y): (f64, f64)| {
+        format!(
+            "{},{}",
+            (x * HPGL_UNITS_PER_MM).round(),
+            (y * HPGL_UNITS_PER_MM).round()
+        )
+    };
+    let mut hpgl = vec!["IN;".to_string(), "SP1;".to_string()];
+    for path in paths(canvas, bed)? {
+        hpgl.push(format!("PU{};", units(path.start)));
+        for stroke in path.strokes {
+            hpgl.push(match stroke {
+                Stroke::Line(to) => format!("PD{};", units(to)),
+                Stroke::Arc { center, sweep, .. } => {
+                    format!("PD;AA{},{};", units(center), canvas::number(sweep))
+                }
+            });
+        }
+    }
+    hpgl.push("PU;".to_string());
+    hpgl.push("SP0;".to_string());
+    Ok(hpgl.join("\n") + "\n")
+}
+
+// ===================== TESTS ============================
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canvas::Arc;
+    use unsvg::Color;
+
+    /// A 100 by 50 canvas with a line and a half circle which meet, and a separate line.
+    fn drawing() -> Canvas {
+        let mut canvas = Canvas::new(100, 50);
+        canvas.draw_line((0, 50), (50, 50), Color::white(), 1);
+        canvas.draw_arc(
+            Arc {
+                center: (60.0, 50.0),
+                radius: 10.0,
+                start: 270.0,
+                sweep: 180.0,
+            },
+            Color::white(),
+            1,
+        );
+        canvas.draw_line((100, 0), (90, 0), Color::white(), 1);
+        canvas
+    }
+
+    // 1. Test G-code is scaled to fit the bed, flipped, and only lifts the pen between paths
+    #[test]
+    fn test_gcode() {
+        let bed = Bed {
+            width: 200.0,
+            height: 200.0,
+        };
+        let gcode = to_gcode(&drawing(), bed).unwrap();
+        let expected = "; Drawn by rslogo
+G21 ; millimetres
+G90 ; absolute coordinates
+G0 Z5
+G0 X0 Y0
+G1 Z0 F1500
+G1 X100 Y0
+G2 X120 Y20 I20 J0
+G2 X140 Y0 I0 J-20
+G0 Z5
+G0 X200 Y100
+G1 Z0 F1500
+G1 X180 Y100
+G0 Z5
+G0 X0 Y0
+M2
+";
+        assert_eq!(gcode, expected);
+    }
+
+    // 2. Test HPGL uses plotter units, and drawing off the canvas is an error
+    #[test]
+    fn test_hpgl() {
+        let bed = Bed {
+            width: 100.0,
+            height: 100.0,
+        };
+        let hpgl = to_hpgl(&drawing(), bed).unwrap();
+        let lines: Vec<_> = hpgl.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "IN;",
+                "SP1;",
+                "PU0,0;",
+                "PD2000,0;",
+                "PD;AA2400,0,-90;",
+                "PD;AA2400,0,-90;",
+                "PU4000,2000;",
+                "PD3600,2000;",
+                "PU;",
+                "SP0;"
+            ]
+        );
+
+        let mut canvas = drawing();
+        canvas.draw_line((0, 0), (0, -10), Color::white(), 1);
+        assert!(to_hpgl(&canvas, bed).is_err());
+    }
+}
@@ -0,0 +1,149 @@
+use crate::canvas::Canvas;
+use crate::compiler;
+use crate::error::{ErrorKind, LogoError};
+use crate::lexer;
+use crate::parser;
+use crate::vm::Vm;
+use std::rc::Rc;
+
+/// What happened to a line given to a `Repl`.
+#[derive(Debug)]
+pub enum Entry {
+    /// The line left a block or procedure open, so more lines are needed before it can run.
+    Incomplete,
+    /// Nothing was entered.
+    Empty,
+    /// The command was run, or failed with these errors.
+    Ran(Result<(), Vec<LogoError>>),
+}
+
+/// An interactive session, which runs commands one at a time on the same machine.
+///
+/// Every command entered is kept as the session's source, and its positions count lines from
+/// the start of the session, so errors can point into any earlier command, such as the
+/// definition of a procedure.
+pub struct Repl<'a> {
+    vm: Vm<'a>,
+    source: String,
+    /// The lines of a command which is not complete yet.
+    pending: String,
+}
+
+impl<'a> Repl<'a> {
+    /// Starts a session with a machine, which keeps its turtle, variables and procedures
+    /// between commands.
+    pub fn new(vm: Vm<'a>) -> Self {
+        Repl {
+            vm,
+            source: String::new(),
+            pending: String::new(),
+        }
+    }
+
+    /// Returns every command entered so far, to render errors with.
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    pub fn canvas(&self) -> &Canvas {
+        self.vm.canvas()
+    }
+
+    /// Returns whether some lines have been entered which are not a complete command yet.
+    pub fn is_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Adds a line, running the command once it is complete.
+    ///
+    /// # Parameters
+    /// * `line`: The line, with or without its newline.
+    pub fn line(&mut self, line: &str) -> Entry {
+        self.pending.push_str(line.trim_end_matches(['\r', '\n']));
+        self.pending.push('\n');
+        self.run(false)
+    }
+
+    /// Runs the lines entered so far, even if they are not complete, such as at the end of
+    /// the input.
+    pub fn finish(&mut self) -> Entry {
+        if self.pending.is_empty() {
+            return Entry::Empty;
+        }
+        self.run(true)
+    }
+
+    fn run(&mut self, force: bool) -> Entry {
+        let offset = self.source.lines().count();
+        let mut tokens = lexer::tokenize(&self.pending);
+        if tokens
+            .iter()
+            .all(|token| token.kind == lexer::TokenKind::Newline)
+        {
+            self.pending.clear();
+            return Entry::Empty;
+        }
+        for token in &mut tokens {
+            token.position.line += offset;
+        }
+
+        let statements = match parser::parse(&tokens) {
+            Err(errors) if !force && errors.iter().any(|e| unfinished(&e.kind)) => {
+                return Entry::Incomplete;
+            }
+            result => result,
+        };
+        self.source.push_str(&std::mem::take(&mut self.pending));
+
+        Entry::Ran(statements.and_then(|statements| {
+            let chunk = Rc::new(compiler::compile(&statements));
+            self.vm.run(&chunk).map_err(|e| vec![e])
+        }))
+    }
+}
+
+/// Returns whether an error only means the input stopped before a block or procedure ended.
+fn unfinished(kind: &ErrorKind) -> bool {
+    matches!(
+        kind,
+        ErrorKind::UnclosedBlock { .. } | ErrorKind::MissingEnd { .. }
+    )
+}
+
+// ===================== TESTS ============================
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 1. Test blocks and procedures are run once complete, keeping state between commands
+    #[test]
+    fn test_repl() {
+        let mut canvas = Canvas::new(100, 100);
+        let mut repl = Repl::new(Vm::new(&mut canvas));
+        assert!(matches!(repl.line("MAKE \"n \"0"), Entry::Ran(Ok(()))));
+        assert!(matches!(repl.line(""), Entry::Empty));
+        assert!(matches!(repl.line("TO Add \"x"), Entry::Incomplete));
+        assert!(matches!(repl.line("REPEAT :x ["), Entry::Incomplete));
+        assert!(matches!(
+            repl.line("ADDASSIGN \"n \"1 ]"),
+            Entry::Incomplete
+        ));
+        assert!(matches!(repl.line("END"), Entry::Ran(Ok(()))));
+        assert!(matches!(repl.line("Add \"3"), Entry::Ran(Ok(()))));
+        assert!(matches!(
+            repl.line("PENDOWN FORWARD :n"),
+            Entry::Ran(Err(_))
+        ));
+        assert!(matches!(repl.line("PENDOWN"), Entry::Ran(Ok(()))));
+        assert!(matches!(repl.line("FORWARD :n"), Entry::Ran(Ok(()))));
+
+        // A failure inside a procedure points at the line it was defined on
+        assert!(
+            matches!(repl.line("Add \"TRUE"), Entry::Ran(Err(errors)) if errors[0].position.line == 3)
+        );
+        assert!(matches!(repl.line("REPEAT \"2 ["), Entry::Incomplete));
+        assert!(matches!(repl.finish(), Entry::Ran(Err(_))));
+        assert!(!repl.is_pending());
+        assert_eq!(repl.canvas().shapes().len(), 1);
+    }
+}
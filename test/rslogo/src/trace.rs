@@ -0,0 +1,291 @@
+use crate::canvas::{self, Shape};
+use crate::lexer::Position;
+use crate::turtle::PenColor;
+use crate::value::Value;
+use crate::vm::{Observer, Vm};
+use serde::Serialize;
+use serde_json::{Map, Value as Json};
+use std::collections::BTreeMap;
+
+/// A line or arc the turtle drew.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+struct Segment {
+    /// How many statements had run before the one which drew it.
+    step: usize,
+    line: usize,
+    start: (i32, i32),
+    end: (i32, i32),
+    color: String,
+    pen_size: u32,
+    /// The exact curve, for an arc.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    arc: Option<ArcTrace>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+struct ArcTrace {
+    center: (f64, f64),
+    radius: f64,
+    /// Degrees clockwise, or anticlockwise if negative.
+    sweep: f64,
+}
+
+/// What a statement changed, holding only the fields which changed.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+struct Change {
+    step: usize,
+    line: usize,
+    #[serde(flatten)]
+    fields: Map<String, Json>,
+}
+
+/// The state of the turtle commands move, and of the canvas it draws on.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+struct State {
+    turtle: String,
+    x: i32,
+    y: i32,
+    heading: i32,
+    pen_down: bool,
+    color: String,
+    pen_size: u32,
+    filling: bool,
+    background: String,
+}
+
+/// Everything a program did, as it is written out.
+#[derive(Serialize)]
+struct Document<'t> {
+    segments: &'t [Segment],
+    changes: &'t [Change],
+    #[serde(rename = "final")]
+    last: State,
+    variables: BTreeMap<&'t str, Json>,
+}
+
+/// Records what a program draws and how it changes the turtle, statement by statement, to write
+/// out as JSON.
+///
+/// Everything is put down to the statement which was running when it happened, given by the
+/// statement's line and by `step`, which counts the statements run before it.
+pub struct Trace {
+    segments: Vec<Segment>,
+    changes: Vec<Change>,
+    /// The state before the statement which is running.
+    state: State,
+    /// The statement which is running, as `(step, line)`, or `None` before the first.
+    running: Option<(usize, usize)>,
+    steps: usize,
+    /// How many lines and arcs had been drawn before the statement which is running.
+    drawn: usize,
+}
+
+impl State {
+    fn of(vm: &Vm) -> Self {
+        let turtle = vm.turtle();
+        State {
+            turtle: vm.turtle_name().to_string(),
+            x: turtle.x,
+            y: turtle.y,
+            heading: turtle.heading,
+            pen_down: turtle.pen_down,
+            color: color(turtle.color),
+            pen_size: turtle.pen_size,
+            filling: turtle.fill.is_some(),
+            background: canvas::hex(vm.canvas().background()),
+        }
+    }
+}
+
+/// Formats a pen colour as `#rrggbb`.
+fn color(color: PenColor) -> String {
+    canvas::hex(color.color())
+}
+
+fn json(value: &Value) -> Json {
+    match value {
+        Value::Number(number) => Json::from(*number),
+        Value::Bool(boolean) => Json::from(*boolean),
+        Value::Word(word) => Json::from(word.as_str()),
+    }
+}
+
+impl Trace {
+    /// Starts a trace of a machine which has not run anything yet.
+    pub fn new(vm: &Vm) -> Self {
+        Trace {
+            segments: Vec::new(),
+            changes: Vec::new(),
+            state: State::of(vm),
+            running: None,
+            steps: 0,
+            drawn: vm.canvas().segment_count(),
+        }
+    }
+
+    /// Records what the statement which was running did.
+    fn record(&mut self, vm: &Vm) {
+        let Some((step, line)) = self.running else {
+            return;
+        };
+
+        let canvas = vm.canvas();
+        let count = canvas.segment_count();
+        let mut new: Vec<&Shape> = canvas
+            .shapes()
+            .iter()
+            .rev()
+            .filter(|shape| !matches!(shape, Shape::Polygon { .. }))
+            .take(count - self.drawn)
+            .collect();
+        new.reverse();
+        for shape in new {
+            let segment = match shape {
+                Shape::Line {
+                    from,
+                    to,
+                    color,
+                    width,
+                } => Segment {
+                    step,
+                    line,
+                    start: *from,
+                    end: *to,
+                    color: canvas::hex(*color),
+                    pen_size: *width,
+                    arc: None,
+                },
+                Shape::Arc { arc, color, width } => {
+                    let round = |(x, y): (f64, f64)| (x.round() as i32, y.round() as i32);
+                    Segment {
+                        step,
+                        line,
+                        start: round(arc.start_point()),
+                        end: round(arc.end_point()),
+                        color: canvas::hex(*color),
+                        pen_size: *width,
+                        arc: Some(ArcTrace {
+                            center: arc.center,
+                            radius: arc.radius,
+                            sweep: arc.sweep,
+                        }),
+                    }
+                }
+                Shape::Polygon { .. } => unreachable!("fills are filtered out"),
+            };
+            self.segments.push(segment);
+        }
+        self.drawn = count;
+
+        let state = State::of(vm);
+        let (Json::Object(before), Json::Object(after)) = (
+            serde_json::to_value(&self.state).expect("state is serializable"),
+            serde_json::to_value(&state).expect("state is serializable"),
+        ) else {
+            unreachable!("a struct is serialized as an object");
+        };
+        let fields: Map<String, Json> = after
+            .into_iter()
+            .filter(|(field, value)| before.get(field) != Some(value))
+            .collect();
+        if !fields.is_empty() {
+            self.changes.push(Change { step, line, fields });
+        }
+        self.state = state;
+    }
+
+    /// Finishes the trace once the program has stopped.
+    ///
+    /// # Returns
+    /// The trace as JSON, with the final state of the turtle and the global variables.
+    pub fn finish(mut self, vm: &Vm) -> String {
+        self.record(vm);
+        let document = Document {
+            segments: &self.segments,
+            changes: &self.changes,
+            last: State::of(vm),
+            variables: vm
+                .variables()
+                .iter()
+                .map(|(name, value)| (name.as_str(), json(value)))
+                .collect(),
+        };
+        serde_json::to_string_pretty(&document).expect("the trace is serializable") + "\n"
+    }
+}
+
+impl Observer for Trace {
+    fn stop(&mut self, vm: &Vm, position: Position) -> bool {
+        self.record(vm);
+        self.running = Some((self.steps, position.line));
+        self.steps += 1;
+        true
+    }
+}
+
+// ===================== TESTS ============================
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canvas::Canvas;
+    use crate::compiler::compile;
+    use crate::lexer::tokenize;
+    use crate::parser::parse;
+    use std::rc::Rc;
+
+    // 1. Test segments and changes are put down to the statements which made them
+    #[test]
+    fn test_trace() {
+        let program = "MAKE \"side \"10
+PENDOWN
+SETPENCOLOR \"1
+BEGINFILL
+REPEAT \"2 [
+  FORWARD :side
+  TURN \"90
+]
+ENDFILL
+";
+        let mut canvas = Canvas::new(100, 100);
+        let mut vm = Vm::new(&mut canvas);
+        let mut trace = Trace::new(&vm);
+        let chunk = Rc::new(compile(&parse(&tokenize(program)).unwrap()));
+        vm.run_observed(&chunk, &mut trace).unwrap();
+        let trace: Json = serde_json::from_str(&trace.finish(&vm)).unwrap();
+
+        let segments = trace["segments"].as_array().unwrap();
+        assert_eq!(segments.len(), 2);
+        assert_eq!(
+            segments[1],
+            serde_json::json!({
+                "step": 9,
+                "line": 6,
+                "start": [50, 40],
+                "end": [60, 40],
+                "color": "#0000ff",
+                "pen_size": 1
+            })
+        );
+
+        let changes = trace["changes"].as_array().unwrap();
+        assert_eq!(
+            changes[0],
+            serde_json::json!({"step": 1, "line": 2, "pen_down": true})
+        );
+        assert_eq!(
+            changes[1],
+            serde_json::json!({"step": 2, "line": 3, "color": "#0000ff"})
+        );
+        assert_eq!(
+            changes[2],
+            serde_json::json!({"step": 3, "line": 4, "filling": true})
+        );
+        assert_eq!(
+            changes.last().unwrap(),
+            &serde_json::json!({"step": 12, "line": 9, "filling": false})
+        );
+
+        assert_eq!(trace["final"]["heading"], 180);
+        assert_eq!(trace["variables"], serde_json::json!({"side": 10}));
+    }
+}
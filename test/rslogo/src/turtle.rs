@@ -0,0 +1,231 @@
+use crate::canvas::{Arc, Canvas, Cursor, Edge};
+use crate::error::ErrorKind;
+use unsvg::{get_end_coordinates, Color, COLORS};
+
+/// The pen colour the turtle starts with, which is white.
+const DEFAULT_COLOR: usize = 7;
+
+/// A colour, either from the 16 colours in `unsvg::COLORS` or given as red, green and blue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PenColor {
+    Palette(usize),
+    Rgb(u8, u8, u8),
+}
+
+impl PenColor {
+    /// Looks up a colour by its index in `unsvg::COLORS`.
+    ///
+    /// # Returns
+    /// The colour, or an error if there is no colour with that index.
+    pub fn palette(index: i32) -> Result<Self, ErrorKind> {
+        match usize::try_from(index) {
+            Ok(index) if index < COLORS.len() => Ok(PenColor::Palette(index)),
+            _ => Err(ErrorKind::InvalidColor(index)),
+        }
+    }
+
+    /// Creates a colour from its red, green and blue components.
+    ///
+    /// # Returns
+    /// The colour, or an error naming the first component which is not between 0 and 255.
+    pub fn rgb(red: i32, green: i32, blue: i32) -> Result<Self, ErrorKind> {
+        let component = |value: i32| u8::try_from(value).map_err(|_| ErrorKind::InvalidRgb(value));
+        Ok(PenColor::Rgb(
+            component(red)?,
+            component(green)?,
+            component(blue)?,
+        ))
+    }
+
+    pub fn color(&self) -> Color {
+        match *self {
+            PenColor::Palette(index) => COLORS[index],
+            PenColor::Rgb(red, green, blue) => Color::new_rgb(red, green, blue),
+        }
+    }
+
+    /// Returns the colour's index for the `COLOR` query, which is -1 for an RGB colour.
+    pub fn index(&self) -> i32 {
+        match *self {
+            PenColor::Palette(index) => index as i32,
+            PenColor::Rgb(..) => -1,
+        }
+    }
+}
+
+/// A shape being recorded between `BEGINFILL` and `ENDFILL`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fill {
+    /// How many shapes the canvas had when the fill began.
+    pub index: usize,
+    pub start: (i32, i32),
+    pub edges: Vec<Edge>,
+    pub color: PenColor,
+}
+
+/// The turtle which moves around the image, drawing wherever it goes while its pen is down.
+///
+/// Coordinates start at the top left of the image, and a heading of 0 points straight up.
+/// Headings are kept between 0 and 359 degrees.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Turtle {
+    pub x: i32,
+    pub y: i32,
+    /// Where the turtle is before rounding, which only differs from `x` and `y` after an arc.
+    /// Arcs start from here, so a chain of them does not drift.
+    exact: (f64, f64),
+    pub heading: i32,
+    pub pen_down: bool,
+    pub color: PenColor,
+    pub pen_size: u32,
+    pub fill: Option<Fill>,
+}
+
+impl Turtle {
+    /// Creates a turtle in the middle of an image, facing up with its pen up.
+    ///
+    /// # Parameters
+    /// * `width`: The width of the image.
+    /// * `height`: The height of the image.
+    pub fn new(width: u32, height: u32) -> Self {
+        let (x, y) = ((width / 2) as i32, (height / 2) as i32);
+        Turtle {
+            x,
+            y,
+            exact: (f64::from(x), f64::from(y)),
+            heading: 0,
+            pen_down: false,
+            color: PenColor::Palette(DEFAULT_COLOR),
+            pen_size: 1,
+            fill: None,
+        }
+    }
+
+    /// Moves the turtle relative to its heading, drawing a line if its pen is down.
+    ///
+    /// # Parameters
+    /// * `canvas`: The canvas to draw on.
+    /// * `offset`: The angle to move at, relative to the turtle's heading.
+    /// * `distance`: How far to move.
+    pub fn move_by(&mut self, canvas: &mut Canvas, offset: i32, distance: i32) {
+        let (x, y) = get_end_coordinates(self.x, self.y, self.heading + offset, distance);
+        let from = (self.x, self.y);
+        self.set_position(x, y);
+        if self.pen_down {
+            canvas.draw_line(from, (x, y), self.color.color(), self.pen_size);
+            canvas.end_frame(self.cursor());
+        }
+    }
+
+    /// Turns the turtle clockwise by an angle, or anticlockwise if it is negative.
+    pub fn turn(&mut self, angle: i32) {
+        // Both are below 360 once wrapped, so adding them cannot overflow
+        self.heading = (self.heading + angle.rem_euclid(360)).rem_euclid(360);
+    }
+
+    /// Points the turtle at a heading, in degrees clockwise from straight up.
+    pub fn set_heading(&mut self, heading: i32) {
+        self.heading = heading.rem_euclid(360);
+    }
+
+    /// Moves along a circular arc, turning by `angle` on the way, and draws it if the pen is
+    /// down.
+    ///
+    /// The centre of the circle is `radius` to the turtle's right when it turns right, or to
+    /// its left when the angle is negative. The turtle ends up facing along the circle.
+    ///
+    /// # Returns
+    /// An error if the radius is negative.
+    pub fn arc(&mut self, canvas: &mut Canvas, angle: i32, radius: i32) -> Result<(), ErrorKind> {
+        if radius < 0 {
+            return Err(ErrorKind::InvalidRadius(radius));
+        }
+        let heading = self.heading;
+        self.turn(angle);
+        if radius > 0 && angle != 0 {
+            let side = if angle > 0 { 1.0 } else { -1.0 };
+            let heading = f64::from(heading);
+            let (sin, cos) = heading.to_radians().sin_cos();
+            let radius = f64::from(radius);
+            let arc = Arc {
+                center: (
+                    self.exact.0 + side * radius * cos,
+                    self.exact.1 + side * radius * sin,
+                ),
+                radius,
+                start: heading - side * 90.0,
+                sweep: f64::from(angle),
+            };
+
+            if let Some(fill) = &mut self.fill {
+                fill.edges.push(Edge::Arc(arc));
+            }
+            self.exact = arc.end_point();
+            self.x = self.exact.0.round() as i32;
+            self.y = self.exact.1.round() as i32;
+            if self.pen_down {
+                canvas.draw_arc(arc, self.color.color(), self.pen_size);
+                canvas.end_frame(self.cursor());
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns where the turtle is, for animating it.
+    pub fn cursor(&self) -> Cursor {
+        Cursor {
+            position: (self.x, self.y),
+            heading: self.heading,
+        }
+    }
+
+    /// Moves the turtle to a point without drawing.
+    pub fn set_position(&mut self, x: i32, y: i32) {
+        if let Some(fill) = &mut self.fill {
+            if (x, y) != (self.x, self.y) {
+                fill.edges.push(Edge::Line((x, y)));
+            }
+        }
+        self.x = x;
+        self.y = y;
+        self.exact = (f64::from(x), f64::from(y));
+    }
+
+    /// Sets how wide the lines the turtle draws are.
+    ///
+    /// # Returns
+    /// An error if the size is not positive.
+    pub fn set_pen_size(&mut self, size: i32) -> Result<(), ErrorKind> {
+        match u32::try_from(size) {
+            Ok(size) if size > 0 => {
+                self.pen_size = size;
+                Ok(())
+            }
+            _ => Err(ErrorKind::InvalidPenSize(size)),
+        }
+    }
+
+    /// Starts recording a polygon to fill in the current pen colour, from where the turtle is.
+    ///
+    /// # Parameters
+    /// * `canvas`: The canvas the polygon will be filled on.
+    pub fn begin_fill(&mut self, canvas: &Canvas) {
+        self.fill = Some(Fill {
+            index: canvas.shapes().len(),
+            start: (self.x, self.y),
+            edges: Vec::new(),
+            color: self.color,
+        });
+    }
+
+    /// Fills the shape the turtle has moved around since `begin_fill`.
+    ///
+    /// # Returns
+    /// An error if no fill was begun.
+    pub fn end_fill(&mut self, canvas: &mut Canvas) -> Result<(), ErrorKind> {
+        let fill = self.fill.take().ok_or(ErrorKind::EndFillWithoutBegin)?;
+        canvas.fill_polygon(fill.index, fill.start, fill.edges, fill.color.color());
+        canvas.end_frame(self.cursor());
+        Ok(())
+    }
+}
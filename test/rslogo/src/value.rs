@@ -0,0 +1,37 @@
+use std::fmt;
+
+/// A value of a Logo program.
+///
+/// Every value is written as a word, such as `"50` or `"TRUE`, and words which are numbers or
+/// booleans are stored as those, so they can be type-checked where they are used.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Number(i32),
+    Bool(bool),
+    Word(String),
+}
+
+impl Value {
+    /// Reads a word as a number or a boolean if it is one.
+    pub fn from_word(word: &str) -> Self {
+        match word {
+            "TRUE" => Value::Bool(true),
+            "FALSE" => Value::Bool(false),
+            _ => match word.parse() {
+                Ok(number) => Value::Number(number),
+                Err(_) => Value::Word(word.to_string()),
+            },
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Number(number) => write!(f, "{}", number),
+            Value::Bool(true) => write!(f, "TRUE"),
+            Value::Bool(false) => write!(f, "FALSE"),
+            Value::Word(word) => write!(f, "{}", word),
+        }
+    }
+}
@@ -0,0 +1,1106 @@
+use crate::ast::{Operator, Query};
+use crate::canvas::Canvas;
+use crate::compiler::{Chunk, Instruction};
+use crate::error::{ErrorKind, LogoError};
+use crate::lexer::Position;
+use crate::turtle::{PenColor, Turtle};
+use crate::value::Value;
+use rustc_hash::FxHashMap;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// How deeply procedures can call each other by default.
+pub const DEFAULT_MAX_DEPTH: usize = 1000;
+
+/// The name of the turtle a machine starts with.
+pub const DEFAULT_TURTLE: &str = "main";
+
+/// A procedure whose parameter names have been evaluated.
+struct Defined {
+    params: Vec<String>,
+    body: Rc<Chunk>,
+}
+
+/// A `REPEAT` or `FOR` loop which is running.
+enum Loop {
+    Repeat {
+        count: i32,
+        done: i32,
+    },
+    For {
+        variable: String,
+        /// The value for the next iteration, or `None` once it would overflow.
+        next: Option<i32>,
+        end: i32,
+        step: i32,
+    },
+}
+
+/// Watches a program as a machine runs it, such as a debugger.
+pub trait Observer {
+    /// Whether to call `stop` at all, so a machine without an observer pays nothing for it.
+    const WATCHING: bool = true;
+
+    /// Called before each statement, and before each iteration of a loop.
+    ///
+    /// # Parameters
+    /// * `vm`: The machine, to inspect.
+    /// * `position`: Where the statement or loop is.
+    ///
+    /// # Returns
+    /// Whether to carry on, or else stop the program where it is.
+    fn stop(&mut self, vm: &Vm, position: Position) -> bool;
+}
+
+/// An optional observer, which watches nothing when it is `None`.
+impl<O: Observer> Observer for Option<O> {
+    const WATCHING: bool = O::WATCHING;
+
+    fn stop(&mut self, vm: &Vm, position: Position) -> bool {
+        self.as_mut()
+            .is_none_or(|observer| observer.stop(vm, position))
+    }
+}
+
+/// Two observers, where the second is not called once the first stops the program.
+impl<A: Observer, B: Observer> Observer for (A, B) {
+    const WATCHING: bool = A::WATCHING || B::WATCHING;
+
+    fn stop(&mut self, vm: &Vm, position: Position) -> bool {
+        self.0.stop(vm, position) && self.1.stop(vm, position)
+    }
+}
+
+/// The observer of a machine which nothing is watching.
+struct Unobserved;
+
+impl Observer for Unobserved {
+    const WATCHING: bool = false;
+
+    fn stop(&mut self, _: &Vm, _: Position) -> bool {
+        true
+    }
+}
+
+/// A procedure call which is running.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CallFrame<'v> {
+    pub procedure: &'v str,
+    /// Where it was called from.
+    pub position: Position,
+}
+
+/// Where to carry on from once a procedure call returns.
+struct Return {
+    chunk: Rc<Chunk>,
+    pc: usize,
+}
+
+/// A stack machine which runs compiled programs, drawing onto a canvas.
+///
+/// Procedure calls are kept on a stack of their own rather than the Rust stack, so deep
+/// recursion is only limited by `max_depth`.
+///
+/// Each procedure call gets a scope of its own holding its parameters. Variables are looked up
+/// from the innermost scope out, and `MAKE` sets a variable in the innermost scope which has it,
+/// creating it globally if none does.
+///
+/// There can be several turtles, each with a name, and commands move whichever one was last
+/// picked with `TELL` or `ASK`. They all draw onto the same canvas.
+pub struct Vm<'a> {
+    canvas: &'a mut Canvas,
+    /// The turtle commands move.
+    turtle: Turtle,
+    turtle_name: String,
+    /// The other turtles, by name.
+    turtles: HashMap<String, Turtle>,
+    /// The turtle to go back to at the end of each `ASK` which is running, innermost last.
+    asks: Vec<String>,
+    /// Values being computed, with where in the program each came from.
+    stack: Vec<(Value, Position)>,
+    /// The global scope, followed by the scope of each procedure being called.
+    scopes: Vec<FxHashMap<String, Value>>,
+    calls: Vec<Return>,
+    /// The loops being run, innermost last.
+    loops: Vec<Loop>,
+    procedures: FxHashMap<String, Defined>,
+    max_depth: usize,
+}
+
+impl<'a> Vm<'a> {
+    /// Creates a machine with a turtle in the middle of the canvas.
+    ///
+    /// # Parameters
+    /// * `canvas`: The canvas to draw on.
+    pub fn new(canvas: &'a mut Canvas) -> Self {
+        let (width, height) = canvas.get_dimensions();
+        let turtle = Turtle::new(width, height);
+        // The first frame of an animation shows the turtle before it has drawn anything
+        canvas.end_frame(turtle.cursor());
+        Vm {
+            canvas,
+            turtle,
+            turtle_name: DEFAULT_TURTLE.to_string(),
+            turtles: HashMap::new(),
+            asks: Vec::new(),
+            stack: Vec::new(),
+            scopes: vec![FxHashMap::default()],
+            calls: Vec::new(),
+            loops: Vec::new(),
+            procedures: FxHashMap::default(),
+            max_depth: DEFAULT_MAX_DEPTH,
+        }
+    }
+
+    /// Returns the canvas the machine draws on.
+    pub fn canvas(&self) -> &Canvas {
+        self.canvas
+    }
+
+    /// Returns the global variables.
+    pub fn variables(&self) -> &FxHashMap<String, Value> {
+        &self.scopes[0]
+    }
+
+    /// Returns the value of a variable, looked up from the innermost scope out.
+    pub fn variable(&self, name: &str) -> Option<&Value> {
+        self.scopes.iter().rev().find_map(|scope| scope.get(name))
+    }
+
+    /// Returns the turtle's answer to a query.
+    fn query(&self, query: Query) -> i32 {
+        match query {
+            Query::XCor => self.turtle.x,
+            Query::YCor => self.turtle.y,
+            Query::Heading => self.turtle.heading,
+            Query::Color => self.turtle.color.index(),
+            // Outside of any REPEAT, as in UCBLogo
+            Query::RepCount => self
+                .loops
+                .iter()
+                .rev()
+                .find_map(|l| match l {
+                    Loop::Repeat { done, .. } => Some(*done),
+                    Loop::For { .. } => None,
+                })
+                .unwrap_or(-1),
+        }
+    }
+
+    /// Returns the turtle commands move.
+    pub fn turtle(&self) -> &Turtle {
+        &self.turtle
+    }
+
+    /// Returns the name of the turtle commands move.
+    pub fn turtle_name(&self) -> &str {
+        &self.turtle_name
+    }
+
+    /// Returns how many procedure calls are running.
+    pub fn depth(&self) -> usize {
+        self.calls.len()
+    }
+
+    /// Returns the procedure calls which are running, innermost last.
+    pub fn call_stack(&self) -> Vec<CallFrame<'_>> {
+        self.calls
+            .iter()
+            .map(|caller| {
+                // A call returns to just after its `Call` instruction
+                let call = caller.pc - 1;
+                let Instruction::Call { name, .. } = &caller.chunk.code[call] else {
+                    unreachable!("a call returns to after a Call instruction");
+                };
+                CallFrame {
+                    procedure: name,
+                    position: caller.chunk.positions[call],
+                }
+            })
+            .collect()
+    }
+
+    /// Sets how deeply procedures can call each other before the program is stopped.
+    ///
+    /// # Parameters
+    /// * `max_depth`: The most procedure calls which can be running at once.
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// Runs a compiled program.
+    ///
+    /// # Parameters
+    /// * `chunk`: The program from `compiler::compile`.
+    ///
+    /// # Returns
+    /// The first error the program hits, including where it happened.
+    pub fn run(&mut self, chunk: &Rc<Chunk>) -> Result<(), LogoError> {
+        self.run_observed(chunk, &mut Unobserved)
+    }
+
+    /// Runs a compiled program, letting an observer watch it and stop it.
+    ///
+    /// # Parameters
+    /// * `chunk`: The program from `compiler::compile`.
+    /// * `observer`: The observer, which is called before each statement.
+    ///
+    /// # Returns
+    /// The first error the program hits, including where it happened.
+    pub fn run_observed<O: Observer>(
+        &mut self,
+        chunk: &Rc<Chunk>,
+        observer: &mut O,
+    ) -> Result<(), LogoError> {
+        let mut chunk = Rc::clone(chunk);
+        let mut pc = 0;
+
+        let result = loop {
+            let Some(instruction) = chunk.code.get(pc) else {
+                // The end of a procedure returns to its caller, and the end of the program stops
+                match self.calls.pop() {
+                    Some(caller) => {
+                        self.scopes.pop();
+                        chunk = caller.chunk;
+                        pc = caller.pc;
+                        continue;
+                    }
+                    None => break Ok(()),
+                }
+            };
+            let position = chunk.positions[pc];
+            if O::WATCHING && chunk.stops[pc] && !observer.stop(self, position) {
+                break Ok(());
+            }
+            pc += 1;
+
+            match instruction {
+                Instruction::Call { name, arguments } => {
+                    match self.call(name, *arguments, position) {
+                        Ok(body) => {
+                            let caller = std::mem::replace(&mut chunk, body);
+                            self.calls.push(Return { chunk: caller, pc });
+                            pc = 0;
+                        }
+                        Err(e) => break Err(e),
+                    }
+                }
+                other => match self.execute(other, position) {
+                    Ok(Some(target)) => pc = target,
+                    Ok(None) => {}
+                    Err(e) => break Err(e),
+                },
+            }
+        };
+
+        // Leave the machine ready to run another program after an error
+        self.stack.clear();
+        self.scopes.truncate(1);
+        self.calls.clear();
+        self.loops.clear();
+        if let Some(name) = self.asks.first().cloned() {
+            self.switch(&name).expect("the turtle before an ASK exists");
+        }
+        self.asks.clear();
+        result
+    }
+
+    /// Runs an instruction other than a call.
+    ///
+    /// # Returns
+    /// The instruction to jump to, if it jumps.
+    fn execute(
+        &mut self,
+        instruction: &Instruction,
+        position: Position,
+    ) -> Result<Option<usize>, LogoError> {
+        match instruction {
+            Instruction::Push(value) => self.stack.push((value.clone(), position)),
+            Instruction::Load(name) => {
+                let value = self.variable(name).cloned().ok_or_else(|| {
+                    LogoError::new(ErrorKind::UnknownVariable(name.clone()), position)
+                })?;
+                self.stack.push((value, position));
+            }
+            Instruction::Query(query) => {
+                let value = Value::Number(self.query(*query));
+                self.stack.push((value, position));
+            }
+            Instruction::Binary(operator) => {
+                let (right, right_position) = self.pop();
+                let (left, left_position) = self.pop();
+                let value = apply(
+                    *operator,
+                    (&left, left_position),
+                    (&right, right_position),
+                    position,
+                )?;
+                self.stack.push((value, position));
+            }
+            Instruction::PenUp => self.turtle.pen_down = false,
+            Instruction::PenDown => self.turtle.pen_down = true,
+            Instruction::Move(direction) => {
+                let distance = self.pop_number()?;
+                self.turtle
+                    .move_by(self.canvas, direction.offset(), distance);
+            }
+            Instruction::Turn => {
+                let angle = self.pop_number()?;
+                self.turtle.turn(angle);
+            }
+            Instruction::SetHeading => {
+                let heading = self.pop_number()?;
+                self.turtle.set_heading(heading);
+            }
+            Instruction::SetX => {
+                let x = self.pop_number()?;
+                self.turtle.set_position(x, self.turtle.y);
+            }
+            Instruction::SetY => {
+                let y = self.pop_number()?;
+                self.turtle.set_position(self.turtle.x, y);
+            }
+            Instruction::SetPenColor { rgb } => {
+                self.turtle.color = self.pop_color(*rgb, position)?
+            }
+            Instruction::SetBackground { rgb } => {
+                let color = self.pop_color(*rgb, position)?;
+                self.canvas.set_background(color.color());
+            }
+            Instruction::SetPenSize => {
+                let (size, size_position) = self.pop();
+                self.turtle
+                    .set_pen_size(number(&size, size_position)?)
+                    .map_err(|kind| LogoError::new(kind, size_position))?;
+            }
+            Instruction::Arc => {
+                let (radius, radius_position) = self.pop();
+                let radius = number(&radius, radius_position)?;
+                let angle = self.pop_number()?;
+                self.turtle
+                    .arc(self.canvas, angle, radius)
+                    .map_err(|kind| match kind {
+                        ErrorKind::InvalidRadius(_) => LogoError::new(kind, radius_position),
+                        _ => LogoError::new(kind, position),
+                    })?;
+            }
+            Instruction::BeginFill => self.turtle.begin_fill(self.canvas),
+            Instruction::EndFill => self
+                .turtle
+                .end_fill(self.canvas)
+                .map_err(|kind| LogoError::new(kind, position))?,
+            Instruction::NewTurtle => {
+                let (name, name_position) = self.pop();
+                let name = name.to_string();
+                if name == self.turtle_name || self.turtles.contains_key(&name) {
+                    return Err(LogoError::new(ErrorKind::TurtleExists(name), name_position));
+                }
+                let (width, height) = self.canvas.get_dimensions();
+                self.turtles.insert(name, Turtle::new(width, height));
+            }
+            Instruction::Tell => {
+                let (name, name_position) = self.pop();
+                self.switch(&name.to_string())
+                    .map_err(|kind| LogoError::new(kind, name_position))?;
+            }
+            Instruction::AskStart => {
+                let (name, name_position) = self.pop();
+                let previous = self
+                    .switch(&name.to_string())
+                    .map_err(|kind| LogoError::new(kind, name_position))?;
+                self.asks.push(previous);
+            }
+            Instruction::AskEnd => {
+                let name = self.asks.pop().expect("AskStart comes before AskEnd");
+                self.switch(&name).expect("the turtle before an ASK exists");
+            }
+            Instruction::Make => {
+                let (value, _) = self.pop();
+                let (name, _) = self.pop();
+                self.assign(&name.to_string(), value);
+            }
+            Instruction::AddAssign => {
+                let value = self.pop_number()?;
+                let (name, name_position) = self.pop();
+                self.add_to(&name.to_string(), value, name_position)?;
+            }
+            Instruction::Store(name) => {
+                let (value, _) = self.pop();
+                self.assign(name, value);
+            }
+            Instruction::AddTo(name) => {
+                let value = self.pop_number()?;
+                self.add_to(name, value, position)?;
+            }
+            Instruction::Define(procedure) => {
+                let start = self.stack.len() - procedure.params;
+                let params = self
+                    .stack
+                    .drain(start..)
+                    .map(|(name, _)| name.to_string())
+                    .collect();
+                self.procedures.insert(
+                    procedure.name.clone(),
+                    Defined {
+                        params,
+                        body: Rc::clone(&procedure.body),
+                    },
+                );
+            }
+            Instruction::Jump(target) => return Ok(Some(*target)),
+            Instruction::JumpUnless(target) => {
+                if !self.pop_bool()? {
+                    return Ok(Some(*target));
+                }
+            }
+            Instruction::RepeatStart => {
+                let count = self.pop_number()?;
+                self.loops.push(Loop::Repeat { count, done: 0 });
+            }
+            Instruction::RepeatNext(exit) => {
+                let Some(Loop::Repeat { count, done }) = self.loops.last_mut() else {
+                    unreachable!("RepeatStart comes before RepeatNext");
+                };
+                if *done >= *count {
+                    self.loops.pop();
+                    return Ok(Some(*exit));
+                }
+                *done += 1;
+            }
+            Instruction::ForStart { step } => {
+                let step = if *step {
+                    let (step, step_position) = self.pop();
+                    match number(&step, step_position)? {
+                        0 => return Err(LogoError::new(ErrorKind::ZeroStep, step_position)),
+                        step => Some(step),
+                    }
+                } else {
+                    None
+                };
+                let end = self.pop_number()?;
+                let start = self.pop_number()?;
+                let (variable, _) = self.pop();
+                self.loops.push(Loop::For {
+                    variable: variable.to_string(),
+                    next: Some(start),
+                    end,
+                    // Count down to an end below the start unless told otherwise
+                    step: step.unwrap_or(if end < start { -1 } else { 1 }),
+                });
+            }
+            Instruction::ForNext(exit) => {
+                let Some(Loop::For {
+                    variable,
+                    next,
+                    end,
+                    step,
+                }) = self.loops.last_mut()
+                else {
+                    unreachable!("ForStart comes before ForNext");
+                };
+                let value = match *next {
+                    Some(value) if (*step > 0 && value <= *end) || (*step < 0 && value >= *end) => {
+                        value
+                    }
+                    _ => {
+                        self.loops.pop();
+                        return Ok(Some(*exit));
+                    }
+                };
+                *next = value.checked_add(*step);
+                let variable = variable.clone();
+                self.assign(&variable, Value::Number(value));
+            }
+            Instruction::Call { .. } => unreachable!("run handles calls"),
+        }
+        Ok(None)
+    }
+
+    /// Starts a procedure call, binding its arguments in a new scope.
+    ///
+    /// # Returns
+    /// The body of the procedure, to run next.
+    fn call(
+        &mut self,
+        name: &str,
+        arguments: usize,
+        position: Position,
+    ) -> Result<Rc<Chunk>, LogoError> {
+        let start = self.stack.len() - arguments;
+        let defined = self.procedures.get(name).ok_or_else(|| {
+            LogoError::new(ErrorKind::UnknownProcedure(name.to_string()), position)
+        })?;
+        let expected = defined.params.len();
+        if arguments < expected {
+            return Err(LogoError::new(
+                ErrorKind::NotEnoughArguments {
+                    command: name.to_string(),
+                    expected,
+                    given: arguments,
+                },
+                position,
+            ));
+        }
+        if arguments > expected {
+            return Err(LogoError::new(
+                ErrorKind::TooManyArguments {
+                    command: name.to_string(),
+                    expected,
+                    given: arguments,
+                },
+                self.stack[start + expected].1,
+            ));
+        }
+        if self.calls.len() >= self.max_depth {
+            return Err(LogoError::new(
+                ErrorKind::TooDeep {
+                    procedure: name.to_string(),
+                    max_depth: self.max_depth,
+                },
+                position,
+            ));
+        }
+
+        let scope = defined
+            .params
+            .iter()
+            .cloned()
+            .zip(self.stack.drain(start..).map(|(value, _)| value))
+            .collect();
+        let body = Rc::clone(&defined.body);
+        self.scopes.push(scope);
+        Ok(body)
+    }
+
+    /// Sets a variable in the innermost scope which has it, or globally if none does.
+    fn assign(&mut self, name: &str, value: Value) {
+        match self
+            .scopes
+            .iter_mut()
+            .rev()
+            .find_map(|scope| scope.get_mut(name))
+        {
+            Some(variable) => *variable = value,
+            None => {
+                self.scopes[0].insert(name.to_string(), value);
+            }
+        }
+    }
+
+    /// Adds a number to a variable, for `ADDASSIGN`.
+    ///
+    /// # Parameters
+    /// * `name`: The name of the variable.
+    /// * `value`: The number to add.
+    /// * `name_position`: Where the name is, for errors.
+    fn add_to(&mut self, name: &str, value: i32, name_position: Position) -> Result<(), LogoError> {
+        let current = self
+            .scopes
+            .iter_mut()
+            .rev()
+            .find_map(|scope| scope.get_mut(name))
+            .ok_or_else(|| {
+                LogoError::new(ErrorKind::UnknownVariable(name.to_string()), name_position)
+            })?;
+        let sum = number(current, name_position)?
+            .checked_add(value)
+            .ok_or_else(|| LogoError::new(ErrorKind::Overflow, name_position))?;
+        *current = Value::Number(sum);
+        Ok(())
+    }
+
+    fn pop(&mut self) -> (Value, Position) {
+        self.stack.pop().expect("compiler pushes every operand")
+    }
+
+    /// Pops a colour, given by `rgb` as either red, green and blue or an index into the palette.
+    fn pop_color(&mut self, rgb: bool, position: Position) -> Result<PenColor, LogoError> {
+        if !rgb {
+            let index = self.pop_number()?;
+            return PenColor::palette(index).map_err(|kind| LogoError::new(kind, position));
+        }
+
+        let mut components = [0; 3];
+        let mut positions = [position; 3];
+        for i in (0..3).rev() {
+            let (value, value_position) = self.pop();
+            components[i] = number(&value, value_position)?;
+            positions[i] = value_position;
+        }
+        let [red, green, blue] = components;
+        PenColor::rgb(red, green, blue).map_err(|kind| {
+            // Point at the component which is out of range
+            let index = components
+                .iter()
+                .position(|c| !(0..=255).contains(c))
+                .unwrap_or(0);
+            LogoError::new(kind, positions[index])
+        })
+    }
+
+    /// Makes the turtle with a name the one commands move.
+    ///
+    /// # Returns
+    /// The name of the turtle commands moved before, or an error if no turtle has the name.
+    fn switch(&mut self, name: &str) -> Result<String, ErrorKind> {
+        if name != self.turtle_name {
+            let turtle = self
+                .turtles
+                .remove(name)
+                .ok_or_else(|| ErrorKind::UnknownTurtle(name.to_string()))?;
+            let previous = std::mem::replace(&mut self.turtle, turtle);
+            self.turtles.insert(self.turtle_name.clone(), previous);
+        }
+        Ok(std::mem::replace(&mut self.turtle_name, name.to_string()))
+    }
+
+    fn pop_number(&mut self) -> Result<i32, LogoError> {
+        let (value, position) = self.pop();
+        number(&value, position)
+    }
+
+    fn pop_bool(&mut self) -> Result<bool, LogoError> {
+        let (value, position) = self.pop();
+        boolean(&value, position)
+    }
+}
+
+/// Checks a value is a number.
+///
+/// # Parameters
+/// * `value`: The value to check.
+/// * `position`: Where the value came from, for the error.
+///
+/// # Returns
+/// The number, or a `WrongType` error.
+fn number(value: &Value, position: Position) -> Result<i32, LogoError> {
+    match value {
+        Value::Number(number) => Ok(*number),
+        other => Err(LogoError::new(
+            ErrorKind::WrongType {
+                expected: "a number",
+                found: other.to_string(),
+            },
+            position,
+        )),
+    }
+}
+
+/// Checks a value is a boolean.
+///
+/// # Parameters
+/// * `value`: The value to check.
+/// * `position`: Where the value came from, for the error.
+///
+/// # Returns
+/// The boolean, or a `WrongType` error.
+fn boolean(value: &Value, position: Position) -> Result<bool, LogoError> {
+    match value {
+        Value::Bool(boolean) => Ok(*boolean),
+        other => Err(LogoError::new(
+            ErrorKind::WrongType {
+                expected: "TRUE or FALSE",
+                found: other.to_string(),
+            },
+            position,
+        )),
+    }
+}
+
+/// Applies an operator to two values, checking they have the types it takes.
+///
+/// # Parameters
+/// * `operator`: The operator to apply.
+/// * `left`, `right`: The operands, with where they came from.
+/// * `position`: Where the operator is, for errors about the result.
+///
+/// # Returns
+/// The result, or an error if an operand has the wrong type or the result cannot be computed.
+fn apply(
+    operator: Operator,
+    left: (&Value, Position),
+    right: (&Value, Position),
+    position: Position,
+) -> Result<Value, LogoError> {
+    match operator {
+        Operator::Add | Operator::Subtract | Operator::Multiply | Operator::Divide => {
+            let a = number(left.0, left.1)?;
+            let b = number(right.0, right.1)?;
+            let result = match operator {
+                Operator::Add => a.checked_add(b),
+                Operator::Subtract => a.checked_sub(b),
+                Operator::Multiply => a.checked_mul(b),
+                _ => {
+                    if b == 0 {
+                        return Err(LogoError::new(ErrorKind::DivisionByZero, position));
+                    }
+                    a.checked_div(b)
+                }
+            };
+            result
+                .map(Value::Number)
+                .ok_or_else(|| LogoError::new(ErrorKind::Overflow, position))
+        }
+        Operator::Lt | Operator::Gt => {
+            let a = number(left.0, left.1)?;
+            let b = number(right.0, right.1)?;
+            Ok(Value::Bool(if operator == Operator::Lt {
+                a < b
+            } else {
+                a > b
+            }))
+        }
+        // Values of different types are never equal, so "1 does not equal "TRUE
+        Operator::Eq => Ok(Value::Bool(left.0 == right.0)),
+        Operator::Ne => Ok(Value::Bool(left.0 != right.0)),
+        Operator::And | Operator::Or => {
+            let a = boolean(left.0, left.1)?;
+            let b = boolean(right.0, right.1)?;
+            Ok(Value::Bool(if operator == Operator::And {
+                a && b
+            } else {
+                a || b
+            }))
+        }
+    }
+}
+
+// ===================== TESTS ============================
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canvas::{Edge, Shape};
+    use crate::compiler::compile;
+    use crate::lexer::tokenize;
+    use crate::parser::parse;
+    use unsvg::Color;
+
+    /// What running a program did.
+    struct Run {
+        result: Result<(), LogoError>,
+        globals: FxHashMap<String, Value>,
+        turtle_name: String,
+        shapes: Vec<Shape>,
+        background: Color,
+    }
+
+    /// Runs a program on a 100 by 100 canvas, with the default maximum depth.
+    fn run(program: &str) -> Run {
+        run_with_depth(program, DEFAULT_MAX_DEPTH)
+    }
+
+    /// Runs a program on a 100 by 100 canvas.
+    fn run_with_depth(program: &str, max_depth: usize) -> Run {
+        let mut canvas = Canvas::new(100, 100);
+        let mut vm = Vm::new(&mut canvas).with_max_depth(max_depth);
+        let result = vm.run(&Rc::new(compile(&parse(&tokenize(program)).unwrap())));
+        let globals = vm.scopes.remove(0);
+        let turtle_name = vm.turtle_name.clone();
+        Run {
+            result,
+            globals,
+            turtle_name,
+            shapes: canvas.shapes().to_vec(),
+            background: canvas.background(),
+        }
+    }
+
+    // 1. Test parameters are local to a call, while MAKE reaches the scope with the variable
+    #[test]
+    fn test_procedure_scope() {
+        let program =
+            "MAKE \"n \"1\nTO Set \"n\nMAKE \"n + :n \"1\nMAKE \"seen :n\nEND\nSet \"10\n";
+        let globals = run(program).globals;
+        assert_eq!(globals.get("n"), Some(&Value::Number(1)));
+        assert_eq!(globals.get("seen"), Some(&Value::Number(11)));
+    }
+
+    // 2. Test recursion, and that recursing too deeply is an error rather than a crash
+    #[test]
+    fn test_recursion() {
+        let program = "MAKE \"sum \"0\nTO Sum \"n\nIF GT :n \"0 [\nADDASSIGN \"sum :n\nSum - :n \"1\n]\nEND\nSum \"10\n";
+        let run_11 = run_with_depth(program, 11);
+        assert_eq!(run_11.result, Ok(()));
+        assert_eq!(run_11.globals.get("sum"), Some(&Value::Number(55)));
+
+        let error = run_with_depth(program, 10).result.unwrap_err();
+        assert_eq!(error.position.line, 5);
+        assert!(matches!(
+            error.kind,
+            ErrorKind::TooDeep { max_depth: 10, .. }
+        ));
+
+        // Calls do not use the Rust stack, so deep recursion only needs a high enough limit
+        let deep = program.replace("Sum \"10\n", "Sum \"1500\n");
+        assert_eq!(run_with_depth(&deep, 1501).result, Ok(()));
+    }
+
+    // 3. Test REPEAT with REPCOUNT, FOR counting up, down and by a step, and IF with ELSE
+    #[test]
+    fn test_loops_and_else() {
+        let program = "MAKE \"counts \"0
+REPEAT \"3 [
+  REPEAT \"2 [ ADDASSIGN \"counts REPCOUNT ]
+  ADDASSIGN \"counts * \"10 REPCOUNT
+]
+MAKE \"up \"0
+FOR \"i \"1 \"10 \"3 [ ADDASSIGN \"up :i ]
+MAKE \"down \"0
+FOR \"j \"3 \"1 [ MAKE \"down + * :down \"10 :j ]
+IF EQ :up \"22 [ MAKE \"branch \"then ] ELSE [ MAKE \"branch \"else ]
+IF EQ :up \"0 [
+  MAKE \"other \"then
+]
+ELSE [
+  MAKE \"other \"else
+]
+";
+        let globals = run(program).globals;
+        assert_eq!(globals.get("counts"), Some(&Value::Number(69)));
+        assert_eq!(globals.get("up"), Some(&Value::Number(22)));
+        assert_eq!(globals.get("i"), Some(&Value::Number(10)));
+        assert_eq!(globals.get("down"), Some(&Value::Number(321)));
+        assert_eq!(
+            globals.get("branch"),
+            Some(&Value::Word("then".to_string()))
+        );
+        assert_eq!(globals.get("other"), Some(&Value::Word("else".to_string())));
+
+        let error = run("FOR \"i \"1 \"5 \"0 [ ]").result.unwrap_err();
+        assert_eq!(error.kind, ErrorKind::ZeroStep);
+        assert_eq!(error.position.column, 14);
+    }
+
+    // 4. Test fills, pen sizes, RGB colours and backgrounds, and their errors
+    #[test]
+    fn test_fills_and_colors() {
+        let program = "SETBACKGROUND \"255 \"255 \"255
+SETPENCOLOR \"10 \"20 \"30
+SETPENSIZE \"3
+PENDOWN
+BEGINFILL
+FORWARD \"10
+RIGHT \"10
+SETX \"50
+ENDFILL
+MAKE \"color COLOR
+";
+        let drawn = run(program);
+        assert_eq!(drawn.globals.get("color"), Some(&Value::Number(-1)));
+        assert_eq!(drawn.background, Color::white());
+
+        let color = Color::new_rgb(10, 20, 30);
+        assert_eq!(
+            drawn.shapes,
+            [
+                Shape::Polygon {
+                    start: (50, 50),
+                    edges: vec![
+                        Edge::Line((50, 40)),
+                        Edge::Line((60, 40)),
+                        Edge::Line((50, 40))
+                    ],
+                    color
+                },
+                Shape::Line {
+                    from: (50, 50),
+                    to: (50, 40),
+                    color,
+                    width: 3
+                },
+                Shape::Line {
+                    from: (50, 40),
+                    to: (60, 40),
+                    color,
+                    width: 3
+                },
+            ]
+        );
+
+        for (program, column) in [
+            ("SETPENCOLOR \"1 \"256 \"3\n", 16),
+            ("SETPENSIZE \"0\n", 12),
+            ("ENDFILL\n", 1),
+        ] {
+            let error = run(program).result.unwrap_err();
+            assert_eq!(error.position.column, column, "{}", program);
+        }
+    }
+
+    // 5. Test ARC and CIRCLE leave the turtle where the curve ends, facing along it
+    #[test]
+    fn test_arcs() {
+        for (program, x, y, heading) in [
+            ("ARC \"90 \"50\n", 100, 0, 90),
+            ("ARC \"-90 \"10\n", 40, 40, 270),
+            ("SETHEADING \"45\nARC \"180 \"10\n", 64, 64, 225),
+            ("CIRCLE \"20\n", 50, 50, 0),
+            ("ARC \"30 \"0\n", 50, 50, 30),
+        ] {
+            let globals = run(&format!(
+                "{}MAKE \"x XCOR\nMAKE \"y YCOR\nMAKE \"heading HEADING\n",
+                program
+            ))
+            .globals;
+            assert_eq!(
+                (&globals["x"], &globals["y"], &globals["heading"]),
+                (
+                    &Value::Number(x),
+                    &Value::Number(y),
+                    &Value::Number(heading)
+                ),
+                "{}",
+                program
+            );
+        }
+
+        let error = run("CIRCLE \"-5\n").result.unwrap_err();
+        assert!(matches!(error.kind, ErrorKind::InvalidRadius(-5)));
+        assert_eq!(error.position.column, 8);
+    }
+
+    // 6. Test each turtle keeps its own state, ASK goes back to the turtle before, and errors
+    #[test]
+    fn test_turtles() {
+        let program = "NEWTURTLE \"a
+NEWTURTLE \"b
+SETPENCOLOR \"1
+ASK \"a [
+  PENDOWN
+  FORWARD \"10
+  ASK \"b [
+    TURN \"90
+  ]
+]
+FORWARD \"5
+MAKE \"main YCOR
+TELL \"b
+MAKE \"b XCOR
+MAKE \"heading HEADING
+TELL \"a
+MAKE \"a YCOR
+MAKE \"color COLOR
+";
+        let turtles = run(program);
+        // The main turtle's pen is up, so only turtle a drew
+        assert_eq!(turtles.shapes.len(), 1);
+        assert_eq!(turtles.turtle_name, "a");
+        assert_eq!(turtles.globals["main"], Value::Number(45));
+        assert_eq!(turtles.globals["b"], Value::Number(50));
+        assert_eq!(turtles.globals["heading"], Value::Number(90));
+        assert_eq!(turtles.globals["a"], Value::Number(40));
+        assert_eq!(turtles.globals["color"], Value::Number(7));
+
+        // A failure inside an ASK still goes back to the turtle before it
+        let failed = run("NEWTURTLE \"a\nTELL \"a\nASK \"main [\nFORWARD \"TRUE\n]\n");
+        assert!(failed.result.is_err());
+        assert_eq!(failed.turtle_name, "a");
+
+        for (program, column) in [
+            ("NEWTURTLE \"main\n", 11),
+            ("TELL \"c\n", 6),
+            ("ASK \"c [\n]\n", 5),
+        ] {
+            let error = run(program).result.unwrap_err();
+            assert_eq!(error.position.column, column, "{}", program);
+        }
+    }
+
+    // 7. Test nested prefix expressions of every operator, and their errors
+    #[test]
+    fn test_operators() {
+        let program = "MAKE \"a \"4
+MAKE \"lt LT + \"3 \"4 * \"6 \"2
+MAKE \"div - / XCOR :a \"30
+MAKE \"and AND EQ \"05 \"5 NE \"a \"b
+MAKE \"or OR GT :a \"4 EQ \"1 \"TRUE
+";
+        let globals = run(program).globals;
+        assert_eq!(globals["lt"], Value::Bool(true));
+        assert_eq!(globals["div"], Value::Number(-18));
+        assert_eq!(globals["and"], Value::Bool(true));
+        assert_eq!(globals["or"], Value::Bool(false));
+
+        let error = run("MAKE \"x + \"1 \"TRUE\n").result.unwrap_err();
+        assert_eq!(error.position.column, 14);
+        assert!(matches!(error.kind, ErrorKind::WrongType { .. }));
+        assert!(matches!(
+            run("MAKE \"x AND \"TRUE \"1\n").result.unwrap_err().kind,
+            ErrorKind::WrongType { .. }
+        ));
+        assert_eq!(
+            run("MAKE \"x / \"1 - \"4 \"4\n").result.unwrap_err().kind,
+            ErrorKind::DivisionByZero
+        );
+        assert_eq!(
+            run("MAKE \"x :b\n").result.unwrap_err().kind,
+            ErrorKind::UnknownVariable("b".to_string())
+        );
+    }
+
+    // 8. Test headings stay between 0 and 359, and turning too far overflows
+    #[test]
+    fn test_headings() {
+        let program = "TURN \"-90
+MAKE \"left HEADING
+TURN \"450
+MAKE \"turned HEADING
+SETHEADING \"-30
+MAKE \"set HEADING
+SETHEADING \"720
+MAKE \"wrapped HEADING
+";
+        let globals = run(program).globals;
+        assert_eq!(globals["left"], Value::Number(270));
+        assert_eq!(globals["turned"], Value::Number(0));
+        assert_eq!(globals["set"], Value::Number(330));
+        assert_eq!(globals["wrapped"], Value::Number(0));
+
+        // Turns too large to add to the heading still wrap around, since 2147483647 is 127
+        // more than a whole number of turns
+        let globals = run("TURN \"2147483647\nTURN \"2147483647\nMAKE \"big HEADING\n").globals;
+        assert_eq!(globals["big"], Value::Number(254));
+        let globals = run("SETHEADING \"10\nARC \"2147483647 \"5\nMAKE \"arc HEADING\n").globals;
+        assert_eq!(globals["arc"], Value::Number(137));
+    }
+
+    // 9. Test chained arcs going all the way around end back where they started
+    #[test]
+    fn test_chained_arcs() {
+        for program in [
+            "SETHEADING \"10\nREPEAT \"36 [ ARC \"10 \"37 ]\n",
+            "SETHEADING \"10\nREPEAT \"12 [ ARC \"-30 \"23 ]\n",
+            "SETHEADING \"10\nREPEAT \"2 [\nARC \"90 \"21\nARC \"90 \"33\n]\n",
+        ] {
+            let drawn = run(&format!(
+                "PENDOWN\n{}MAKE \"x XCOR\nMAKE \"y YCOR\nMAKE \"heading HEADING\n",
+                program
+            ));
+            assert_eq!(
+                (
+                    &drawn.globals["x"],
+                    &drawn.globals["y"],
+                    &drawn.globals["heading"]
+                ),
+                (&Value::Number(50), &Value::Number(50), &Value::Number(10)),
+                "{}",
+                program
+            );
+
+            // Each arc starts exactly where the one before it ended
+            let arcs: Vec<_> = drawn
+                .shapes
+                .iter()
+                .map(|shape| match shape {
+                    Shape::Arc { arc, .. } => *arc,
+                    other => panic!("expected an arc, found {:?}", other),
+                })
+                .collect();
+            for pair in arcs.windows(2) {
+                let (end, start) = (pair[0].end_point(), pair[1].start_point());
+                assert!((end.0 - start.0).abs() < 1e-9 && (end.1 - start.1).abs() < 1e-9);
+            }
+        }
+    }
+}
@@ -0,0 +1,224 @@
+//! Runs every program in `logo_examples` and compares what it does with `tests/expected`.
+//!
+//! A program which succeeds must draw the same SVG as `<name>.svg`, after normalizing. A
+//! program named `*_err.lg` must fail, with the error codes listed in `<name>.err`, one per line.
+//!
+//! Run `cargo test --test golden -- --bless` to write the expectations from the current output.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// The size of the image every example is drawn on.
+const SIZE: &str = "400";
+
+/// What running an example produced.
+enum Outcome {
+    Drawn(String),
+    Failed(Vec<String>),
+}
+
+fn main() {
+    check_normalize();
+    let bless = std::env::args().any(|arg| arg == "--bless");
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let expected_dir = root.join("tests/expected");
+    let output_dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("golden");
+    fs::create_dir_all(&expected_dir).unwrap();
+    fs::create_dir_all(&output_dir).unwrap();
+
+    let mut paths: Vec<_> = fs::read_dir(root.join("logo_examples"))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "lg"))
+        .collect();
+    paths.sort();
+    assert!(!paths.is_empty(), "no examples found");
+
+    let mut failures = Vec::new();
+    for path in &paths {
+        let name = path.file_stem().unwrap().to_string_lossy().to_string();
+        let outcome = run(path, &output_dir.join(format!("{}.svg", name)));
+        let result = if bless {
+            write_expected(&expected_dir, &name, &outcome);
+            Ok(())
+        } else {
+            check(&expected_dir, &name, &outcome)
+        };
+        match result {
+            Ok(()) => println!("test {} ... ok", name),
+            Err(e) => {
+                println!("test {} ... FAILED", name);
+                failures.push(format!("{}: {}", name, e));
+            }
+        }
+    }
+
+    if bless {
+        println!("\nblessed {} examples", paths.len());
+    } else if !failures.is_empty() {
+        eprintln!("\n{}", failures.join("\n"));
+        eprintln!("\nrun `cargo test --test golden -- --bless` if the changes are intended");
+        std::process::exit(1);
+    } else {
+        println!("\ntest result: ok. {} examples passed", paths.len());
+    }
+}
+
+/// Runs an example, drawing it to `image`.
+fn run(program: &Path, image: &PathBuf) -> Outcome {
+    let output = Command::new(env!("CARGO_BIN_EXE_rslogo"))
+        .arg(program)
+        .arg(image)
+        .args([SIZE, SIZE])
+        .output()
+        .unwrap();
+
+    if output.status.success() {
+        Outcome::Drawn(normalize(&fs::read_to_string(image).unwrap()))
+    } else {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        Outcome::Failed(error_codes(&stderr))
+    }
+}
+
+/// Normalizes an SVG so formatting and rounding differences do not matter.
+///
+/// Blank lines and indentation are dropped, and decimal numbers are rounded to 3 decimal
+/// places wherever they appear, including inside quoted attributes such as `x1="12.3456"`.
+fn normalize(svg: &str) -> String {
+    svg.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(round_numbers)
+        .collect::<Vec<_>>()
+        .join("\n")
+        + "\n"
+}
+
+/// Rounds every decimal number in a line, leaving the rest of the line as it is.
+///
+/// A number must not follow a letter or digit, so names such as `w3.org` are left alone.
+fn round_numbers(line: &str) -> String {
+    let chars: Vec<char> = line.chars().collect();
+    let mut result = String::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let starts_word = i == 0 || !chars[i - 1].is_ascii_alphanumeric();
+        let sign = usize::from(chars[i] == '-');
+        if !starts_word || !chars.get(i + sign).is_some_and(char::is_ascii_digit) {
+            result.push(chars[i]);
+            i += 1;
+            continue;
+        }
+
+        let mut end = i + sign;
+        while chars.get(end).is_some_and(char::is_ascii_digit) {
+            end += 1;
+        }
+        if chars.get(end) == Some(&'.') && chars.get(end + 1).is_some_and(char::is_ascii_digit) {
+            end += 1;
+            while chars.get(end).is_some_and(char::is_ascii_digit) {
+                end += 1;
+            }
+        }
+
+        let number: String = chars[i..end].iter().collect();
+        match number.parse::<f64>() {
+            Ok(value) if number.contains('.') => result.push_str(&round(value)),
+            _ => result.push_str(&number),
+        }
+        i = end;
+    }
+    result
+}
+
+/// Formats a number to at most 3 decimal places, without trailing zeros.
+fn round(number: f64) -> String {
+    let rounded = format!("{:.3}", number);
+    let rounded = rounded.trim_end_matches('0').trim_end_matches('.');
+    if rounded == "-0" {
+        "0".to_string()
+    } else {
+        rounded.to_string()
+    }
+}
+
+/// Checks `normalize` itself, since every comparison relies on it.
+fn check_normalize() {
+    assert_eq!(
+        normalize("  <line x1=\"12.3456\" y1=\"-0.0001\" x2=\"7\"/>\n\n"),
+        "<line x1=\"12.346\" y1=\"0\" x2=\"7\"/>\n"
+    );
+    assert_eq!(
+        normalize("d=\"M 1.50 2 L 3.1239 4.0\"/>"),
+        "d=\"M 1.5 2 L 3.124 4\"/>\n"
+    );
+    // Names and colours containing digits are not numbers
+    assert_eq!(
+        normalize("xmlns=\"http://www.w3.org/2000/svg\" stroke=\"#1a2.5b\""),
+        "xmlns=\"http://www.w3.org/2000/svg\" stroke=\"#1a2.5b\"\n"
+    );
+}
+
+/// Finds the code of every `error[CODE]: ...` diagnostic.
+fn error_codes(stderr: &str) -> Vec<String> {
+    stderr
+        .lines()
+        .filter_map(|line| line.strip_prefix("error["))
+        .filter_map(|line| line.split_once(']'))
+        .map(|(code, _)| code.to_string())
+        .collect()
+}
+
+fn expected_paths(expected_dir: &Path, name: &str) -> (PathBuf, PathBuf) {
+    (
+        expected_dir.join(format!("{}.svg", name)),
+        expected_dir.join(format!("{}.err", name)),
+    )
+}
+
+fn write_expected(expected_dir: &Path, name: &str, outcome: &Outcome) {
+    let (svg_path, err_path) = expected_paths(expected_dir, name);
+    let _ = fs::remove_file(&svg_path);
+    let _ = fs::remove_file(&err_path);
+    match outcome {
+        Outcome::Drawn(svg) => fs::write(svg_path, svg).unwrap(),
+        Outcome::Failed(codes) => fs::write(err_path, codes.join("\n") + "\n").unwrap(),
+    }
+}
+
+fn check(expected_dir: &Path, name: &str, outcome: &Outcome) -> Result<(), String> {
+    let (svg_path, err_path) = expected_paths(expected_dir, name);
+    let should_fail = name.ends_with("_err");
+
+    match outcome {
+        Outcome::Drawn(_) if should_fail => Err("expected an error but it succeeded".to_string()),
+        Outcome::Failed(codes) if !should_fail => Err(format!(
+            "expected it to succeed but it failed with {:?}",
+            codes
+        )),
+        Outcome::Drawn(svg) => {
+            let expected = fs::read_to_string(&svg_path)
+                .map_err(|e| format!("cannot read {}: {}", svg_path.display(), e))?;
+            if normalize(&expected) == *svg {
+                Ok(())
+            } else {
+                Err(format!("drew a different image to {}", svg_path.display()))
+            }
+        }
+        Outcome::Failed(codes) => {
+            let expected = fs::read_to_string(&err_path)
+                .map_err(|e| format!("cannot read {}: {}", err_path.display(), e))?;
+            let expected: Vec<_> = expected.lines().map(str::trim).collect();
+            if expected == *codes {
+                Ok(())
+            } else {
+                Err(format!(
+                    "expected errors {:?} but found {:?}",
+                    expected, codes
+                ))
+            }
+        }
+    }
+}